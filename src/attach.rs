@@ -0,0 +1,218 @@
+use std::io::Write;
+use std::os::fd::{AsFd, BorrowedFd, RawFd};
+
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+use nix::sys::termios::{self, OutputFlags, SetArg, Termios};
+use tokio::io::unix::AsyncFd;
+
+use crate::shell::{RemoteShell, ShellId};
+
+/// Where keystrokes go while the terminal is passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AttachTarget {
+    /// `:attach`: a single shell owns the terminal
+    Shell(ShellId),
+    /// `:raw`: keystrokes go to every enabled shell, output stays multiplexed
+    Broadcast,
+}
+
+/// Parse an escape sequence, where `^X` stands for Ctrl-X (`^]`, `^\`...).
+pub(crate) fn parse_escape(text: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = text.bytes().peekable();
+    while let Some(b) = bytes.next() {
+        match (b, bytes.peek()) {
+            (b'^', Some(&next)) if (b'@'..=b'_').contains(&next.to_ascii_uppercase()) => {
+                out.push(next.to_ascii_uppercase() & 0x1f);
+                bytes.next();
+            }
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+/// Watches the keystrokes sent to an attached shell for the detach sequence. Like ssh's
+/// `~.`, a printable sequence only counts at the start of a line, so it can still be typed
+/// in the middle of one; a sequence starting with a control character counts anywhere.
+pub(crate) struct EscapeDetector {
+    sequence: Vec<u8>,
+    line_start_only: bool,
+    matched: usize,
+    at_line_start: bool,
+}
+
+impl EscapeDetector {
+    pub(crate) fn new(sequence: Vec<u8>) -> Self {
+        let line_start_only = sequence.first().is_some_and(|b| !b.is_ascii_control());
+        Self {
+            sequence,
+            line_start_only,
+            matched: 0,
+            at_line_start: true,
+        }
+    }
+
+    /// Returns the input to forward to the shell, and whether the sequence was typed.
+    /// Bytes that may start the sequence are held back until it is clear they don't.
+    pub(crate) fn feed(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        if self.sequence.is_empty() {
+            return (input.to_vec(), false);
+        }
+        for &b in input {
+            let can_start = self.at_line_start || !self.line_start_only;
+            if (self.matched > 0 || can_start) && b == self.sequence[self.matched] {
+                self.matched += 1;
+                if self.matched == self.sequence.len() {
+                    self.matched = 0;
+                    return (out, true);
+                }
+                continue;
+            }
+            if self.matched > 0 {
+                // Not the escape sequence after all
+                out.extend_from_slice(&self.sequence[..self.matched]);
+                self.matched = 0;
+            }
+            out.push(b);
+            self.at_line_start = b == b'\r' || b == b'\n';
+        }
+        (out, false)
+    }
+}
+
+/// The local terminal in raw mode with stdin read directly, restored on drop.
+pub(crate) struct RawTerminal {
+    stdin: AsyncFd<RawFd>,
+    saved_termios: Termios,
+}
+
+impl RawTerminal {
+    /// With `keep_newlines`, output still turns \n into \r\n.
+    pub(crate) fn start(keep_newlines: bool) -> std::io::Result<Self> {
+        let stdin = std::io::stdin();
+        let saved_termios = termios::tcgetattr(stdin.as_fd())?;
+        let mut raw = saved_termios.clone();
+        termios::cfmakeraw(&mut raw);
+        if keep_newlines {
+            raw.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+        }
+        termios::tcsetattr(stdin.as_fd(), SetArg::TCSADRAIN, &raw)?;
+        let stdin = match AsyncFd::new(nix::libc::STDIN_FILENO) {
+            Ok(fd) => fd,
+            Err(e) => {
+                termios::tcsetattr(std::io::stdin().as_fd(), SetArg::TCSADRAIN, &saved_termios).ok();
+                return Err(e);
+            }
+        };
+        Ok(Self { stdin, saved_termios })
+    }
+
+    /// Wait for keystrokes, None once stdin is closed.
+    pub(crate) async fn read_input(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut ready = self.stdin.readable().await.ok()?;
+            // stdin stays blocking (it usually shares its file description with stdout),
+            // so only read once poll confirms there is something to read
+            let fd = unsafe { BorrowedFd::borrow_raw(nix::libc::STDIN_FILENO) };
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, PollTimeout::ZERO) {
+                Ok(n) if n > 0 => {
+                    return match nix::unistd::read(fd, &mut buf) {
+                        Ok(0) | Err(_) => None,
+                        Ok(n) => Some(buf[..n].to_vec()),
+                    };
+                }
+                Ok(_) => ready.clear_ready(),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        termios::tcsetattr(std::io::stdin().as_fd(), SetArg::TCSADRAIN, &self.saved_termios).ok();
+    }
+}
+
+/// The local terminal passed through to the shells.
+pub(crate) struct Attachment {
+    pub(crate) target: AttachTarget,
+    pub(crate) escape: EscapeDetector,
+    terminal: RawTerminal,
+}
+
+impl Attachment {
+    pub(crate) fn start(target: AttachTarget, escape: Vec<u8>) -> std::io::Result<Self> {
+        // In broadcast mode mash still prints the output, as lines ending in \n
+        let terminal = RawTerminal::start(target == AttachTarget::Broadcast)?;
+        Ok(Self {
+            target,
+            escape: EscapeDetector::new(escape),
+            terminal,
+        })
+    }
+
+    /// The shell that owns the terminal, if any.
+    pub(crate) fn shell(&self) -> Option<ShellId> {
+        match self.target {
+            AttachTarget::Shell(id) => Some(id),
+            AttachTarget::Broadcast => None,
+        }
+    }
+
+    pub(crate) fn receives_input(&self, shell: &RemoteShell) -> bool {
+        match self.target {
+            AttachTarget::Shell(id) => shell.id == id,
+            AttachTarget::Broadcast => shell.enabled,
+        }
+    }
+
+    /// Wait for keystrokes, None once stdin is closed.
+    pub(crate) async fn read_input(&mut self) -> Option<Vec<u8>> {
+        self.terminal.read_input().await
+    }
+}
+
+/// Output of the attached shell goes to the terminal untouched.
+pub(crate) fn write_output(data: &[u8]) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(data);
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_escape() {
+        assert_eq!(parse_escape("~."), b"~.");
+        assert_eq!(parse_escape("^]"), b"\x1d");
+        assert_eq!(parse_escape("^a^"), b"\x01^");
+    }
+
+    #[test]
+    fn test_escape_at_line_start() {
+        let mut detector = EscapeDetector::new(b"~.".to_vec());
+        assert_eq!(detector.feed(b"~."), (Vec::new(), true));
+
+        let mut detector = EscapeDetector::new(b"~.".to_vec());
+        // In the middle of a line the sequence is just text
+        assert_eq!(detector.feed(b"a~."), (b"a~.".to_vec(), false));
+        // The tilde is held back until the next key shows whether it is the escape
+        assert_eq!(detector.feed(b"\r~"), (b"\r".to_vec(), false));
+        assert_eq!(detector.feed(b"x"), (b"~x".to_vec(), false));
+        assert_eq!(detector.feed(b"\n~"), (b"\n".to_vec(), false));
+        assert_eq!(detector.feed(b"."), (Vec::new(), true));
+    }
+
+    #[test]
+    fn test_control_escape_anywhere() {
+        let mut detector = EscapeDetector::new(parse_escape("^]"));
+        assert_eq!(detector.feed(b"vim\x1d"), (b"vim".to_vec(), true));
+    }
+}
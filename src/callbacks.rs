@@ -13,7 +13,13 @@ fn random_string(length: usize) -> String {
 #[derive(Debug, Clone)]
 pub(crate) enum CallbackAction {
     SeenPrompt,
-    Rename { new_name: Vec<u8> },
+    Rename {
+        new_name: Vec<u8>,
+    },
+    Keepalive,
+    /// Frame the candidates printed for the completion query with this number
+    CompletionStart(u32),
+    CompletionEnd(u32),
     None,
 }
 
@@ -0,0 +1,176 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::control_socket::Responder;
+use crate::display_names::display_width;
+use crate::shell::ShellId;
+
+/// What collected output is for once every shell has finished the command.
+pub(crate) enum CaptureTarget {
+    /// `:pipe`: fed to a local command, prefixed with host names unless raw
+    Pipe { local_command: String, raw: bool },
+    /// `:capture`: stored in a named buffer
+    Buffer { name: String },
+    /// A control socket `run`, answered with each shell's output and exit code
+    Socket { responder: Responder },
+}
+
+/// A `:pipe` or `:capture` whose shells have all finished the command.
+pub(crate) enum FinishedCapture {
+    Pipe {
+        local_command: String,
+        input: Vec<u8>,
+    },
+    Buffer {
+        name: String,
+        hosts: usize,
+        lines: usize,
+    },
+    Socket {
+        responder: Responder,
+        outputs: Vec<CapturedOutput>,
+    },
+}
+
+/// One host's output in a capture buffer.
+pub(crate) struct CapturedOutput {
+    pub(crate) id: ShellId,
+    /// Display name of the shell at capture time
+    pub(crate) name: String,
+    pub(crate) output: Vec<u8>,
+    /// None when the shell died or its prompt did not report one
+    pub(crate) exit_code: Option<i32>,
+}
+
+/// Output of a `:capture`, kept for later queries.
+pub(crate) struct CaptureBuffer {
+    pub(crate) command: String,
+    pub(crate) outputs: Vec<CapturedOutput>,
+}
+
+impl CaptureBuffer {
+    pub(crate) fn line_count(&self) -> usize {
+        self.outputs
+            .iter()
+            .map(|o| o.output.iter().filter(|&&b| b == b'\n').count())
+            .sum()
+    }
+
+    /// Shells with an output line matching the regex.
+    pub(crate) fn matching_shells(&self, re: &Regex) -> Vec<ShellId> {
+        self.outputs
+            .iter()
+            .filter(|o| String::from_utf8_lossy(&o.output).lines().any(|line| re.is_match(line)))
+            .map(|o| o.id)
+            .collect()
+    }
+
+    /// `{"command": ..., "hosts": {"name": "output", ...}}`
+    pub(crate) fn to_json(&self) -> Value {
+        let hosts: Map<String, Value> = self
+            .outputs
+            .iter()
+            .map(|o| {
+                (
+                    o.name.clone(),
+                    Value::String(String::from_utf8_lossy(&o.output).into_owned()),
+                )
+            })
+            .collect();
+        let mut json = Map::new();
+        json.insert("command".to_string(), Value::String(self.command.clone()));
+        json.insert("hosts".to_string(), Value::Object(hosts));
+        Value::Object(json)
+    }
+}
+
+/// Every line of every output, as `name : line` with the names aligned.
+pub(crate) fn prefixed_lines<'a>(outputs: impl IntoIterator<Item = (&'a str, &'a [u8])> + Clone) -> Vec<u8> {
+    let max_name_len = outputs
+        .clone()
+        .into_iter()
+        .map(|(name, _)| display_width(name))
+        .max()
+        .unwrap_or(0);
+    let mut out = Vec::new();
+    for (name, output) in outputs {
+        let prefix = format!("{}{} : ", name, " ".repeat(max_name_len - display_width(name)));
+        for line in output.split_inclusive(|&b| b == b'\n') {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(line);
+        }
+    }
+    out
+}
+
+/// Split a `buffer:NAME:REGEX` selection pattern.
+pub(crate) fn parse_buffer_pattern(pattern: &str) -> Option<(&str, &str)> {
+    pattern.strip_prefix("buffer:")?.split_once(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer() -> CaptureBuffer {
+        CaptureBuffer {
+            command: "rpm -qa".to_string(),
+            outputs: vec![
+                CapturedOutput {
+                    id: ShellId(1),
+                    name: "web1".to_string(),
+                    output: b"openssl-3.0.7\nbash-5.2\n".to_vec(),
+                    exit_code: Some(0),
+                },
+                CapturedOutput {
+                    id: ShellId(2),
+                    name: "db".to_string(),
+                    output: b"openssl-1.1.1\n".to_vec(),
+                    exit_code: Some(0),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_prefixed_lines() {
+        let buffer = buffer();
+        let out = prefixed_lines(buffer.outputs.iter().map(|o| (o.name.as_str(), o.output.as_slice())));
+        assert_eq!(
+            out,
+            b"web1 : openssl-3.0.7\nweb1 : bash-5.2\ndb   : openssl-1.1.1\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_matching_shells() {
+        let buffer = buffer();
+        assert_eq!(
+            buffer.matching_shells(&Regex::new("^openssl-3").unwrap()),
+            vec![ShellId(1)]
+        );
+        assert_eq!(
+            buffer.matching_shells(&Regex::new("openssl").unwrap()),
+            vec![ShellId(1), ShellId(2)]
+        );
+        assert_eq!(buffer.line_count(), 3);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = buffer().to_json();
+        assert_eq!(json["command"], "rpm -qa");
+        assert_eq!(json["hosts"]["db"], "openssl-1.1.1\n");
+    }
+
+    #[test]
+    fn test_parse_buffer_pattern() {
+        assert_eq!(
+            parse_buffer_pattern("buffer:rpm:^openssl-3"),
+            Some(("rpm", "^openssl-3"))
+        );
+        assert_eq!(parse_buffer_pattern("buffer:rpm:a:b"), Some(("rpm", "a:b")));
+        assert_eq!(parse_buffer_pattern("buffer:rpm"), None);
+        assert_eq!(parse_buffer_pattern("web*"), None);
+    }
+}
@@ -1,10 +1,20 @@
 use argh::FromArgs;
+use owo_colors::DynColors;
 use std::fs;
 
+use crate::colors;
+use crate::hosts::{self, HostEntry, HostLine};
+use crate::inventory::Inventory;
+use crate::saved_session::SavedSession;
+use crate::ssh_config;
+
+/// `--ssh` when not given.
+const DEFAULT_SSH: &str = "exec ssh -oLogLevel=Quiet -t %(host)s %(port)s";
+
 /// mash: control multiple SSH sessions from a single interactive shell
 #[derive(FromArgs)]
 pub(crate) struct Args {
-    /// read hostnames from given file, one per line
+    /// read hostnames from given file, one per line, optionally followed by key=value variables
     #[argh(option, long = "hosts-file")]
     pub(crate) hosts_filenames: Vec<String>,
 
@@ -13,13 +23,57 @@ pub(crate) struct Args {
     pub(crate) command: Option<String>,
 
     /// ssh command template
-    #[argh(option, default = "String::from(\"exec ssh -oLogLevel=Quiet -t %(host)s %(port)s\")")]
+    #[argh(option, default = "DEFAULT_SSH.to_string()")]
     pub(crate) ssh: String,
 
+    /// print each host's output as one block when its command finishes, instead of interleaving lines
+    #[argh(switch, long = "group-output")]
+    pub(crate) group_output: bool,
+
+    /// order of the --group-output blocks: completion (default) or name
+    #[argh(option, long = "group-order", default = "String::from(\"completion\")")]
+    pub(crate) group_order: String,
+
+    /// keep or strip the colors in the remote output: keep (default) or strip
+    #[argh(option, long = "remote-colors", default = "String::from(\"keep\")")]
+    pub(crate) remote_colors: String,
+
+    /// how to show output bytes that are not valid UTF-8: lossy (default), hex or raw
+    #[argh(option, long = "invalid-utf8", default = "String::from(\"lossy\")")]
+    pub(crate) invalid_utf8: String,
+
+    /// sequence typed at the start of a line to leave :attach and :raw, ^X meaning Ctrl-X (default: ~.)
+    #[argh(option, long = "attach-escape", default = "String::from(\"~.\")")]
+    pub(crate) attach_escape: String,
+
+    /// write each host's output to DIR/NAME.out and its exit code to DIR/NAME.status
+    #[argh(option, long = "output-dir")]
+    pub(crate) output_dir: Option<String>,
+
+    /// listen on a Unix socket at PATH for JSON line requests driving the session
+    #[argh(option, long = "control-socket")]
+    pub(crate) control_socket: Option<String>,
+
+    /// run in a background session that survives the terminal, see `mash attach` and `mash ls`
+    #[argh(switch)]
+    pub(crate) detachable: bool,
+
+    /// name of the --detachable session (default: the process id)
+    #[argh(option, long = "session-name")]
+    pub(crate) session_name: Option<String>,
+
+    /// restore the hosts and their settings saved by :save_session to FILE
+    #[argh(option)]
+    pub(crate) session: Option<String>,
+
     /// remote user to log in as
     #[argh(option)]
     pub(crate) user: Option<String>,
 
+    /// render {{host}}, {{name}}, {{rank}}... placeholders per shell in commands
+    #[argh(switch)]
+    pub(crate) templates: bool,
+
     /// disable colored hostnames
     #[argh(switch, long = "no-color")]
     pub(crate) no_color: bool,
@@ -36,6 +90,38 @@ pub(crate) struct Args {
     #[argh(switch, long = "abort-errors")]
     pub(crate) abort_errors: bool,
 
+    /// interrupt commands still running after SECS seconds, then disconnect
+    #[argh(option, long = "command-timeout")]
+    pub(crate) command_timeout: Option<f64>,
+
+    /// automatically reconnect shells that die
+    #[argh(switch, long = "auto-reconnect")]
+    pub(crate) auto_reconnect: bool,
+
+    /// maximum automatic reconnect attempts per shell (default: 5)
+    #[argh(option, long = "reconnect-attempts", default = "5")]
+    pub(crate) reconnect_attempts: u32,
+
+    /// seconds before the first automatic reconnect, doubled on each attempt (default: 1)
+    #[argh(option, long = "reconnect-delay", default = "1.0")]
+    pub(crate) reconnect_delay: f64,
+
+    /// send a keepalive probe to enabled idle shells every SECS seconds
+    #[argh(option)]
+    pub(crate) keepalive: Option<f64>,
+
+    /// seconds without a keepalive reply before a shell is marked unresponsive (default: 5)
+    #[argh(option, long = "keepalive-timeout", default = "5.0")]
+    pub(crate) keepalive_timeout: f64,
+
+    /// do not print the end-of-run summary in non-interactive mode
+    #[argh(switch, long = "no-summary")]
+    pub(crate) no_summary: bool,
+
+    /// write hosts that failed in non-interactive mode to this file
+    #[argh(option, long = "failed-hosts-file")]
+    pub(crate) failed_hosts_file: Option<String>,
+
     /// print debugging information
     #[argh(switch)]
     pub(crate) debug: bool,
@@ -44,12 +130,37 @@ pub(crate) struct Args {
     #[argh(switch)]
     pub(crate) version: bool,
 
-    /// hostnames to connect to
+    /// run a local command and read hosts from its output, in the hosts file format
+    #[argh(option, long = "hosts-cmd")]
+    pub(crate) hosts_cmds: Vec<String>,
+
+    /// read hosts from an Ansible INI/YAML or a JSON inventory; @GROUP arguments select groups
+    #[argh(option)]
+    pub(crate) inventory: Option<String>,
+
+    /// add the concrete Host aliases from ~/.ssh/config (and its Includes) matching this glob
+    #[argh(option, long = "from-ssh-config")]
+    pub(crate) from_ssh_config: Vec<String>,
+
+    /// add the unhashed ~/.ssh/known_hosts entries matching this glob
+    #[argh(option, long = "from-known-hosts")]
+    pub(crate) from_known_hosts: Vec<String>,
+
+    /// color for hosts matching a glob, as PATTERN=COLOR (name, 0-255 or #rrggbb), may be repeated
+    #[argh(option, long = "host-color")]
+    pub(crate) host_colors: Vec<String>,
+
+    /// exclude hosts matching this pattern (e.g. 'web<13,17>'), may be repeated
+    #[argh(option)]
+    pub(crate) exclude: Vec<String>,
+
+    /// hostnames to connect to, ^PATTERN excludes hosts, @GROUP selects an inventory group
     #[argh(positional)]
     pub(crate) host_names: Vec<String>,
 }
 
-pub(crate) fn parse_args() -> Args {
+/// Parse the command line, returning the host entries and the exclusion patterns to apply after expansion.
+pub(crate) fn parse_args() -> (Args, Vec<HostEntry>, Vec<String>) {
     let mut args: Args = argh::from_env();
 
     if args.version {
@@ -57,19 +168,82 @@ pub(crate) fn parse_args() -> Args {
         std::process::exit(0);
     }
 
+    let mut hosts: Vec<HostEntry> = Vec::new();
+    let mut exclusions: Vec<String> = args.exclude.clone();
+    let mut groups: Vec<&str> = Vec::new();
+    for name in &args.host_names {
+        if let Some(pattern) = name.strip_prefix('^') {
+            exclusions.push(pattern.to_string());
+        } else if let Some(group) = name.strip_prefix('@') {
+            groups.push(group);
+        } else {
+            hosts.push(HostEntry::new(name));
+        }
+    }
+
+    if let Some(ref path) = args.inventory {
+        let inventory = Inventory::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        if groups.is_empty() {
+            hosts.extend(inventory.entries(None));
+        }
+        for group in &groups {
+            if !inventory.has_group(group) {
+                eprintln!("Unknown inventory group: {}", group);
+                std::process::exit(1);
+            }
+            hosts.extend(inventory.entries(Some(group)));
+        }
+    } else if !groups.is_empty() {
+        eprintln!("@GROUP arguments need --inventory");
+        std::process::exit(1);
+    }
+
+    for cmd in &args.hosts_cmds {
+        let output = match std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(cmd)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Error running hosts command {}: {}", cmd, e);
+                std::process::exit(1);
+            }
+        };
+        if !output.status.success() {
+            eprintln!("Hosts command {} failed: {}", cmd, output.status);
+            std::process::exit(1);
+        }
+        for (nr, line) in String::from_utf8_lossy(&output.stdout).lines().enumerate() {
+            match hosts::parse_line(line) {
+                Ok(Some(HostLine::Host(entry))) => hosts.push(entry),
+                Ok(Some(HostLine::Exclude(pattern))) => exclusions.push(pattern),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Error in output of hosts command {}:{}: {}", cmd, nr + 1, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
     // Read hosts from files
     for filename in &args.hosts_filenames {
         match fs::read_to_string(filename) {
             Ok(content) => {
-                for line in content.lines() {
-                    let line = if let Some(idx) = line.find('#') {
-                        &line[..idx]
-                    } else {
-                        line
-                    };
-                    let line = line.trim();
-                    if !line.is_empty() {
-                        args.host_names.push(line.to_string());
+                for (nr, line) in content.lines().enumerate() {
+                    match hosts::parse_line(line) {
+                        Ok(Some(HostLine::Host(entry))) => hosts.push(entry),
+                        Ok(Some(HostLine::Exclude(pattern))) => exclusions.push(pattern),
+                        Ok(None) => {}
+                        Err(e) => {
+                            eprintln!("Error in hosts file {}:{}: {}", filename, nr + 1, e);
+                            std::process::exit(1);
+                        }
                     }
                 }
             }
@@ -80,10 +254,56 @@ pub(crate) fn parse_args() -> Args {
         }
     }
 
-    if args.host_names.is_empty() {
+    if !args.from_ssh_config.is_empty() {
+        let config_hosts = ssh_config::default_config_hosts();
+        for pattern in &args.from_ssh_config {
+            let matched = ssh_config::filter_hosts(config_hosts.clone(), pattern);
+            hosts.extend(matched.into_iter().map(HostEntry::new));
+        }
+    }
+    if !args.from_known_hosts.is_empty() {
+        let known_hosts = ssh_config::default_known_hosts();
+        for pattern in &args.from_known_hosts {
+            let matched = ssh_config::filter_hosts(known_hosts.clone(), pattern);
+            hosts.extend(matched.into_iter().map(HostEntry::new));
+        }
+    }
+
+    if let Some(path) = args.session.clone() {
+        let saved = SavedSession::load(&path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        hosts.extend(saved.entries());
+        // Options given on the command line win over the saved ones
+        if args.ssh == DEFAULT_SSH {
+            args.ssh = saved.ssh;
+        }
+        if args.user.is_none() {
+            args.user = saved.user;
+        }
+    }
+
+    if hosts.is_empty() {
         eprintln!("No hosts given");
         std::process::exit(1);
     }
 
-    args
+    (args, hosts, exclusions)
+}
+
+/// Parse the `--host-color PATTERN=COLOR` overrides, exiting on invalid ones.
+pub(crate) fn host_colors(args: &Args) -> Vec<(glob::Pattern, DynColors)> {
+    args.host_colors
+        .iter()
+        .map(|spec| {
+            let parsed = spec
+                .rsplit_once('=')
+                .and_then(|(pattern, color)| Some((glob::Pattern::new(pattern).ok()?, colors::parse_color(color)?)));
+            parsed.unwrap_or_else(|| {
+                eprintln!("Invalid --host-color {}, expected PATTERN=COLOR", spec);
+                std::process::exit(1);
+            })
+        })
+        .collect()
 }
@@ -0,0 +1,261 @@
+use owo_colors::{AnsiColors, DynColors, XtermColors};
+
+/// Levels of the xterm 6x6x6 color cube.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColorDepth {
+    Ansi16,
+    Xterm256,
+    TrueColor,
+}
+
+/// The colors available for host names, chosen to stay readable on the terminal background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Palette {
+    pub(crate) depth: ColorDepth,
+    pub(crate) light_background: bool,
+}
+
+impl Palette {
+    /// Detect the color depth from `COLORTERM`/`TERM` and the background from `COLORFGBG`.
+    pub(crate) fn detect() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self::from_env(
+            var("COLORTERM").as_deref(),
+            var("TERM").as_deref(),
+            var("COLORFGBG").as_deref(),
+        )
+    }
+
+    fn from_env(colorterm: Option<&str>, term: Option<&str>, colorfgbg: Option<&str>) -> Self {
+        let depth = if matches!(colorterm, Some("truecolor" | "24bit")) {
+            ColorDepth::TrueColor
+        } else if term.is_some_and(|t| t.contains("256color")) {
+            ColorDepth::Xterm256
+        } else {
+            ColorDepth::Ansi16
+        };
+        // COLORFGBG is "fg;bg" (sometimes "fg;default;bg"); 7 and 15 are the light backgrounds
+        let light_background = colorfgbg
+            .and_then(|v| v.rsplit(';').next())
+            .and_then(|bg| bg.parse::<u8>().ok())
+            .is_some_and(|bg| bg == 7 || bg == 15);
+        Self {
+            depth,
+            light_background,
+        }
+    }
+
+    /// A color derived from the hostname, so a host keeps its color across sessions and reconnects.
+    pub(crate) fn host_color(&self, hostname: &str) -> DynColors {
+        let hash = fnv1a(hostname.as_bytes());
+        match self.depth {
+            ColorDepth::Ansi16 => {
+                let colors = self.ansi_colors();
+                DynColors::Ansi(colors[(hash % colors.len() as u64) as usize])
+            }
+            ColorDepth::Xterm256 => {
+                let colors = self.xterm_colors();
+                DynColors::Xterm(XtermColors::from(colors[(hash % colors.len() as u64) as usize]))
+            }
+            ColorDepth::TrueColor => {
+                let hue = (hash % 360) as f64;
+                let lightness = if self.light_background { 0.35 } else { 0.65 };
+                let (r, g, b) = hsl_to_rgb(hue, 0.7, lightness);
+                DynColors::Rgb(r, g, b)
+            }
+        }
+    }
+
+    fn ansi_colors(&self) -> &'static [AnsiColors] {
+        if self.light_background {
+            &[
+                AnsiColors::Red,
+                AnsiColors::Green,
+                AnsiColors::Blue,
+                AnsiColors::Magenta,
+                AnsiColors::Cyan,
+                AnsiColors::BrightRed,
+                AnsiColors::BrightBlue,
+                AnsiColors::BrightMagenta,
+            ]
+        } else {
+            &[
+                AnsiColors::Red,
+                AnsiColors::Green,
+                AnsiColors::Yellow,
+                AnsiColors::Magenta,
+                AnsiColors::Cyan,
+                AnsiColors::BrightRed,
+                AnsiColors::BrightGreen,
+                AnsiColors::BrightYellow,
+                AnsiColors::BrightBlue,
+                AnsiColors::BrightMagenta,
+                AnsiColors::BrightCyan,
+            ]
+        }
+    }
+
+    /// Saturated color cube entries whose luminance contrasts with the background.
+    fn xterm_colors(&self) -> Vec<u8> {
+        let mut colors = Vec::new();
+        for n in 0..216u8 {
+            let rgb = [
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            ];
+            let spread = rgb.iter().max().unwrap() - rgb.iter().min().unwrap();
+            let lum = luminance(rgb[0], rgb[1], rgb[2]);
+            let readable = if self.light_background {
+                (0.08..0.4).contains(&lum)
+            } else {
+                (0.4..0.85).contains(&lum)
+            };
+            if spread >= 95 && readable {
+                colors.push(16 + n);
+            }
+        }
+        colors
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Relative luminance, 0 (black) to 1 (white).
+fn luminance(r: u8, g: u8, b: u8) -> f64 {
+    (0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64) / 255.0
+}
+
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> (u8, u8, u8) {
+    let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = lightness - c / 2.0;
+    let to_u8 = |v: f64| ((v + m) * 255.0).round() as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+/// Parse a color name (`red`, `bright-blue`), an xterm color number (`0`-`255`) or `#rrggbb`.
+pub(crate) fn parse_color(text: &str) -> Option<DynColors> {
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(DynColors::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    if let Ok(n) = text.parse::<u8>() {
+        return Some(DynColors::Xterm(XtermColors::from(n)));
+    }
+    let color = match text.to_lowercase().replace('_', "-").as_str() {
+        "black" => AnsiColors::Black,
+        "red" => AnsiColors::Red,
+        "green" => AnsiColors::Green,
+        "yellow" => AnsiColors::Yellow,
+        "blue" => AnsiColors::Blue,
+        "magenta" => AnsiColors::Magenta,
+        "cyan" => AnsiColors::Cyan,
+        "white" => AnsiColors::White,
+        "bright-black" | "grey" | "gray" => AnsiColors::BrightBlack,
+        "bright-red" => AnsiColors::BrightRed,
+        "bright-green" => AnsiColors::BrightGreen,
+        "bright-yellow" => AnsiColors::BrightYellow,
+        "bright-blue" => AnsiColors::BrightBlue,
+        "bright-magenta" => AnsiColors::BrightMagenta,
+        "bright-cyan" => AnsiColors::BrightCyan,
+        "bright-white" => AnsiColors::BrightWhite,
+        _ => return None,
+    };
+    Some(DynColors::Ansi(color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_depth_and_background() {
+        let p = Palette::from_env(Some("truecolor"), Some("xterm-256color"), None);
+        assert_eq!(p.depth, ColorDepth::TrueColor);
+        assert!(!p.light_background);
+
+        let p = Palette::from_env(None, Some("screen-256color"), Some("0;15"));
+        assert_eq!(p.depth, ColorDepth::Xterm256);
+        assert!(p.light_background);
+
+        let p = Palette::from_env(None, Some("xterm"), Some("15;default;0"));
+        assert_eq!(p.depth, ColorDepth::Ansi16);
+        assert!(!p.light_background);
+    }
+
+    #[test]
+    fn test_host_color_is_stable() {
+        for depth in [ColorDepth::Ansi16, ColorDepth::Xterm256, ColorDepth::TrueColor] {
+            let palette = Palette {
+                depth,
+                light_background: false,
+            };
+            assert_eq!(palette.host_color("web1"), palette.host_color("web1"));
+        }
+        // Neighbouring names should not all collapse onto the same color
+        let palette = Palette {
+            depth: ColorDepth::Xterm256,
+            light_background: false,
+        };
+        let colors: std::collections::HashSet<_> = (1..=10)
+            .map(|i| format!("{:?}", palette.host_color(&format!("web{}", i))))
+            .collect();
+        assert!(colors.len() > 5);
+    }
+
+    #[test]
+    fn test_palettes_contrast_with_background() {
+        for light_background in [false, true] {
+            let palette = Palette {
+                depth: ColorDepth::Xterm256,
+                light_background,
+            };
+            let colors = palette.xterm_colors();
+            assert!(colors.len() > 20);
+            for idx in colors {
+                let n = idx - 16;
+                let lum = luminance(
+                    CUBE_LEVELS[(n / 36) as usize],
+                    CUBE_LEVELS[(n / 6 % 6) as usize],
+                    CUBE_LEVELS[(n % 6) as usize],
+                );
+                assert_eq!(lum < 0.4, light_background, "color {} has luminance {}", idx, lum);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("red"), Some(DynColors::Ansi(AnsiColors::Red)));
+        assert_eq!(
+            parse_color("Bright_Blue"),
+            Some(DynColors::Ansi(AnsiColors::BrightBlue))
+        );
+        assert_eq!(parse_color("208"), Some(DynColors::Xterm(XtermColors::from(208))));
+        assert_eq!(parse_color("#ff8000"), Some(DynColors::Rgb(255, 128, 0)));
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("mauve"), None);
+    }
+}
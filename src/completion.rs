@@ -1,12 +1,16 @@
 use std::collections::HashSet;
 
 use crate::control_commands;
+use crate::remote_completion::RemoteKind;
 use crate::shell_manager::ShellManager;
+use crate::ssh_config;
 
 pub(crate) struct CompletionState {
     pub(crate) shell_names: Vec<String>,
     pub(crate) history_words: HashSet<String>,
     pub(crate) commands_in_path: Vec<String>,
+    /// Hosts from ~/.ssh/config and known_hosts, offered when completing `:add`
+    pub(crate) ssh_hosts: Vec<String>,
 }
 
 impl CompletionState {
@@ -15,6 +19,7 @@ impl CompletionState {
             shell_names: mgr.shell_display_names(),
             history_words: HashSet::new(),
             commands_in_path: read_commands_in_path(),
+            ssh_hosts: read_ssh_hosts(),
         }
     }
 
@@ -33,6 +38,12 @@ impl CompletionState {
     }
 }
 
+fn read_ssh_hosts() -> Vec<String> {
+    let mut hosts = ssh_config::default_config_hosts();
+    hosts.extend(ssh_config::default_known_hosts());
+    ssh_config::filter_hosts(hosts, "*")
+}
+
 fn read_commands_in_path() -> Vec<String> {
     let mut commands = HashSet::new();
     if let Ok(path) = std::env::var("PATH") {
@@ -51,47 +62,96 @@ fn read_commands_in_path() -> Vec<String> {
     commands.into_iter().collect()
 }
 
-pub(crate) fn complete_line(line: &str, text: &str, state: &CompletionState) -> Vec<String> {
+/// Control commands whose arguments are local paths.
+const LOCAL_PATH_COMMANDS: &[&str] = &[":chdir", ":set_log", ":tee", ":save", ":save_session"];
+
+/// What to ask the remote shells for when completing `text`: the first word of a command
+/// is a command, the others paths. None for control commands and local `!` commands.
+pub(crate) fn remote_request(line: &str, text: &str) -> Option<RemoteKind> {
+    if line.starts_with(':') || line.starts_with('!') || line.contains(" |> ") || text.starts_with('-') {
+        return None;
+    }
+    // `@PATTERN command`, the pattern is not a remote word
+    let command = match line.strip_prefix('@') {
+        Some(rest) => rest.split_once(' ')?.1.trim_start(),
+        None => line,
+    };
+    if command.contains(' ') {
+        Some(RemoteKind::Path)
+    } else {
+        Some(RemoteKind::Command)
+    }
+}
+
+/// Candidates for the word `text` of `line`, `remote` holding what the shells answered
+/// for `remote_request`.
+pub(crate) fn complete_line(line: &str, text: &str, state: &CompletionState, remote: &[String]) -> Vec<String> {
     if line.starts_with(':') {
-        complete_control_command(line, text, state)
+        return complete_control_command(line, text, state);
+    }
+    if let Some(pattern) = line.strip_prefix('@').filter(|rest| !rest.contains(' ')) {
+        return state
+            .shell_names
+            .iter()
+            .filter(|name| name.starts_with(pattern))
+            .map(|name| format!("@{} ", name))
+            .collect();
+    }
+    if line.starts_with('!') || line.contains(" |> ") {
+        return complete_local_line(line, text, state);
+    }
+
+    let mut results: Vec<String> = match remote_request(line, text) {
+        Some(RemoteKind::Command) => remote.iter().map(|cmd| format!("{} ", cmd)).collect(),
+        _ => remote.to_vec(),
+    };
+    let tlen = text.len();
+    for word in &state.history_words {
+        if word.len() > tlen && word.starts_with(text) {
+            results.push(format!("{} ", word));
+        }
+    }
+    remove_dupes(results)
+}
+
+/// Local paths and `$PATH` commands, for `!` commands run on this machine.
+fn complete_local_line(line: &str, text: &str, state: &CompletionState) -> Vec<String> {
+    let (dropped_exclam, text) = if line.starts_with('!') && !text.is_empty() && line.starts_with(text) {
+        (true, &text[1..])
     } else {
-        let (dropped_exclam, text) = if line.starts_with('!') && !text.is_empty() && line.starts_with(text) {
-            (true, &text[1..])
-        } else {
-            (false, text)
-        };
+        (false, text)
+    };
 
-        let mut results = Vec::new();
+    let mut results = Vec::new();
 
-        // Complete local paths
-        results.extend(complete_local_path(text));
+    // Complete local paths
+    results.extend(complete_local_path(text));
 
-        // Complete from history
-        let tlen = text.len();
-        for word in &state.history_words {
-            if word.len() > tlen && word.starts_with(text) {
-                results.push(format!("{} ", word));
-            }
+    // Complete from history
+    let tlen = text.len();
+    for word in &state.history_words {
+        if word.len() > tlen && word.starts_with(text) {
+            results.push(format!("{} ", word));
         }
+    }
 
-        // Complete first word from $PATH
-        let is_first_word = !line.contains(' ') || (line.starts_with('!') && !line[1..].contains(' '));
-        if is_first_word {
-            for cmd in &state.commands_in_path {
-                if cmd.len() > tlen && cmd.starts_with(text) {
-                    results.push(format!("{} ", cmd));
-                }
+    // Complete first word from $PATH
+    let is_first_word = !line.contains(' ') || (line.starts_with('!') && !line[1..].contains(' '));
+    if is_first_word {
+        for cmd in &state.commands_in_path {
+            if cmd.len() > tlen && cmd.starts_with(text) {
+                results.push(format!("{} ", cmd));
             }
         }
+    }
 
-        results = remove_dupes(results);
-
-        if dropped_exclam {
-            results = results.into_iter().map(|r| format!("!{}", r)).collect();
-        }
+    results = remove_dupes(results);
 
-        results
+    if dropped_exclam {
+        results = results.into_iter().map(|r| format!("!{}", r)).collect();
     }
+
+    results
 }
 
 fn complete_control_command(line: &str, text: &str, state: &CompletionState) -> Vec<String> {
@@ -105,6 +165,15 @@ fn complete_control_command(line: &str, text: &str, state: &CompletionState) ->
             .filter(|cmd| cmd.starts_with(prefix))
             .map(|cmd| format!(":{} ", cmd))
             .collect()
+    } else if parts.first().is_some_and(|cmd| LOCAL_PATH_COMMANDS.contains(cmd)) {
+        complete_local_path(text)
+    } else if parts.first() == Some(&":add") {
+        state
+            .ssh_hosts
+            .iter()
+            .filter(|host| host.starts_with(text) && !line.contains(&format!(" {} ", host)))
+            .map(|host| format!("{} ", host))
+            .collect()
     } else {
         // Completing command parameters - complete with shell names
         state
@@ -160,6 +229,7 @@ mod tests {
             shell_names: shell_names.into_iter().map(String::from).collect(),
             history_words: history.into_iter().map(String::from).collect(),
             commands_in_path: commands.into_iter().map(String::from).collect(),
+            ssh_hosts: Vec::new(),
         }
     }
 
@@ -229,14 +299,14 @@ mod tests {
     #[test]
     fn test_complete_control_command_name() {
         let state = make_state(vec!["web1", "web2"], vec![], vec![]);
-        let results = complete_line(":li", ":li", &state);
+        let results = complete_line(":li", ":li", &state, &[]);
         assert!(results.iter().any(|r| r == ":list "));
     }
 
     #[test]
     fn test_complete_control_command_all() {
         let state = make_state(vec![], vec![], vec![]);
-        let results = complete_line(":", ":", &state);
+        let results = complete_line(":", ":", &state, &[]);
         // Should list all commands
         assert!(results.len() > 10);
         assert!(results.iter().any(|r| r == ":help "));
@@ -246,32 +316,78 @@ mod tests {
     #[test]
     fn test_complete_control_command_params() {
         let state = make_state(vec!["web1", "web2", "db1"], vec![], vec![]);
-        let results = complete_line(":enable w", "w", &state);
+        let results = complete_line(":enable w", "w", &state, &[]);
         assert!(results.iter().any(|r| r == "web1 "));
         assert!(results.iter().any(|r| r == "web2 "));
         assert!(!results.iter().any(|r| r.starts_with("db")));
     }
 
+    #[test]
+    fn test_complete_add_from_ssh_hosts() {
+        let mut state = make_state(vec!["web1"], vec![], vec![]);
+        state.ssh_hosts = vec!["web1.example.com".into(), "web2.example.com".into(), "db1".into()];
+        let results = complete_line(":add web1.example.com w", "w", &state, &[]);
+        assert_eq!(results, vec!["web2.example.com "]);
+    }
+
     #[test]
     fn test_complete_line_from_history() {
         let state = make_state(vec![], vec!["uptime", "hostname"], vec![]);
-        let results = complete_line("upt", "upt", &state);
+        let results = complete_line("upt", "upt", &state, &[]);
         assert!(results.iter().any(|r| r == "uptime "));
     }
 
     #[test]
-    fn test_complete_line_from_path() {
-        let state = make_state(vec![], vec![], vec!["ls", "lsblk", "lsof"]);
-        let results = complete_line("ls", "ls", &state);
+    fn test_complete_line_from_remote() {
+        // Remote commands come from the shells, not the local $PATH
+        let state = make_state(vec![], vec![], vec!["lsusb"]);
+        let remote = vec!["lsblk".to_string(), "lsof".to_string()];
+        let results = complete_line("ls", "ls", &state, &remote);
         assert!(results.iter().any(|r| r == "lsblk "));
         assert!(results.iter().any(|r| r == "lsof "));
+        assert!(!results.iter().any(|r| r == "lsusb "));
+
+        let remote = vec!["/etc/".to_string(), "/etc.bak".to_string()];
+        let results = complete_line("cat /etc", "/etc", &state, &remote);
+        assert_eq!(results, vec!["/etc/", "/etc.bak"]);
+    }
+
+    #[test]
+    fn test_remote_request() {
+        assert_eq!(remote_request("sys", "sys"), Some(RemoteKind::Command));
+        assert_eq!(remote_request("cat /et", "/et"), Some(RemoteKind::Path));
+        assert_eq!(remote_request("@web* sys", "sys"), Some(RemoteKind::Command));
+        assert_eq!(remote_request("@web* cat /e", "/e"), Some(RemoteKind::Path));
+        assert_eq!(remote_request("@web", "@web"), None);
+        assert_eq!(remote_request("ls -l", "-l"), None);
+        assert_eq!(remote_request("!ls", "!ls"), None);
+        assert_eq!(remote_request(":set_log /tm", "/tm"), None);
+        assert_eq!(remote_request("rpm -qa |> gr", "gr"), None);
+    }
+
+    #[test]
+    fn test_complete_control_command_local_path() {
+        let state = make_state(vec!["web1"], vec![], vec![]);
+        let dir = std::env::temp_dir().join(format!("mash-local-path-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("session.json"), "").unwrap();
+        let text = format!("{}/sess", dir.display());
+        let results = complete_line(&format!(":save_session {}", text), &text, &state, &[]);
+        assert_eq!(results, vec![format!("{}/session.json", dir.display())]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_complete_at_pattern() {
+        let state = make_state(vec!["web1", "db1"], vec![], vec![]);
+        assert_eq!(complete_line("@w", "@w", &state, &[]), vec!["@web1 "]);
     }
 
     #[test]
     fn test_complete_line_no_path_after_space() {
         // PATH completion only happens for first word
         let state = make_state(vec![], vec![], vec!["ls", "lsblk"]);
-        let results = complete_line("echo ls", "ls", &state);
+        let results = complete_line("echo ls", "ls", &state, &[]);
         // Should not include lsblk since it's not the first word
         assert!(!results.iter().any(|r| r == "lsblk "));
     }
@@ -279,7 +395,7 @@ mod tests {
     #[test]
     fn test_complete_line_exclamation() {
         let state = make_state(vec![], vec![], vec!["ls", "lsblk"]);
-        let results = complete_line("!ls", "!ls", &state);
+        let results = complete_line("!ls", "!ls", &state, &[]);
         // Results should have ! prefix
         assert!(results.iter().any(|r| r == "!lsblk "));
     }
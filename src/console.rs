@@ -5,6 +5,10 @@ pub(crate) struct Console {
     interactive: bool,
     last_status_length: usize,
     log_file: Option<File>,
+    /// Output kept back while the terminal belongs to an attached shell
+    held: Option<Vec<u8>>,
+    /// Output of a control command from the socket, returned to the client instead
+    collected: Option<Vec<u8>>,
 }
 
 impl Console {
@@ -23,6 +27,8 @@ impl Console {
             interactive,
             last_status_length: 0,
             log_file,
+            held: None,
+            collected: None,
         }
     }
 
@@ -32,6 +38,14 @@ impl Console {
 
     pub(crate) async fn output_with_log(&mut self, msg: &[u8], log_msg: Option<&[u8]>) {
         self.log(log_msg.unwrap_or(msg)).await;
+        if let Some(ref mut collected) = self.collected {
+            collected.extend_from_slice(msg);
+            return;
+        }
+        if let Some(ref mut held) = self.held {
+            held.extend_from_slice(msg);
+            return;
+        }
         if self.interactive && self.last_status_length > 0 {
             let clear = format!("\r{}\r", " ".repeat(self.last_status_length));
             safe_write(clear.as_bytes()).await;
@@ -46,6 +60,28 @@ impl Console {
         }
     }
 
+    /// Keep output back instead of writing it, until `release`.
+    pub(crate) fn hold(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+
+    /// Write the output kept back since `hold`.
+    pub(crate) async fn release(&mut self) {
+        if let Some(held) = self.held.take() {
+            safe_write(&held).await;
+        }
+    }
+
+    /// Collect output instead of writing it, until `take_collected`.
+    pub(crate) fn collect(&mut self) {
+        self.collected = Some(Vec::new());
+    }
+
+    /// The output collected since `collect`, writing it out again from now on.
+    pub(crate) fn take_collected(&mut self) -> Vec<u8> {
+        self.collected.take().unwrap_or_default()
+    }
+
     pub(crate) fn set_last_status_length(&mut self, length: usize) {
         self.last_status_length = length;
     }
@@ -1,18 +1,31 @@
 use std::borrow::Cow;
 use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use regex::Regex;
+
+use crate::attach::AttachTarget;
+use crate::capture::{self, CaptureTarget, parse_buffer_pattern};
 use crate::cli::Args;
 use crate::console::Console;
-use crate::display_names::DisplayNameRegistry;
+use crate::display_names::{DisplayNameRegistry, display_width};
+use crate::durations::{format_duration, format_uptime};
 use crate::host_syntax::expand_syntax;
-use crate::shell::ShellState;
-use crate::shell_manager::ShellManager;
+use crate::hosts::HostEntry;
+use crate::inventory::Inventory;
+use crate::pty_spawn;
+use crate::saved_session::{SavedHost, SavedSession};
+use crate::shell::{ShellId, ShellState};
+use crate::shell_manager::{BlockOrder, OutputMode, ShellManager};
 
 pub(crate) enum CmdResult {
     Ok,
     Quit,
     Error(String),
-    AddHosts(Vec<String>),
+    AddHosts(Vec<HostEntry>),
+    Reconnect(Vec<ShellId>),
+    Attach(AttachTarget),
 }
 
 pub(crate) async fn dispatch(
@@ -21,7 +34,7 @@ pub(crate) async fn dispatch(
     console: &mut Console,
     display_names: &mut DisplayNameRegistry,
     interactive: bool,
-    _args: &Args,
+    args: &Args,
 ) -> CmdResult {
     if line.is_empty() {
         return CmdResult::Ok;
@@ -38,15 +51,34 @@ pub(crate) async fn dispatch(
         "quit" => CmdResult::Quit,
         "enable" => do_enable(params, mgr, console, display_names, interactive).await,
         "disable" => do_disable(params, mgr, console, display_names, interactive).await,
-        "reconnect" => do_reconnect(params, mgr, console, display_names).await,
-        "add" => do_add(params),
+        "reconnect" => do_reconnect(params, mgr, console).await,
+        "autoreconnect" => do_autoreconnect(params, mgr, console).await,
+        "add" => do_add(params, args),
         "purge" => do_purge(params, mgr, console, display_names).await,
         "rename" => do_rename(params, mgr).await,
+        "on" => do_on(params, mgr, console).await,
+        "pipe" => do_pipe(params, mgr).await,
+        "capture" => do_capture(params, mgr).await,
+        "buffers" => do_buffers(mgr, console).await,
+        "buffer" => do_buffer(params, mgr, console).await,
+        "save" => do_save(params, mgr).await,
+        "save_session" => do_save_session(params, mgr, args),
+        "history" => do_history(params, mgr, console).await,
+        "rerun" => do_rerun(params, mgr, console).await,
+        "send" => do_send(params, mgr, console).await,
         "send_ctrl" => do_send_ctrl(params, mgr, console).await,
         "reset_prompt" => do_reset_prompt(params, mgr, console).await,
+        "attach" => do_attach(params, mgr, console, interactive).await,
+        "raw" => do_raw(interactive),
         "chdir" => do_chdir(params, console).await,
         "hide_password" => do_hide_password(mgr, console).await,
         "set_debug" => do_set_debug(params, mgr, console).await,
+        "set_templates" => do_set_templates(params, mgr),
+        "set_output" => do_set_output(params, mgr, console).await,
+        "set_size" => do_set_size(params, mgr, console, display_names).await,
+        "tee" => do_tee(params, mgr, console).await,
+        "timeout" => do_timeout(params, mgr).await,
+        "set_command_timeout" => do_set_command_timeout(params, mgr, console).await,
         "export_vars" => do_export_vars(mgr).await,
         "set_log" => do_set_log(params, console).await,
         "show_read_buffer" => do_show_read_buffer(params, mgr, console).await,
@@ -95,9 +127,14 @@ const COMMANDS: &[CommandInfo] = &[
         args: "[PATTERN]",
         description: "Reconnect dead shells",
     },
+    CommandInfo {
+        name: "autoreconnect",
+        args: "on|off [PATTERN]",
+        description: "Automatically reconnect shells when they die",
+    },
     CommandInfo {
         name: "add",
-        args: "HOST...",
+        args: "HOST|@GROUP...",
         description: "Add new SSH connections",
     },
     CommandInfo {
@@ -110,6 +147,56 @@ const COMMANDS: &[CommandInfo] = &[
         args: "NAME",
         description: "Rename enabled shells (supports shell expansion)",
     },
+    CommandInfo {
+        name: "on",
+        args: "PATTERN COMMAND",
+        description: "Run a command on matching shells only (same as @PATTERN COMMAND)",
+    },
+    CommandInfo {
+        name: "pipe",
+        args: "[--raw] LOCAL -- COMMAND",
+        description: "Feed the output of COMMAND to a local command (same as COMMAND |> LOCAL)",
+    },
+    CommandInfo {
+        name: "capture",
+        args: "[--quiet] NAME COMMAND",
+        description: "Run a command and keep each shell's output in a named buffer",
+    },
+    CommandInfo {
+        name: "buffers",
+        args: "",
+        description: "List the capture buffers",
+    },
+    CommandInfo {
+        name: "buffer",
+        args: "NAME [PATTERN]",
+        description: "Show a capture buffer, for matching shells only with PATTERN",
+    },
+    CommandInfo {
+        name: "save",
+        args: "NAME FILE [--json]",
+        description: "Write a capture buffer to FILE, as prefixed lines or JSON",
+    },
+    CommandInfo {
+        name: "save_session",
+        args: "FILE",
+        description: "Save the hosts, names, enabled state and ssh template for --session",
+    },
+    CommandInfo {
+        name: "history",
+        args: "[N]",
+        description: "List the commands sent with their outcome, or each host's exit code and duration for command N",
+    },
+    CommandInfo {
+        name: "rerun",
+        args: "N [failed]",
+        description: "Send command N again to its hosts, or only to those that failed it",
+    },
+    CommandInfo {
+        name: "send",
+        args: "TEXT [PATTERN]",
+        description: "Send TEXT without a newline (\\n, \\r, \\t escapes allowed)",
+    },
     CommandInfo {
         name: "send_ctrl",
         args: "LETTER [PATTERN]",
@@ -120,6 +207,16 @@ const COMMANDS: &[CommandInfo] = &[
         args: "[PATTERN]",
         description: "Re-send the prompt initialization string",
     },
+    CommandInfo {
+        name: "attach",
+        args: "PATTERN",
+        description: "Pass the terminal through to one shell, for full-screen programs",
+    },
+    CommandInfo {
+        name: "raw",
+        args: "",
+        description: "Send every keystroke to the enabled shells (also F2), for menus and key prompts",
+    },
     CommandInfo {
         name: "chdir",
         args: "[PATH]",
@@ -135,6 +232,36 @@ const COMMANDS: &[CommandInfo] = &[
         args: "y|n [PATTERN]",
         description: "Enable or disable debug output per shell",
     },
+    CommandInfo {
+        name: "set_templates",
+        args: "y|n",
+        description: "Render {{host}}, {{name}}, {{rank}}... per shell in commands",
+    },
+    CommandInfo {
+        name: "set_output",
+        args: "[grouped [completion|name]|stream]",
+        description: "Print output as per-host blocks or interleaved lines",
+    },
+    CommandInfo {
+        name: "set_size",
+        args: "COLSxROWS|auto [PATTERN]",
+        description: "Set the window size of shells, or follow the terminal again",
+    },
+    CommandInfo {
+        name: "tee",
+        args: "[DIR|off]",
+        description: "Write each shell's output to DIR/NAME.out",
+    },
+    CommandInfo {
+        name: "timeout",
+        args: "SECS COMMAND",
+        description: "Run a command, interrupting shells still running after SECS",
+    },
+    CommandInfo {
+        name: "set_command_timeout",
+        args: "[SECS|off]",
+        description: "Set or show the timeout applied to every command",
+    },
     CommandInfo {
         name: "export_vars",
         args: "",
@@ -171,7 +298,15 @@ async fn do_help(console: &mut Console) -> CmdResult {
         "  {} Run a mash control command (see below)\n",
         ":command [args]".cyan()
     ));
+    out.push_str(&format!(
+        "  {} Send to matching shells only, enabled or not\n",
+        "@PATTERN command".cyan()
+    ));
     out.push_str(&format!("  {}         Run a local shell command\n", "!command".cyan()));
+    out.push_str(&format!(
+        "  {} Feed the output of all enabled shells to a local command\n",
+        "command |> local".cyan()
+    ));
     out.push_str(&format!(
         "  {}           Send EOF to all remote shells\n\n",
         "Ctrl-D".cyan()
@@ -179,12 +314,13 @@ async fn do_help(console: &mut Console) -> CmdResult {
 
     out.push_str(&format!("{}\n", "Prompt indicators:".yellow().bold()));
     out.push_str(&format!(
-        "  {} idle  {} running  {} pending  {} dead  {} disabled\n\n",
+        "  {} idle  {} running  {} pending  {} dead  {} disabled  {} unresponsive\n\n",
         "●".green(),
         "◉".yellow(),
         "◌".blue(),
         "✕".red(),
-        "○".bright_black()
+        "○".bright_black(),
+        "⚠".magenta()
     ));
 
     out.push_str(&format!("{}\n", "Control commands:".yellow().bold()));
@@ -217,12 +353,34 @@ async fn do_help(console: &mut Console) -> CmdResult {
         "*".bold(),
         "?".bold()
     ));
+    out.push_str(&format!(
+        "Host syntax such as {} or {} is expanded, {} selects tagged shells and {} excludes shells.\n",
+        "web<1-5>".bold(),
+        "{web,api}1".bold(),
+        "tag:NAME".bold(),
+        "^PATTERN".cyan()
+    ));
+    out.push_str(&format!(
+        "{} selects shells with a line matching REGEX in a {} buffer.\n",
+        "buffer:NAME:REGEX".bold(),
+        ":capture".cyan()
+    ));
     out.push_str(&format!("Omitting {} selects all shells.\n", "PATTERN".cyan()));
 
     console.output(out.as_bytes()).await;
     CmdResult::Ok
 }
 
+/// Shells whose output in the named buffer has a line matching the regex.
+fn buffer_matches(mgr: &ShellManager, name: &str, regex: &str) -> Result<Vec<ShellId>, String> {
+    let buffer = mgr
+        .buffers()
+        .get(name)
+        .ok_or_else(|| format!("No buffer named {}", name))?;
+    let re = Regex::new(regex).map_err(|e| format!("Invalid regex {}: {}", regex, e))?;
+    Ok(buffer.matching_shells(&re))
+}
+
 async fn selected_shells_indices(command: &str, mgr: &ShellManager, console: &mut Console) -> Vec<usize> {
     let _ids = mgr.shell_ids();
     let shells = mgr.all_shells();
@@ -231,31 +389,38 @@ async fn selected_shells_indices(command: &str, mgr: &ShellManager, console: &mu
         return (0..shells.len()).collect();
     }
 
+    // `^PATTERN` words exclude shells; with only exclusions, start from all shells
+    let (exclusions, patterns): (Vec<&str>, Vec<&str>) = command.split_whitespace().partition(|p| p.starts_with('^'));
+    let exclusions: Vec<&str> = exclusions.iter().map(|p| &p[1..]).collect();
+
     let mut selected = Vec::new();
     let mut selected_set = std::collections::HashSet::new();
+    if patterns.is_empty() {
+        selected = (0..shells.len()).collect();
+    }
 
-    for pattern in command.split_whitespace() {
+    for pattern in patterns {
+        if let Some((name, regex)) = parse_buffer_pattern(pattern) {
+            match buffer_matches(mgr, name, regex) {
+                Ok(ids) => {
+                    for (idx, shell) in shells.iter().enumerate() {
+                        if ids.contains(&shell.id) && selected_set.insert(idx) {
+                            selected.push(idx);
+                        }
+                    }
+                }
+                Err(e) => console.output(format!("{}\n", e).as_bytes()).await,
+            }
+            continue;
+        }
         let expanded: Vec<String> = expand_syntax(pattern);
         let mut found = false;
         for expanded_pattern in &expanded {
-            let glob_pat = glob::Pattern::new(expanded_pattern);
             for (idx, shell) in shells.iter().enumerate() {
-                if !selected_set.contains(&idx) {
-                    let matches = match &glob_pat {
-                        Ok(p) => {
-                            p.matches(&shell.display_name)
-                                || p.matches(&String::from_utf8_lossy(&shell.last_printed_line))
-                        }
-                        Err(_) => {
-                            shell.display_name == *expanded_pattern
-                                || String::from_utf8_lossy(&shell.last_printed_line) == *expanded_pattern
-                        }
-                    };
-                    if matches {
-                        found = true;
-                        selected_set.insert(idx);
-                        selected.push(idx);
-                    }
+                if !selected_set.contains(&idx) && shell.matches_pattern(expanded_pattern) {
+                    found = true;
+                    selected_set.insert(idx);
+                    selected.push(idx);
                 }
             }
         }
@@ -264,6 +429,19 @@ async fn selected_shells_indices(command: &str, mgr: &ShellManager, console: &mu
         }
     }
 
+    let (buffer_exclusions, exclusions): (Vec<&str>, Vec<&str>) =
+        exclusions.into_iter().partition(|e| parse_buffer_pattern(e).is_some());
+    for exclusion in buffer_exclusions {
+        let (name, regex) = parse_buffer_pattern(exclusion).unwrap_or_default();
+        match buffer_matches(mgr, name, regex) {
+            Ok(ids) => selected.retain(|&idx| !ids.contains(&shells[idx].id)),
+            Err(e) => console.output(format!("{}\n", e).as_bytes()).await,
+        }
+    }
+    if !exclusions.is_empty() {
+        let exclusions: Vec<String> = exclusions.iter().flat_map(|e| expand_syntax(e)).collect();
+        selected.retain(|&idx| !exclusions.iter().any(|e| shells[idx].matches_pattern(e)));
+    }
     selected
 }
 
@@ -272,8 +450,12 @@ async fn do_list(params: &str, mgr: &ShellManager, console: &mut Console) -> Cmd
     let indices = selected_shells_indices(params, mgr, console).await;
     let info_list: Vec<Vec<Vec<u8>>> = indices.iter().map(|&i| shells[i].get_info()).collect();
     let formatted = ShellManager::format_info(&info_list);
-    for line in formatted {
-        console.output(&line).await;
+    for (line, &i) in formatted.iter().zip(&indices) {
+        // Color the name after padding, so escape codes don't skew the column widths
+        let shell = &shells[i];
+        let mut colored = shell.colored_name().into_bytes();
+        colored.extend_from_slice(&line[shell.display_name.len()..]);
+        console.output(&colored).await;
     }
     CmdResult::Ok
 }
@@ -357,48 +539,67 @@ async fn toggle_shells(
     }
 }
 
-async fn do_reconnect(
-    params: &str,
-    mgr: &mut ShellManager,
-    console: &mut Console,
-    display_names: &mut DisplayNameRegistry,
-) -> CmdResult {
+async fn do_reconnect(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
     let indices = selected_shells_indices(params, mgr, console).await;
     let shells = mgr.all_shells();
-    let hosts: Vec<String> = indices
-        .iter()
-        .filter(|&&i| shells[i].state == ShellState::Dead)
-        .map(|&i| {
-            let h = shells[i].hostname.clone();
-            let p = shells[i].port.clone();
-            if p == "22" { h } else { format!("{}:{}", h, p) }
-        })
-        .collect();
-
-    // Remove dead shells
-    let to_remove: Vec<_> = indices
+    let ids: Vec<ShellId> = indices
         .iter()
         .filter(|&&i| shells[i].state == ShellState::Dead)
         .map(|&i| shells[i].id)
         .collect();
-    drop(shells);
-
-    for id in to_remove {
-        if let Some(shell) = mgr.get_shell(id) {
-            display_names.change(Some(&shell.display_name.clone()), None);
-        }
-        mgr.remove_shell(id);
-    }
 
-    if hosts.is_empty() {
+    if ids.is_empty() {
         CmdResult::Ok
     } else {
-        CmdResult::AddHosts(hosts)
+        CmdResult::Reconnect(ids)
     }
 }
 
-fn do_add(params: &str) -> CmdResult {
-    let hosts: Vec<String> = params.split_whitespace().map(String::from).collect();
+async fn do_autoreconnect(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let mut split = params.split_whitespace();
+    let enable = match split.next() {
+        Some("on") => true,
+        Some("off") => false,
+        Some(other) => return CmdResult::Error(format!("Expected 'on' or 'off', got: {}", other)),
+        None => return CmdResult::Error("Expected 'on' or 'off'".into()),
+    };
+
+    let remaining: String = split.collect::<Vec<&str>>().join(" ");
+    let indices = selected_shells_indices(&remaining, mgr, console).await;
+    let shells = mgr.all_shells();
+    let ids: Vec<_> = indices.iter().map(|&i| shells[i].id).collect();
+    drop(shells);
+    for id in ids {
+        if let Some(shell) = mgr.get_shell_mut(id) {
+            shell.auto_reconnect = enable;
+            if !enable {
+                shell.next_reconnect_at = None;
+            }
+        }
+    }
+    CmdResult::Ok
+}
+
+fn do_add(params: &str, args: &Args) -> CmdResult {
+    let mut hosts = Vec::new();
+    for name in params.split_whitespace() {
+        let Some(group) = name.strip_prefix('@') else {
+            hosts.push(HostEntry::new(name));
+            continue;
+        };
+        // Re-read the inventory so hosts added to it since startup are picked up
+        let Some(ref path) = args.inventory else {
+            return CmdResult::Error("@GROUP needs --inventory".into());
+        };
+        let inventory = match Inventory::load(path) {
+            Ok(inventory) => inventory,
+            Err(e) => return CmdResult::Error(e),
+        };
+        if !inventory.has_group(group) {
+            return CmdResult::Error(format!("Unknown inventory group: {}", group));
+        }
+        hosts.extend(inventory.entries(Some(group)));
+    }
     if hosts.is_empty() {
         CmdResult::Error("Expected at least one hostname".into())
     } else {
@@ -461,6 +662,320 @@ async fn do_rename(params: &str, mgr: &mut ShellManager) -> CmdResult {
     CmdResult::Ok
 }
 
+/// Ids of the live shells matched by `pattern`, regardless of their enabled flag.
+pub(crate) async fn targeted_shell_ids(pattern: &str, mgr: &ShellManager, console: &mut Console) -> Vec<ShellId> {
+    let indices = selected_shells_indices(pattern, mgr, console).await;
+    let shells = mgr.all_shells();
+    indices
+        .iter()
+        .filter(|&&i| shells[i].state != ShellState::Dead)
+        .map(|&i| shells[i].id)
+        .collect()
+}
+
+async fn do_on(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let (pattern, command) = match params.trim().split_once(char::is_whitespace) {
+        Some((pattern, command)) => (pattern, command.trim_start()),
+        None => return CmdResult::Error("Expected a pattern and a command".into()),
+    };
+    let ids = targeted_shell_ids(pattern, mgr, console).await;
+    mgr.dispatch_line_to(&ids, command, mgr.command_timeout).await;
+    CmdResult::Ok
+}
+
+/// Split `[--raw] LOCAL -- COMMAND` into the local command, the raw flag and the remote command.
+fn parse_pipe(params: &str) -> Result<(String, bool, &str), String> {
+    // The separator is the first ` -- ` outside of the quoted local command
+    let (mut words, command) = params
+        .match_indices(" -- ")
+        .find_map(|(i, sep)| Some((shell_words::split(&params[..i]).ok()?, params[i + sep.len()..].trim())))
+        .filter(|(_, command)| !command.is_empty())
+        .ok_or_else(|| "Usage: :pipe [--raw] LOCAL -- COMMAND".to_string())?;
+    let raw = matches!(words.first().map(String::as_str), Some("--raw" | "-r"));
+    if raw {
+        words.remove(0);
+    }
+    let local = match words.as_slice() {
+        [] => return Err("Expected a local command".to_string()),
+        [word] => word.clone(),
+        _ => shell_words::join(&words),
+    };
+    Ok((local, raw, command))
+}
+
+async fn do_pipe(params: &str, mgr: &mut ShellManager) -> CmdResult {
+    let (local, raw, command) = match parse_pipe(params) {
+        Ok(parsed) => parsed,
+        Err(e) => return CmdResult::Error(e),
+    };
+    let target = CaptureTarget::Pipe {
+        local_command: local,
+        raw,
+    };
+    start_capture(command, target, false, mgr).await
+}
+
+async fn start_capture(command: &str, target: CaptureTarget, print: bool, mgr: &mut ShellManager) -> CmdResult {
+    if mgr.has_pending_capture() {
+        return CmdResult::Error("Still waiting for the shells of the previous :pipe or :capture".to_string());
+    }
+    let ids = mgr.enabled_shell_ids();
+    // A shell still connecting would be taken for one that has finished
+    let connecting = ids
+        .iter()
+        .filter_map(|id| mgr.get_shell(*id))
+        .filter(|s| s.state == ShellState::NotStarted)
+        .count();
+    if connecting > 0 {
+        return CmdResult::Error(format!("{} shells are still connecting", connecting));
+    }
+    if !mgr
+        .start_capture(ids, command, target, print, mgr.command_timeout)
+        .await
+    {
+        return CmdResult::Error("No enabled shell to run the command on".to_string());
+    }
+    CmdResult::Ok
+}
+
+async fn do_capture(params: &str, mgr: &mut ShellManager) -> CmdResult {
+    let params = params.trim();
+    let (quiet, params) = match params.strip_prefix("--quiet ").or_else(|| params.strip_prefix("-q ")) {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, params),
+    };
+    let (name, command) = match params.split_once(char::is_whitespace) {
+        Some((name, command)) => (name, command.trim_start()),
+        None => return CmdResult::Error("Usage: :capture [--quiet] NAME COMMAND".into()),
+    };
+    let target = CaptureTarget::Buffer { name: name.to_string() };
+    start_capture(command, target, !quiet, mgr).await
+}
+
+async fn do_buffers(mgr: &ShellManager, console: &mut Console) -> CmdResult {
+    let info: Vec<Vec<Vec<u8>>> = mgr
+        .buffers()
+        .iter()
+        .map(|(name, buffer)| {
+            vec![
+                name.as_bytes().to_vec(),
+                format!("{} hosts", buffer.outputs.len()).into_bytes(),
+                format!("{} lines", buffer.line_count()).into_bytes(),
+                buffer.command.as_bytes().to_vec(),
+            ]
+        })
+        .collect();
+    if info.is_empty() {
+        console.output(b"No buffers\n").await;
+    }
+    for line in ShellManager::format_info(&info) {
+        console.output(&line).await;
+    }
+    CmdResult::Ok
+}
+
+async fn do_buffer(params: &str, mgr: &ShellManager, console: &mut Console) -> CmdResult {
+    let params = params.trim();
+    let (name, pattern) = params.split_once(char::is_whitespace).unwrap_or((params, ""));
+    if name.is_empty() {
+        return CmdResult::Error("Usage: :buffer NAME [PATTERN]".into());
+    }
+    let Some(buffer) = mgr.buffers().get(name) else {
+        return CmdResult::Error(format!("No buffer named {}", name));
+    };
+    let ids: Vec<ShellId> = if pattern.trim().is_empty() {
+        buffer.outputs.iter().map(|o| o.id).collect()
+    } else {
+        let shells = mgr.all_shells();
+        let indices = selected_shells_indices(pattern, mgr, console).await;
+        indices.iter().map(|&i| shells[i].id).collect()
+    };
+    let outputs = buffer
+        .outputs
+        .iter()
+        .filter(|o| ids.contains(&o.id))
+        .map(|o| (o.name.as_str(), o.output.as_slice()));
+    console.output(&capture::prefixed_lines(outputs)).await;
+    CmdResult::Ok
+}
+
+async fn do_save(params: &str, mgr: &ShellManager) -> CmdResult {
+    let words = match shell_words::split(params) {
+        Ok(w) => w,
+        Err(e) => return CmdResult::Error(format!("Invalid arguments: {}", e)),
+    };
+    let json = words.iter().any(|w| w == "--json");
+    let (name, file) = match words.iter().filter(|w| *w != "--json").collect::<Vec<_>>().as_slice() {
+        [name, file] => (name.as_str(), shellexpand::tilde(file.as_str()).to_string()),
+        _ => return CmdResult::Error("Usage: :save NAME FILE [--json]".into()),
+    };
+    let Some(buffer) = mgr.buffers().get(name) else {
+        return CmdResult::Error(format!("No buffer named {}", name));
+    };
+    let contents = if json {
+        let mut text = serde_json::to_string_pretty(&buffer.to_json()).unwrap_or_default();
+        text.push('\n');
+        text.into_bytes()
+    } else {
+        capture::prefixed_lines(buffer.outputs.iter().map(|o| (o.name.as_str(), o.output.as_slice())))
+    };
+    match std::fs::write(&file, contents) {
+        Ok(()) => CmdResult::Ok,
+        Err(e) => CmdResult::Error(format!("Failed to write {}: {}", file, e)),
+    }
+}
+
+fn do_save_session(params: &str, mgr: &ShellManager, args: &Args) -> CmdResult {
+    let file = params.trim();
+    if file.is_empty() {
+        return CmdResult::Error("Usage: :save_session FILE".into());
+    }
+    let session = SavedSession {
+        ssh: args.ssh.clone(),
+        user: args.user.clone(),
+        hosts: mgr.all_shells().into_iter().map(SavedHost::from_shell).collect(),
+    };
+    match session.save(&shellexpand::tilde(file)) {
+        Ok(()) => CmdResult::Ok,
+        Err(e) => CmdResult::Error(e),
+    }
+}
+
+async fn do_history(params: &str, mgr: &ShellManager, console: &mut Console) -> CmdResult {
+    let params = params.trim();
+    let history = mgr.history();
+    if params.is_empty() {
+        let info: Vec<Vec<Vec<u8>>> = history
+            .entries()
+            .map(|entry| {
+                vec![
+                    entry.number.to_string().into_bytes(),
+                    format!("{} ago", format_uptime(entry.age().as_secs())).into_bytes(),
+                    format!("{} hosts", entry.hosts.len()).into_bytes(),
+                    entry.summary().into_bytes(),
+                    format_duration(entry.duration()).into_bytes(),
+                    entry.command.as_bytes().to_vec(),
+                ]
+            })
+            .collect();
+        if info.is_empty() {
+            console.output(b"No commands sent yet\n").await;
+        }
+        for line in ShellManager::format_info(&info) {
+            console.output(&line).await;
+        }
+        return CmdResult::Ok;
+    }
+
+    let Some(entry) = params.parse().ok().and_then(|number| history.get(number)) else {
+        return CmdResult::Error(format!("No command {} in the history", params));
+    };
+    let header = format!(
+        "{}: {} ({} ago)\n",
+        entry.number,
+        entry.command,
+        format_uptime(entry.age().as_secs())
+    );
+    console.output(header.as_bytes()).await;
+    let info: Vec<Vec<Vec<u8>>> = entry
+        .hosts
+        .iter()
+        .map(|run| {
+            let (status, duration) = match run.outcome {
+                Some(outcome) => (
+                    outcome
+                        .exit_code
+                        .map_or("no exit code".to_string(), |code| format!("exit {}", code)),
+                    format_duration(outcome.duration),
+                ),
+                None => ("running".to_string(), String::new()),
+            };
+            vec![run.name.as_bytes().to_vec(), status.into_bytes(), duration.into_bytes()]
+        })
+        .collect();
+    for line in ShellManager::format_info(&info) {
+        console.output(&line).await;
+    }
+    CmdResult::Ok
+}
+
+async fn do_rerun(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let (number, failed_only) = match params.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [number] => (*number, false),
+        [number, "failed"] => (*number, true),
+        _ => return CmdResult::Error("Usage: :rerun N [failed]".into()),
+    };
+    let Some(entry) = number.parse().ok().and_then(|number| mgr.history().get(number)) else {
+        return CmdResult::Error(format!("No command {} in the history", number));
+    };
+    let runs: Vec<_> = entry.hosts.iter().filter(|run| !failed_only || run.failed()).collect();
+    if runs.is_empty() && failed_only {
+        return CmdResult::Error(format!("No host failed command {}", number));
+    }
+    let ids: Vec<ShellId> = runs
+        .iter()
+        .map(|run| run.id)
+        .filter(|&id| mgr.get_shell(id).is_some_and(|s| s.state != ShellState::Dead))
+        .collect();
+    if ids.is_empty() {
+        return CmdResult::Error(format!("None of the hosts of command {} is connected", number));
+    }
+    let command = entry.command.clone();
+    console.output(format!("{}\n", command).as_bytes()).await;
+    mgr.dispatch_line_to(&ids, &command, mgr.command_timeout).await;
+    CmdResult::Ok
+}
+
+/// Turn `\n`, `\r`, `\t` and `\\` into the characters they stand for.
+fn unescape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(other) => {
+                if other != '\\' {
+                    out.push('\\');
+                }
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+async fn do_send(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let words = match shell_words::split(params) {
+        Ok(w) => w,
+        Err(e) => return CmdResult::Error(format!("Invalid arguments: {}", e)),
+    };
+    let mut words = words.into_iter();
+    let text = match words.next() {
+        Some(t) => unescape_text(&t),
+        None => return CmdResult::Error("Expected the text to send".into()),
+    };
+    let pattern: String = words.collect::<Vec<String>>().join(" ");
+    // Like a typed line, the text only reaches disabled shells when a pattern names them
+    let ids = if pattern.is_empty() {
+        mgr.enabled_shell_ids()
+    } else {
+        targeted_shell_ids(&pattern, mgr, console).await
+    };
+    for id in ids {
+        if let Some(shell) = mgr.get_shell_mut(id) {
+            shell.write_to_pty(text.as_bytes());
+        }
+    }
+    CmdResult::Ok
+}
+
 async fn do_send_ctrl(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
     let mut split = params.split_whitespace();
     let letter = match split.next() {
@@ -495,14 +1010,42 @@ async fn do_reset_prompt(params: &str, mgr: &mut ShellManager, console: &mut Con
     drop(shells);
     for id in ids {
         if let Some(shell) = mgr.get_shell_mut(id) {
-            shell.rebuild_init_string();
-            let init = shell.init_string.clone();
-            shell.dispatch_command(&init).await;
+            shell.reset_prompt().await;
         }
     }
     CmdResult::Ok
 }
 
+async fn do_attach(params: &str, mgr: &mut ShellManager, console: &mut Console, interactive: bool) -> CmdResult {
+    if !interactive {
+        return CmdResult::Error("Cannot attach in non-interactive mode".to_string());
+    }
+    if params.trim().is_empty() {
+        return CmdResult::Error("Usage: :attach PATTERN".to_string());
+    }
+    let indices = selected_shells_indices(params, mgr, console).await;
+    let shells = mgr.all_shells();
+    let live: Vec<usize> = indices
+        .iter()
+        .copied()
+        .filter(|&i| shells[i].state != ShellState::Dead)
+        .collect();
+    match live.as_slice() {
+        &[i] => CmdResult::Attach(AttachTarget::Shell(shells[i].id)),
+        // Unmatched patterns were already reported
+        [] if indices.is_empty() => CmdResult::Ok,
+        [] => CmdResult::Error(format!("{} only matches dead shells", params.trim())),
+        _ => CmdResult::Error(format!("{} matches {} shells, expected one", params.trim(), live.len())),
+    }
+}
+
+fn do_raw(interactive: bool) -> CmdResult {
+    if !interactive {
+        return CmdResult::Error("Cannot use raw mode in non-interactive mode".to_string());
+    }
+    CmdResult::Attach(AttachTarget::Broadcast)
+}
+
 async fn do_chdir(params: &str, console: &mut Console) -> CmdResult {
     let path = params.trim();
     let path = if path.is_empty() { "~" } else { path };
@@ -568,6 +1111,142 @@ async fn do_set_debug(params: &str, mgr: &mut ShellManager, console: &mut Consol
     CmdResult::Ok
 }
 
+fn do_set_templates(params: &str, mgr: &mut ShellManager) -> CmdResult {
+    match params.trim().to_lowercase().as_str() {
+        "y" => mgr.render_templates = true,
+        "n" => mgr.render_templates = false,
+        other => return CmdResult::Error(format!("Expected 'y' or 'n', got: {}", other)),
+    }
+    CmdResult::Ok
+}
+
+async fn do_set_output(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let mut split = params.split_whitespace();
+    let mode = match (split.next(), split.next()) {
+        (None, _) => {
+            let msg = match mgr.output_mode() {
+                OutputMode::Stream => "Output: stream\n".to_string(),
+                OutputMode::Grouped(BlockOrder::Completion) => "Output: grouped by completion\n".to_string(),
+                OutputMode::Grouped(BlockOrder::Name) => "Output: grouped by name\n".to_string(),
+            };
+            console.output(msg.as_bytes()).await;
+            return CmdResult::Ok;
+        }
+        (Some("stream"), None) => OutputMode::Stream,
+        (Some("grouped"), None) => OutputMode::Grouped(BlockOrder::Completion),
+        (Some("grouped"), Some(order)) => match BlockOrder::parse(order) {
+            Some(order) => OutputMode::Grouped(order),
+            None => return CmdResult::Error(format!("Expected 'completion' or 'name', got: {}", order)),
+        },
+        (Some(other), _) => return CmdResult::Error(format!("Expected 'grouped' or 'stream', got: {}", other)),
+    };
+    // Don't leave half-collected blocks behind when switching modes
+    mgr.flush_output_blocks(console, true).await;
+    mgr.set_output_mode(mode);
+    CmdResult::Ok
+}
+
+fn parse_size(text: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = text.split_once(['x', 'X'])?;
+    let cols = cols.parse().ok().filter(|&c| c > 0)?;
+    let rows = rows.parse().ok().filter(|&r| r > 0)?;
+    Some((cols, rows))
+}
+
+async fn do_set_size(
+    params: &str,
+    mgr: &mut ShellManager,
+    console: &mut Console,
+    display_names: &DisplayNameRegistry,
+) -> CmdResult {
+    let params = params.trim();
+    let (size, pattern) = params.split_once(char::is_whitespace).unwrap_or((params, ""));
+    let fixed_size = match size {
+        "auto" => None,
+        _ => match parse_size(size) {
+            Some(size) => Some(size),
+            None => return CmdResult::Error("Usage: :set_size COLSxROWS|auto [PATTERN]".to_string()),
+        },
+    };
+    let indices = selected_shells_indices(pattern.trim(), mgr, console).await;
+    let shells = mgr.all_shells();
+    let ids: Vec<_> = indices.iter().map(|&i| shells[i].id).collect();
+    drop(shells);
+    let (cols, rows) = pty_spawn::shell_term_size(display_names.max_display_name_length);
+    for id in ids {
+        if let Some(shell) = mgr.get_shell_mut(id) {
+            shell.fixed_size = fixed_size;
+            shell.resize(cols, rows);
+        }
+    }
+    CmdResult::Ok
+}
+
+async fn do_tee(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let dir = params.trim();
+    if dir.is_empty() {
+        let msg = match mgr.output_dir() {
+            Some(dir) => format!("Writing output to {}\n", dir.display()),
+            None => "Not writing output to files\n".to_string(),
+        };
+        console.output(msg.as_bytes()).await;
+        return CmdResult::Ok;
+    }
+    let dir = if dir == "off" {
+        None
+    } else {
+        Some(PathBuf::from(shellexpand::tilde(dir).to_string()))
+    };
+    match mgr.set_output_dir(dir) {
+        Ok(()) => CmdResult::Ok,
+        Err(e) => CmdResult::Error(format!("Failed to write output files: {}", e)),
+    }
+}
+
+fn parse_seconds(text: &str) -> Option<Duration> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+async fn do_timeout(params: &str, mgr: &mut ShellManager) -> CmdResult {
+    let (secs, command) = match params.trim().split_once(char::is_whitespace) {
+        Some((secs, command)) => (secs, command.trim_start()),
+        None => return CmdResult::Error("Expected a timeout and a command".into()),
+    };
+    let timeout = match parse_seconds(secs) {
+        Some(t) => t,
+        None => return CmdResult::Error(format!("Invalid timeout: {}", secs)),
+    };
+
+    mgr.dispatch_line(command, Some(timeout)).await;
+    CmdResult::Ok
+}
+
+async fn do_set_command_timeout(params: &str, mgr: &mut ShellManager, console: &mut Console) -> CmdResult {
+    let value = params.trim();
+    if value.is_empty() {
+        let msg = match mgr.command_timeout {
+            Some(t) => format!("Command timeout: {}s\n", t.as_secs_f64()),
+            None => "Command timeout: off\n".to_string(),
+        };
+        console.output(msg.as_bytes()).await;
+        return CmdResult::Ok;
+    }
+    if value == "off" || value == "0" {
+        mgr.command_timeout = None;
+        return CmdResult::Ok;
+    }
+    match parse_seconds(value) {
+        Some(t) => {
+            mgr.command_timeout = Some(t);
+            CmdResult::Ok
+        }
+        None => CmdResult::Error(format!("Invalid timeout: {}", value)),
+    }
+}
+
 async fn do_export_vars(mgr: &mut ShellManager) -> CmdResult {
     let mut rank = 0usize;
     let shells: Vec<_> = mgr
@@ -619,7 +1298,7 @@ async fn do_show_read_buffer(params: &str, mgr: &mut ShellManager, console: &mut
     let max_name_len = shells
         .iter()
         .filter(|s| s.enabled)
-        .map(|s| s.display_name.len())
+        .map(|s| display_width(&s.display_name))
         .max()
         .unwrap_or(0);
     drop(shells);
@@ -634,3 +1313,43 @@ async fn do_show_read_buffer(params: &str, mgr: &mut ShellManager, console: &mut
     }
     CmdResult::Ok
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("200x50"), Some((200, 50)));
+        assert_eq!(parse_size("80X24"), Some((80, 24)));
+        assert_eq!(parse_size("0x24"), None);
+        assert_eq!(parse_size("200"), None);
+        assert_eq!(parse_size("widex50"), None);
+    }
+
+    #[test]
+    fn test_parse_pipe() {
+        assert_eq!(
+            parse_pipe("'sort -k5 -n' -- df -h /"),
+            Ok(("sort -k5 -n".to_string(), false, "df -h /"))
+        );
+        assert_eq!(
+            parse_pipe("--raw sort -u -- cat /etc/hostname"),
+            Ok(("sort -u".to_string(), true, "cat /etc/hostname"))
+        );
+        assert_eq!(
+            parse_pipe("'grep -- -x' -- ls -- -x"),
+            Ok(("grep -- -x".to_string(), false, "ls -- -x"))
+        );
+        assert!(parse_pipe("sort").is_err());
+        assert!(parse_pipe("--raw -- uptime").is_err());
+    }
+
+    #[test]
+    fn test_unescape_text() {
+        assert_eq!(unescape_text("yes\\n"), "yes\n");
+        assert_eq!(unescape_text("a\\tb\\r"), "a\tb\r");
+        assert_eq!(unescape_text("c:\\\\dir"), "c:\\dir");
+        assert_eq!(unescape_text("keep \\q and \\"), "keep \\q and \\");
+    }
+}
@@ -0,0 +1,325 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::sys::stat::{Mode, umask};
+use serde_json::{Map, Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::shell::ShellState;
+
+/// What happens to the shells, streamed to subscribed clients.
+#[derive(Debug, Clone)]
+pub(crate) enum SessionEvent {
+    Output {
+        name: String,
+        line: String,
+    },
+    State {
+        name: String,
+        state: ShellState,
+        /// Exit code of the command that just finished, when going idle
+        exit_code: Option<i32>,
+    },
+}
+
+impl SessionEvent {
+    pub(crate) fn to_json(&self) -> Value {
+        match self {
+            SessionEvent::Output { name, line } => json!({"event": "output", "name": name, "line": line}),
+            SessionEvent::State { name, state, exit_code } => {
+                json!({"event": "state", "name": name, "state": state.name(), "exit_code": exit_code})
+            }
+        }
+    }
+}
+
+/// A request line sent by a client.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Request {
+    /// Shells with their display name, state and enabled flag
+    List,
+    Enable {
+        pattern: String,
+    },
+    Disable {
+        pattern: String,
+    },
+    /// Any control command, as typed after `:`
+    Control {
+        command: String,
+    },
+    /// Run a command and reply once every shell is done, with its output and exit code
+    Run {
+        command: String,
+        pattern: Option<String>,
+        timeout: Option<Duration>,
+    },
+    /// Stream every `SessionEvent` to the client
+    Subscribe,
+}
+
+impl Request {
+    pub(crate) fn parse(json: &Value) -> Result<Self, String> {
+        let field = |name: &str| json.get(name).and_then(Value::as_str).map(str::to_string);
+        let required = |name: &str| field(name).ok_or_else(|| format!("Missing \"{}\"", name));
+        match json.get("cmd").and_then(Value::as_str) {
+            Some("list") => Ok(Request::List),
+            Some("enable") => Ok(Request::Enable {
+                pattern: field("pattern").unwrap_or_default(),
+            }),
+            Some("disable") => Ok(Request::Disable {
+                pattern: field("pattern").unwrap_or_default(),
+            }),
+            Some("control") => Ok(Request::Control {
+                command: required("command")?,
+            }),
+            Some("run") => {
+                let timeout = match json.get("timeout") {
+                    None | Some(Value::Null) => None,
+                    Some(secs) => Some(
+                        secs.as_f64()
+                            .filter(|secs| secs.is_finite() && *secs > 0.0)
+                            .map(Duration::from_secs_f64)
+                            .ok_or_else(|| format!("Invalid timeout: {}", secs))?,
+                    ),
+                };
+                Ok(Request::Run {
+                    command: required("command")?,
+                    pattern: field("pattern"),
+                    timeout,
+                })
+            }
+            Some("subscribe") => Ok(Request::Subscribe),
+            Some(other) => Err(format!("Unknown cmd: {}", other)),
+            None => Err("Missing \"cmd\"".to_string()),
+        }
+    }
+}
+
+/// Sends the reply to a request, echoing its `id` so clients can match them up.
+#[derive(Debug, Clone)]
+pub(crate) struct Responder {
+    id: Option<Value>,
+    tx: mpsc::UnboundedSender<Value>,
+}
+
+impl Responder {
+    pub(crate) fn ok(&self, fields: Map<String, Value>) {
+        let mut reply = Map::new();
+        if let Some(ref id) = self.id {
+            reply.insert("id".to_string(), id.clone());
+        }
+        reply.insert("ok".to_string(), Value::Bool(true));
+        reply.extend(fields);
+        let _ = self.tx.send(Value::Object(reply));
+    }
+
+    pub(crate) fn error(&self, message: &str) {
+        self.error_with(message, Map::new());
+    }
+
+    pub(crate) fn error_with(&self, message: &str, fields: Map<String, Value>) {
+        let mut reply = Map::new();
+        if let Some(ref id) = self.id {
+            reply.insert("id".to_string(), id.clone());
+        }
+        reply.insert("ok".to_string(), Value::Bool(false));
+        reply.insert("error".to_string(), Value::String(message.to_string()));
+        reply.extend(fields);
+        let _ = self.tx.send(Value::Object(reply));
+    }
+}
+
+/// A request for the main loop, which owns the shells.
+pub(crate) struct SocketRequest {
+    pub(crate) request: Request,
+    pub(crate) responder: Responder,
+}
+
+/// Listens on a Unix socket, removed again on drop.
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Start accepting clients; their requests arrive on the returned channel.
+    pub(crate) fn listen(
+        path: &Path,
+        events: broadcast::Sender<SessionEvent>,
+    ) -> std::io::Result<(Self, mpsc::Receiver<SocketRequest>)> {
+        // A socket left behind by a previous session, anything else is not ours to remove
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        // The socket runs commands on every host, only its owner may connect. It is created that
+        // way rather than chmod'ed afterwards, which would leave a window for others to connect.
+        let old_umask = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(path);
+        umask(old_umask);
+        let listener = listener?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_client(stream, tx.clone(), events.clone()));
+            }
+        });
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+            },
+            rx,
+        ))
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn serve_client(
+    stream: UnixStream,
+    requests: mpsc::Sender<SocketRequest>,
+    events: broadcast::Sender<SessionEvent>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Value>();
+    // Dropped once the client stops sending, so the replies end with the last pending request
+    let mut reply_tx = Some(reply_tx);
+    let mut subscription: Option<broadcast::Receiver<SessionEvent>> = None;
+
+    loop {
+        let out = tokio::select! {
+            line = lines.next_line(), if reply_tx.is_some() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    _ => {
+                        reply_tx = None;
+                        continue;
+                    }
+                };
+                let Some(tx) = reply_tx.as_ref().filter(|_| !line.trim().is_empty()) else {
+                    continue;
+                };
+                let json: Value = match serde_json::from_str(&line) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        let _ = tx.send(json!({"ok": false, "error": format!("Invalid JSON: {}", e)}));
+                        continue;
+                    }
+                };
+                let responder = Responder {
+                    id: json.get("id").cloned(),
+                    tx: tx.clone(),
+                };
+                match Request::parse(&json) {
+                    Ok(Request::Subscribe) => {
+                        subscription = Some(events.subscribe());
+                        responder.ok(Map::new());
+                    }
+                    Ok(request) => {
+                        if requests.send(SocketRequest { request, responder }).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => responder.error(&e),
+                }
+                continue;
+            }
+            // Ends once the client stopped sending and every request was answered
+            Some(reply) = reply_rx.recv() => reply,
+            event = async {
+                match subscription.as_mut() {
+                    Some(rx) => rx.recv().await,
+                    None => std::future::pending().await,
+                }
+            }, if subscription.is_some() => match event {
+                Ok(event) => event.to_json(),
+                Err(broadcast::error::RecvError::Lagged(missed)) => json!({"event": "lagged", "missed": missed}),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            else => break,
+        };
+        let mut line = out.to_string();
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn parse(text: &str) -> Result<Request, String> {
+        Request::parse(&serde_json::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(parse(r#"{"cmd": "list"}"#), Ok(Request::List));
+        assert_eq!(
+            parse(r#"{"cmd": "disable", "pattern": "web*"}"#),
+            Ok(Request::Disable {
+                pattern: "web*".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"{"cmd": "run", "command": "uptime", "timeout": 2.5}"#),
+            Ok(Request::Run {
+                command: "uptime".to_string(),
+                pattern: None,
+                timeout: Some(Duration::from_millis(2500)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_requests() {
+        assert!(parse(r#"{"cmd": "run"}"#).is_err());
+        assert!(parse(r#"{"cmd": "run", "command": "ls", "timeout": -1}"#).is_err());
+        assert!(parse(r#"{"cmd": "explode"}"#).is_err());
+        assert!(parse(r#"{"command": "ls"}"#).is_err());
+    }
+
+    #[test]
+    fn test_responder_echoes_id() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let responder = Responder { id: Some(json!(7)), tx };
+        responder.error("nope");
+        assert_eq!(rx.try_recv().unwrap(), json!({"id": 7, "ok": false, "error": "nope"}));
+    }
+
+    #[tokio::test]
+    async fn test_socket_round_trip() {
+        let dir = std::env::temp_dir().join(format!("mash-socket-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let (events, _) = broadcast::channel(16);
+        let (socket, mut requests) = ControlSocket::listen(&path, events).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"{\"id\": \"a\", \"cmd\": \"list\"}\n").await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.request, Request::List);
+        request.responder.ok(Map::new());
+
+        let mut lines = BufReader::new(reader).lines();
+        let reply: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply, json!({"id": "a", "ok": true}));
+
+        drop(socket);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use unicode_width::UnicodeWidthStr;
+
 pub(crate) struct DisplayNameRegistry {
     prefixes: HashMap<String, Vec<bool>>,
     nr_enabled_by_length: HashMap<usize, usize>,
@@ -92,7 +94,7 @@ impl DisplayNameRegistry {
     }
 
     pub(crate) fn set_enabled(&mut self, display_name: &str, enabled: bool) {
-        let length = display_name.len();
+        let length = display_width(display_name);
         if enabled {
             *self.nr_enabled_by_length.entry(length).or_insert(0) += 1;
         } else {
@@ -106,6 +108,11 @@ impl DisplayNameRegistry {
     }
 }
 
+/// The number of terminal columns a name takes, which is what prefixes are aligned on.
+pub(crate) fn display_width(name: &str) -> usize {
+    UnicodeWidthStr::width(name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,6 +149,15 @@ mod tests {
         assert_eq!(reg.max_display_name_length, 10);
     }
 
+    #[test]
+    fn test_max_length_uses_display_width() {
+        let mut reg = DisplayNameRegistry::new();
+        let _n1 = reg.change(None, Some("münchen"));
+        assert_eq!(reg.max_display_name_length, 7);
+        let _n2 = reg.change(None, Some("東京-1"));
+        assert_eq!(reg.max_display_name_length, 7);
+    }
+
     #[test]
     fn test_max_length_after_removal() {
         let mut reg = DisplayNameRegistry::new();
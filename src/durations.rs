@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+/// `42s`, `10m`, `3h05m` or `2d01h`, for uptimes and ages.
+pub(crate) fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// `0.4s` for short durations, like `format_uptime` for longer ones.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(60) {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format_uptime(duration.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(600), "10m");
+        assert_eq!(format_uptime(3 * 3600 + 5 * 60), "3h05m");
+        assert_eq!(format_uptime(2 * 86400 + 3600), "2d01h");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(420)), "0.4s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m");
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::shell::ShellId;
+
+/// Entries kept for `:history`, dropping the oldest first.
+const MAX_ENTRIES: usize = 1000;
+
+/// How a host finished a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RunOutcome {
+    /// None when the shell died or its prompt did not report one
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) duration: Duration,
+}
+
+/// One host's part in a history entry.
+pub(crate) struct HostRun {
+    pub(crate) id: ShellId,
+    /// Display name when the command was sent
+    pub(crate) name: String,
+    /// None while the command is running
+    pub(crate) outcome: Option<RunOutcome>,
+}
+
+impl HostRun {
+    /// Finished without exit code 0, dying counts as failing.
+    pub(crate) fn failed(&self) -> bool {
+        self.outcome.is_some_and(|o| o.exit_code != Some(0))
+    }
+}
+
+/// A command sent to the shells, for `:history` and `:rerun`.
+pub(crate) struct HistoryEntry {
+    pub(crate) number: usize,
+    pub(crate) sent_at: SystemTime,
+    started: Instant,
+    pub(crate) command: String,
+    pub(crate) hosts: Vec<HostRun>,
+}
+
+impl HistoryEntry {
+    /// A short summary like `3 ok, 1 failed, 2 running`.
+    pub(crate) fn summary(&self) -> String {
+        let running = self.hosts.iter().filter(|r| r.outcome.is_none()).count();
+        let failed = self.hosts.iter().filter(|r| r.failed()).count();
+        let ok = self.hosts.len() - running - failed;
+        let parts: Vec<String> = [(ok, "ok"), (failed, "failed"), (running, "running")]
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, what)| format!("{} {}", count, what))
+            .collect();
+        if parts.is_empty() {
+            "no hosts".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+
+    /// The duration of the slowest host so far.
+    pub(crate) fn duration(&self) -> Duration {
+        if self.hosts.iter().any(|r| r.outcome.is_none()) {
+            return self.started.elapsed();
+        }
+        self.hosts
+            .iter()
+            .filter_map(|r| r.outcome.map(|o| o.duration))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// How long ago the command was sent.
+    pub(crate) fn age(&self) -> Duration {
+        self.sent_at.elapsed().unwrap_or_default()
+    }
+}
+
+/// Every command sent to the shells along with each host's outcome.
+pub(crate) struct CommandHistory {
+    entries: VecDeque<HistoryEntry>,
+    next_number: usize,
+    /// Numbers of the entries with hosts still running the command
+    unfinished: Vec<usize>,
+}
+
+impl CommandHistory {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_number: 1,
+            unfinished: Vec::new(),
+        }
+    }
+
+    /// Add an entry for a command just sent to the given shells, returning its number.
+    pub(crate) fn record(&mut self, command: &str, hosts: Vec<(ShellId, String)>) -> usize {
+        let now = Instant::now();
+        // A shell still busy with an older command reports the next exit code for that one,
+        // there is no telling which command it belongs to
+        for &number in &self.unfinished {
+            if let Some(entry) = self.entries.iter_mut().find(|e| e.number == number) {
+                let started = entry.started;
+                for run in &mut entry.hosts {
+                    if run.outcome.is_none() && hosts.iter().any(|(id, _)| *id == run.id) {
+                        run.outcome = Some(RunOutcome {
+                            exit_code: None,
+                            duration: now - started,
+                        });
+                    }
+                }
+            }
+        }
+        self.forget_finished();
+
+        let number = self.next_number;
+        self.next_number += 1;
+        if !hosts.is_empty() {
+            self.unfinished.push(number);
+        }
+        self.entries.push_back(HistoryEntry {
+            number,
+            sent_at: SystemTime::now(),
+            started: now,
+            command: command.to_string(),
+            hosts: hosts
+                .into_iter()
+                .map(|(id, name)| HostRun {
+                    id,
+                    name,
+                    outcome: None,
+                })
+                .collect(),
+        });
+        if self.entries.len() > MAX_ENTRIES
+            && let Some(dropped) = self.entries.pop_front()
+        {
+            self.unfinished.retain(|&n| n != dropped.number);
+        }
+        number
+    }
+
+    /// Fill in the outcome of the hosts done with their command. `finished` gives the exit code
+    /// of a shell that is done, None while it is still running.
+    pub(crate) fn update(&mut self, finished: impl Fn(ShellId) -> Option<Option<i32>>) {
+        if self.unfinished.is_empty() {
+            return;
+        }
+        for &number in &self.unfinished {
+            if let Some(entry) = self.entries.iter_mut().find(|e| e.number == number) {
+                let elapsed = entry.started.elapsed();
+                for run in entry.hosts.iter_mut().filter(|r| r.outcome.is_none()) {
+                    if let Some(exit_code) = finished(run.id) {
+                        run.outcome = Some(RunOutcome {
+                            exit_code,
+                            duration: elapsed,
+                        });
+                    }
+                }
+            }
+        }
+        self.forget_finished();
+    }
+
+    fn forget_finished(&mut self) {
+        let entries = &self.entries;
+        self.unfinished.retain(|&number| {
+            entries
+                .iter()
+                .find(|e| e.number == number)
+                .is_some_and(|e| e.hosts.iter().any(|r| r.outcome.is_none()))
+        });
+    }
+
+    pub(crate) fn get(&self, number: usize) -> Option<&HistoryEntry> {
+        let first = self.entries.front()?.number;
+        self.entries.get(number.checked_sub(first)?)
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(ids: &[usize]) -> Vec<(ShellId, String)> {
+        ids.iter().map(|&id| (ShellId(id), format!("host{}", id))).collect()
+    }
+
+    #[test]
+    fn test_record_and_update() {
+        let mut history = CommandHistory::new();
+        assert_eq!(history.record("uptime", hosts(&[1, 2, 3])), 1);
+        history.update(|id| match id.0 {
+            1 => Some(Some(0)),
+            2 => Some(Some(2)),
+            _ => None,
+        });
+        let entry = history.get(1).unwrap();
+        assert_eq!(entry.summary(), "1 ok, 1 failed, 1 running");
+        assert!(entry.hosts[1].failed());
+        assert!(!entry.hosts[2].failed());
+
+        // A dead shell fails without an exit code
+        history.update(|_| Some(None));
+        let entry = history.get(1).unwrap();
+        assert_eq!(entry.hosts[2].outcome.unwrap().exit_code, None);
+        assert_eq!(entry.summary(), "1 ok, 2 failed");
+        // Finished hosts keep their outcome
+        assert_eq!(entry.hosts[0].outcome.unwrap().exit_code, Some(0));
+    }
+
+    #[test]
+    fn test_newer_command_closes_older_runs() {
+        let mut history = CommandHistory::new();
+        history.record("sleep 100", hosts(&[1, 2]));
+        history.record("uptime", hosts(&[2]));
+        let first = history.get(1).unwrap();
+        assert!(first.hosts[0].outcome.is_none());
+        assert_eq!(first.hosts[1].outcome.unwrap().exit_code, None);
+        assert!(history.get(2).unwrap().hosts[0].outcome.is_none());
+    }
+
+    #[test]
+    fn test_oldest_entries_are_dropped() {
+        let mut history = CommandHistory::new();
+        for _ in 0..MAX_ENTRIES + 5 {
+            history.record("true", hosts(&[1]));
+        }
+        assert!(history.get(5).is_none());
+        assert_eq!(history.get(6).unwrap().number, 6);
+        assert_eq!(history.entries().count(), MAX_ENTRIES);
+        assert!(history.get(MAX_ENTRIES + 6).is_none());
+    }
+}
@@ -2,8 +2,13 @@ use std::sync::LazyLock;
 
 use regex::Regex;
 
-static SYNTAX_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<([0-9,\-]+)>").unwrap());
-static INTERVAL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([0-9]+)(-[0-9]+)?$").unwrap());
+static SYNTAX_RE: LazyLock<Regex> = LazyLock::new(|| {
+    let interval = r"(?:[0-9]+(?:-[0-9]+)?|[a-zA-Z](?:-[a-zA-Z])?)(?:/[1-9][0-9]*)?";
+    Regex::new(&format!(r"<({interval}(?:,{interval})*)>")).unwrap()
+});
+static INTERVAL_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([0-9]+|[a-zA-Z])(?:-([0-9]+|[a-zA-Z]))?(?:/([0-9]+))?$").unwrap());
+static BRACE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([^{},]*(?:,[^{},]*)+)\}").unwrap());
 
 pub(crate) fn split_port(hostname: &str) -> (String, String) {
     match hostname.split_once(':') {
@@ -12,54 +17,102 @@ pub(crate) fn split_port(hostname: &str) -> (String, String) {
     }
 }
 
-fn iter_numbers(start: &str, end: &str) -> Vec<String> {
+fn iter_numbers(start: &str, end: &str, step: usize) -> Vec<String> {
     let s: i64 = start.parse().unwrap_or(0);
     let e: i64 = end.parse().unwrap_or(0);
     let zero_pad = (start.len() > 1 && start.starts_with('0')) || (end.len() > 1 && end.starts_with('0'));
     let width = start.len().max(end.len());
-    let increment: i64 = if s <= e { 1 } else { -1 };
-
-    let mut results = Vec::new();
-    let mut i = s;
-    loop {
-        let formatted = if zero_pad {
-            format!("{:0>width$}", i, width = width)
-        } else {
-            i.to_string()
-        };
-        results.push(formatted);
-        if i == e {
-            break;
+    let values: Vec<i64> = if s <= e {
+        (s..=e).step_by(step).collect()
+    } else {
+        (e..=s).rev().step_by(step).collect()
+    };
+
+    values
+        .into_iter()
+        .map(|i| {
+            if zero_pad {
+                format!("{:0>width$}", i, width = width)
+            } else {
+                i.to_string()
+            }
+        })
+        .collect()
+}
+
+fn iter_letters(start: char, end: char, step: usize) -> Vec<String> {
+    let (s, e) = (start as u8, end as u8);
+    let values: Vec<u8> = if s <= e {
+        (s..=e).step_by(step).collect()
+    } else {
+        (e..=s).rev().step_by(step).collect()
+    };
+    values.into_iter().map(|c| (c as char).to_string()).collect()
+}
+
+/// Values of a single `<...>` interval: `1-5`, `01-10/3`, `a-f`, `x`...
+fn iter_interval(interval: &str) -> Vec<String> {
+    let Some(caps) = INTERVAL_RE.captures(interval) else {
+        return Vec::new();
+    };
+    let start = caps.get(1).unwrap().as_str();
+    let end = caps.get(2).map(|m| m.as_str()).unwrap_or(start);
+    let step = caps.get(3).and_then(|m| m.as_str().parse().ok()).unwrap_or(1).max(1);
+
+    let numeric = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    match (numeric(start), numeric(end)) {
+        (true, true) => iter_numbers(start, end, step),
+        (false, false) => {
+            let (s, e) = (start.chars().next().unwrap(), end.chars().next().unwrap());
+            if s.is_ascii_lowercase() != e.is_ascii_lowercase() {
+                return Vec::new();
+            }
+            iter_letters(s, e, step)
         }
-        i += increment;
+        _ => Vec::new(),
     }
-    results
 }
 
+/// Expand `<1-3,7>`, `<a-f>`, `<1-100/10>` ranges and `{web,api}` alternatives, leftmost first.
 pub(crate) fn expand_syntax(input: &str) -> Vec<String> {
-    if let Some(m) = SYNTAX_RE.find(input) {
-        let prefix = &input[..m.start()];
-        let suffix = &input[m.end()..];
-        let inner = &input[m.start() + 1..m.end() - 1];
-        let mut results = Vec::new();
-
-        for interval in inner.split(',') {
-            if let Some(caps) = INTERVAL_RE.captures(interval) {
-                let start = caps.get(1).unwrap().as_str();
-                let end = caps
-                    .get(2)
-                    .map(|m| &m.as_str()[1..]) // strip leading '-'
-                    .unwrap_or(start);
-                for num_str in iter_numbers(start, end) {
-                    let combined = format!("{}{}{}", prefix, num_str, suffix);
-                    results.extend(expand_syntax(&combined));
-                }
+    let range = SYNTAX_RE.find(input);
+    let brace = BRACE_RE.find(input);
+    let m = match (range, brace) {
+        (Some(r), Some(b)) => {
+            if b.start() < r.start() {
+                b
+            } else {
+                r
             }
         }
-        results
+        (Some(m), None) | (None, Some(m)) => m,
+        (None, None) => return vec![input.to_string()],
+    };
+
+    let prefix = &input[..m.start()];
+    let suffix = &input[m.end()..];
+    let inner = &input[m.start() + 1..m.end() - 1];
+    let values: Vec<String> = if m.as_str().starts_with('{') {
+        inner.split(',').map(str::to_string).collect()
     } else {
-        vec![input.to_string()]
-    }
+        inner.split(',').flat_map(iter_interval).collect()
+    };
+
+    values
+        .iter()
+        .flat_map(|value| expand_syntax(&format!("{}{}{}", prefix, value, suffix)))
+        .collect()
+}
+
+/// Whether `name` matches an exclusion pattern, which may use the expansion syntax and globs.
+pub(crate) fn is_excluded(name: &str, exclusions: &[String]) -> bool {
+    exclusions
+        .iter()
+        .flat_map(|e| expand_syntax(e))
+        .any(|pattern| match glob::Pattern::new(&pattern) {
+            Ok(p) => p.matches(name),
+            Err(_) => pattern == name,
+        })
 }
 
 #[cfg(test)]
@@ -110,10 +163,10 @@ mod tests {
 
     #[test]
     fn test_nested_expansion() {
-        // Double expansion: prefix<1-2><a-b> should not work (no alpha ranges)
-        // But prefix<1-2> with suffix<3-4> should work
         let result = expand_syntax("h<1-2>s<3-4>");
         assert_eq!(result, vec!["h1s3", "h1s4", "h2s3", "h2s4"]);
+        let result = expand_syntax("prefix<1-2><a-b>");
+        assert_eq!(result, vec!["prefix1a", "prefix1b", "prefix2a", "prefix2b"]);
     }
 
     #[test]
@@ -142,4 +195,53 @@ mod tests {
         let result = expand_syntax("host<1,5,9>");
         assert_eq!(result, vec!["host1", "host5", "host9"]);
     }
+
+    #[test]
+    fn test_alpha_range() {
+        assert_eq!(expand_syntax("rack<a-d>"), vec!["racka", "rackb", "rackc", "rackd"]);
+        assert_eq!(expand_syntax("rack<C-A,x>"), vec!["rackC", "rackB", "rackA", "rackx"]);
+        assert_eq!(expand_syntax("rack<a-C>"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_step() {
+        assert_eq!(
+            expand_syntax("node<1-31/10>"),
+            vec!["node1", "node11", "node21", "node31"]
+        );
+        assert_eq!(expand_syntax("node<001-020/10>"), vec!["node001", "node011"]);
+        assert_eq!(expand_syntax("node<9-1/4>"), vec!["node9", "node5", "node1"]);
+        assert_eq!(expand_syntax("r<a-g/3>"), vec!["ra", "rd", "rg"]);
+    }
+
+    #[test]
+    fn test_braces() {
+        assert_eq!(
+            expand_syntax("{web,api}<1-2>.example.com"),
+            vec![
+                "web1.example.com",
+                "web2.example.com",
+                "api1.example.com",
+                "api2.example.com"
+            ]
+        );
+        assert_eq!(expand_syntax("db{,-replica}"), vec!["db", "db-replica"]);
+        assert_eq!(expand_syntax("{single}"), vec!["{single}"]);
+    }
+
+    #[test]
+    fn test_not_syntax_left_alone() {
+        assert_eq!(expand_syntax("host<abc>"), vec!["host<abc>"]);
+        assert_eq!(expand_syntax("host<1-x>"), vec!["host<1-x>"]);
+    }
+
+    #[test]
+    fn test_is_excluded() {
+        let exclusions = vec!["web<13,17>".to_string(), "db*".to_string()];
+        assert!(is_excluded("web13", &exclusions));
+        assert!(is_excluded("web17", &exclusions));
+        assert!(!is_excluded("web15", &exclusions));
+        assert!(is_excluded("db-replica", &exclusions));
+        assert!(!is_excluded("web1", &[]));
+    }
 }
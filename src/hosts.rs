@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+use owo_colors::DynColors;
+
+use crate::colors::parse_color;
+use crate::host_syntax::{expand_syntax, is_excluded, split_port};
+
+/// A host to connect to, as given on the command line or in a hosts file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HostEntry {
+    pub(crate) host: String,
+    /// Remote user, overriding `--user`
+    pub(crate) user: Option<String>,
+    /// Initial display name instead of the hostname
+    pub(crate) name: Option<String>,
+    /// ssh command template, overriding `--ssh`
+    pub(crate) ssh: Option<String>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) color: Option<DynColors>,
+    pub(crate) vars: BTreeMap<String, String>,
+    /// False for the disabled hosts of a `--session`
+    pub(crate) enabled: bool,
+}
+
+impl HostEntry {
+    pub(crate) fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            user: None,
+            name: None,
+            ssh: None,
+            tags: Vec::new(),
+            color: None,
+            vars: BTreeMap::new(),
+            enabled: true,
+        }
+    }
+
+    /// Set an attribute (`user`, `name`, `ssh`, `port`, `tags`, `color`) or, for any other key, a template variable.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "user" => self.user = Some(value.to_string()),
+            "name" => self.name = Some(value.to_string()),
+            "ssh" => self.ssh = Some(value.to_string()),
+            "port" => {
+                let host = self.host.split_once(':').map_or(self.host.as_str(), |(host, _)| host);
+                self.host = format!("{}:{}", host, value);
+            }
+            "tags" => self
+                .tags
+                .extend(value.split(',').filter(|t| !t.is_empty()).map(str::to_string)),
+            "color" => self.color = Some(parse_color(value).ok_or(format!("invalid color: {}", value))?),
+            _ => {
+                self.vars.insert(key.to_string(), value.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the host syntax (e.g. `web<1-3>`), sharing the attributes between all expanded hosts.
+    pub(crate) fn expand(&self) -> Vec<HostEntry> {
+        expand_syntax(&self.host)
+            .into_iter()
+            .map(|host| HostEntry { host, ..self.clone() })
+            .collect()
+    }
+}
+
+/// A parsed hosts file line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostLine {
+    Host(HostEntry),
+    /// `^PATTERN`: drop matching hosts from the expanded list.
+    Exclude(String),
+}
+
+/// Expand all entries, dropping the hosts matched by an exclusion pattern.
+pub(crate) fn expand_hosts(entries: &[HostEntry], exclusions: &[String]) -> Vec<HostEntry> {
+    entries
+        .iter()
+        .flat_map(HostEntry::expand)
+        .filter(|entry| {
+            let (name, _) = split_port(&entry.host);
+            let tags: Vec<String> = entry.tags.iter().map(|t| format!("tag:{}", t)).collect();
+            !is_excluded(&entry.host, exclusions)
+                && !is_excluded(&name, exclusions)
+                && !tags.iter().any(|t| is_excluded(t, exclusions))
+        })
+        .collect()
+}
+
+/// Drop everything after a `#` that is not inside quotes, except right after the `=` of
+/// a `key=value`, so values such as `color=#ff8000` are kept.
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut prev = ' ';
+    for (idx, c) in line.char_indices() {
+        let after_equals = prev == '=';
+        prev = c;
+        match (quote, c) {
+            (None, '#') if !after_equals => return &line[..idx],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parse a hosts file line: a host followed by optional `key=value` attributes, or a `^PATTERN` exclusion.
+pub(crate) fn parse_line(line: &str) -> Result<Option<HostLine>, String> {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let words = shell_words::split(line).map_err(|e| e.to_string())?;
+    if let Some(pattern) = words.first().and_then(|w| w.strip_prefix('^')) {
+        if pattern.is_empty() || words.len() > 1 {
+            return Err(format!("expected a single ^PATTERN, got: {}", line));
+        }
+        return Ok(Some(HostLine::Exclude(pattern.to_string())));
+    }
+    let mut words = words.into_iter();
+    let mut entry = match words.next() {
+        Some(host) => HostEntry::new(host),
+        None => return Ok(None),
+    };
+    for word in words {
+        match word.split_once('=') {
+            Some((key, value)) if !key.is_empty() => entry.set(key, value)?,
+            _ => return Err(format!("expected key=value, got: {}", word)),
+        }
+    }
+    Ok(Some(HostLine::Host(entry)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_host(line: &str) -> HostEntry {
+        match parse_line(line).unwrap() {
+            Some(HostLine::Host(entry)) => entry,
+            other => panic!("expected a host, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_plain_host() {
+        assert_eq!(parse_host("web1"), HostEntry::new("web1"));
+        assert_eq!(parse_host("  web1:2222  "), HostEntry::new("web1:2222"));
+    }
+
+    #[test]
+    fn test_parse_blank_and_comment() {
+        assert_eq!(parse_line("").unwrap(), None);
+        assert_eq!(parse_line("   # just a comment").unwrap(), None);
+        assert_eq!(parse_host("web1 # trailing"), HostEntry::new("web1"));
+        // Like a `#` anywhere in a word, apart from `key=#value`
+        assert_eq!(parse_host("web1#comment"), HostEntry::new("web1"));
+        assert_eq!(parse_host("web1 role=db#old"), parse_host("web1 role=db"));
+    }
+
+    #[test]
+    fn test_parse_vars() {
+        let entry = parse_host("db1 role=primary dc=\"eu west\"");
+        assert_eq!(entry.host, "db1");
+        assert_eq!(entry.vars.get("role").map(String::as_str), Some("primary"));
+        assert_eq!(entry.vars.get("dc").map(String::as_str), Some("eu west"));
+    }
+
+    #[test]
+    fn test_parse_hash_inside_quotes() {
+        let entry = parse_host("db1 note=\"rack #4\" # comment");
+        assert_eq!(entry.vars.get("note").map(String::as_str), Some("rack #4"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_line("db1 novalue").is_err());
+        assert!(parse_line("db1 =value").is_err());
+        assert!(parse_line("db1 key=\"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_expand_keeps_vars() {
+        let entry = parse_host("web<1-2> role=frontend");
+        let expanded = entry.expand();
+        assert_eq!(expanded.len(), 2);
+        assert_eq!(expanded[1].host, "web2");
+        assert_eq!(expanded[1].vars.get("role").map(String::as_str), Some("frontend"));
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let entry =
+            parse_host("db1.example.com:2222 user=postgres name=db-primary ssh=\"ssh -J bastion\" tags=db,primary");
+        assert_eq!(entry.host, "db1.example.com:2222");
+        assert_eq!(entry.user.as_deref(), Some("postgres"));
+        assert_eq!(entry.name.as_deref(), Some("db-primary"));
+        assert_eq!(entry.ssh.as_deref(), Some("ssh -J bastion"));
+        assert_eq!(entry.tags, vec!["db", "primary"]);
+        assert!(entry.vars.is_empty());
+
+        assert_eq!(parse_host("db1 color=#ff8000").color, Some(DynColors::Rgb(255, 128, 0)));
+        assert!(parse_line("db1 color=mauve").is_err());
+        assert_eq!(parse_host("db1:2222 port=5432").host, "db1:5432");
+        assert_eq!(parse_host("db1 port=5432").host, "db1:5432");
+    }
+
+    #[test]
+    fn test_parse_exclusion() {
+        assert_eq!(
+            parse_line("^web<13,17>  # broken").unwrap(),
+            Some(HostLine::Exclude("web<13,17>".into()))
+        );
+        assert!(parse_line("^").is_err());
+        assert!(parse_line("^web1 web2").is_err());
+    }
+
+    #[test]
+    fn test_expand_hosts_with_exclusions() {
+        let mut tagged = HostEntry::new("cache1");
+        tagged.tags = vec!["broken".into()];
+        let entries = vec![HostEntry::new("web<1-5>"), HostEntry::new("db1:2222"), tagged];
+        let exclusions = vec!["web<2,4>".into(), "db*".into(), "tag:broken".into()];
+        let hosts: Vec<String> = expand_hosts(&entries, &exclusions)
+            .into_iter()
+            .map(|e| e.host)
+            .collect();
+        assert_eq!(hosts, vec!["web1", "web3", "web5"]);
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
 use rustyline::completion::Completer;
@@ -5,10 +7,14 @@ use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
 use rustyline::validate::Validator;
-use rustyline::{CompletionType, Config, Editor, Helper};
+use rustyline::{
+    Cmd, CompletionType, ConditionalEventHandler, Config, Editor, Event, EventContext, EventHandler, Helper, KeyCode,
+    KeyEvent, Modifiers, RepeatCount,
+};
 use tokio::sync::mpsc;
 
 use crate::completion::{self, CompletionState};
+use crate::remote_completion::RemoteCompleter;
 
 #[derive(Clone)]
 pub(crate) enum InputRequest {
@@ -21,10 +27,13 @@ pub(crate) enum InputEvent {
     Line(String),
     Eof,
     Interrupted,
+    /// The raw mode hotkey was pressed
+    RawMode,
 }
 
 struct MashHelper {
     state: Arc<RwLock<CompletionState>>,
+    remote: RemoteCompleter,
 }
 
 impl Helper for MashHelper {}
@@ -34,6 +43,19 @@ impl Hinter for MashHelper {
 }
 impl Validator for MashHelper {}
 
+/// F2 leaves readline to switch to raw mode. rustyline has no command for that, so the
+/// handler raises a flag and interrupts the line.
+struct RawModeHotkey {
+    pressed: Arc<AtomicBool>,
+}
+
+impl ConditionalEventHandler for RawModeHotkey {
+    fn handle(&self, _evt: &Event, _n: RepeatCount, _positive: bool, _ctx: &EventContext) -> Option<Cmd> {
+        self.pressed.store(true, Ordering::SeqCst);
+        Some(Cmd::Interrupt)
+    }
+}
+
 impl Completer for MashHelper {
     type Candidate = String;
 
@@ -43,22 +65,28 @@ impl Completer for MashHelper {
         pos: usize,
         _ctx: &rustyline::Context<'_>,
     ) -> rustyline::Result<(usize, Vec<String>)> {
-        let state = match self.state.read() {
-            Ok(s) => s,
-            Err(_) => return Ok((0, Vec::new())),
-        };
-
         // Find the start of the current word
         let start = line[..pos].rfind([' ', '\t']).map(|i| i + 1).unwrap_or(0);
         let text = &line[start..pos];
 
-        let candidates = completion::complete_line(line, text, &state);
+        // Asked before taking the lock, the main loop may need it to answer
+        let remote = completion::remote_request(line, text)
+            .map(|kind| self.remote.complete(kind, text))
+            .unwrap_or_default();
+
+        let state = match self.state.read() {
+            Ok(s) => s,
+            Err(_) => return Ok((0, Vec::new())),
+        };
+        let candidates = completion::complete_line(line, text, &state, &remote);
         Ok((start, candidates))
     }
 }
 
 pub(crate) fn spawn_input_thread(
     completion_state: Arc<RwLock<CompletionState>>,
+    remote: RemoteCompleter,
+    histfile: PathBuf,
 ) -> (mpsc::Sender<InputRequest>, mpsc::Receiver<InputEvent>) {
     let (req_tx, mut req_rx) = mpsc::channel::<InputRequest>(1);
     let (resp_tx, resp_rx) = mpsc::channel::<InputEvent>(1);
@@ -68,11 +96,28 @@ pub(crate) fn spawn_input_thread(
         let mut rl = Editor::with_config(config).unwrap();
         let helper = MashHelper {
             state: completion_state,
+            remote,
         };
         rl.set_helper(Some(helper));
-
-        let histfile = dirs_histfile();
-        rl.load_history(&histfile).ok();
+        let raw_mode_pressed = Arc::new(AtomicBool::new(false));
+        let hotkey = RawModeHotkey {
+            pressed: raw_mode_pressed.clone(),
+        };
+        rl.bind_sequence(
+            KeyEvent(KeyCode::F(2), Modifiers::NONE),
+            EventHandler::Conditional(Box::new(hotkey)),
+        );
+
+        if histfile.exists() {
+            rl.load_history(&histfile).ok();
+        } else {
+            // The first new set of hosts takes over the history kept before it was split per set,
+            // moved aside so that it is not copied into every set that follows
+            let legacy = legacy_history_file();
+            if rl.load_history(&legacy).is_ok() {
+                std::fs::rename(&legacy, legacy.with_extension("migrated")).ok();
+            }
+        }
 
         while let Some(req) = req_rx.blocking_recv() {
             match req {
@@ -84,6 +129,9 @@ pub(crate) fn spawn_input_thread(
                     Err(ReadlineError::Eof) => {
                         resp_tx.blocking_send(InputEvent::Eof).ok();
                     }
+                    Err(ReadlineError::Interrupted) if raw_mode_pressed.swap(false, Ordering::SeqCst) => {
+                        resp_tx.blocking_send(InputEvent::RawMode).ok();
+                    }
                     Err(ReadlineError::Interrupted) => {
                         resp_tx.blocking_send(InputEvent::Interrupted).ok();
                     }
@@ -92,6 +140,9 @@ pub(crate) fn spawn_input_thread(
                     }
                 },
                 InputRequest::Shutdown => {
+                    if let Some(dir) = histfile.parent() {
+                        std::fs::create_dir_all(dir).ok();
+                    }
                     rl.save_history(&histfile).ok();
                     break;
                 }
@@ -102,8 +153,48 @@ pub(crate) fn spawn_input_thread(
     (req_tx, resp_rx)
 }
 
-fn dirs_histfile() -> String {
+/// The readline history of a set of hosts, kept apart from the other sets so that commands
+/// typed for prod are not recalled for staging: `~/.mash_history.d/<hash of the hosts>`.
+pub(crate) fn history_file<'a>(hosts: impl IntoIterator<Item = &'a str>) -> PathBuf {
+    let dir = etcetera::home_dir()
+        .map(|d| d.join(".mash_history.d"))
+        .unwrap_or_else(|_| PathBuf::from(".mash_history.d"));
+    dir.join(host_set_key(hosts))
+}
+
+/// `~/.mash_history`, shared by every set of hosts before `~/.mash_history.d`. Renamed to
+/// `~/.mash_history.migrated` once imported.
+fn legacy_history_file() -> PathBuf {
     etcetera::home_dir()
-        .map(|d| d.join(".mash_history").to_string_lossy().to_string())
-        .unwrap_or_else(|_| ".mash_history".to_string())
+        .map(|d| d.join(".mash_history"))
+        .unwrap_or_else(|_| PathBuf::from(".mash_history"))
+}
+
+/// FNV-1a of the sorted host names, which stays the same across builds unlike `DefaultHasher`.
+fn host_set_key<'a>(hosts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hosts: Vec<&str> = hosts.into_iter().collect();
+    hosts.sort_unstable();
+    hosts.dedup();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for host in hosts {
+        for &byte in host.as_bytes().iter().chain(b"\n") {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_set_key() {
+        let key = host_set_key(["web1", "web2"]);
+        assert_eq!(key.len(), 16);
+        assert_eq!(key, host_set_key(["web2", "web1", "web2"]));
+        assert_ne!(key, host_set_key(["web1"]));
+        assert_ne!(host_set_key(["ab", "c"]), host_set_key(["a", "bc"]));
+    }
 }
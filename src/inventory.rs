@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+use crate::hosts::HostEntry;
+
+/// Ansible host ranges: `web[01:50]`, `db-[a:f]`, `node[1:100:10]`.
+static ANSIBLE_RANGE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[([0-9]+|[a-zA-Z]):([0-9]+|[a-zA-Z])(?::([0-9]+))?\]").unwrap());
+
+type Vars = BTreeMap<String, String>;
+
+#[derive(Debug, Default)]
+struct Group {
+    hosts: Vec<String>,
+    vars: Vars,
+    children: Vec<String>,
+}
+
+/// Groups and host variables as written in the inventory, before inheritance is resolved.
+#[derive(Debug, Default)]
+pub(crate) struct Inventory {
+    groups: BTreeMap<String, Group>,
+    host_vars: BTreeMap<String, Vars>,
+    /// Hosts in the order they first appear
+    host_order: Vec<String>,
+}
+
+/// Rewrite Ansible host ranges into the mash host syntax.
+fn convert_ranges(name: &str) -> String {
+    ANSIBLE_RANGE_RE
+        .replace_all(name, |caps: &Captures| match caps.get(3) {
+            Some(step) => format!("<{}-{}/{}>", &caps[1], &caps[2], step.as_str()),
+            None => format!("<{}-{}>", &caps[1], &caps[2]),
+        })
+        .into_owned()
+}
+
+fn parse_key_values(words: &[String]) -> Result<Vars, String> {
+    let mut vars = Vars::new();
+    for word in words {
+        match word.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                vars.insert(key.to_string(), value.to_string());
+            }
+            _ => return Err(format!("expected key=value, got: {}", word)),
+        }
+    }
+    Ok(vars)
+}
+
+impl Inventory {
+    /// Load an inventory, picking the format from the extension (`.json`, `.yml`/`.yaml`, INI otherwise).
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Error reading inventory {}: {}", path, e))?;
+        let parsed = if path.ends_with(".json") {
+            Self::parse_json(&content)
+        } else if path.ends_with(".yml") || path.ends_with(".yaml") {
+            Self::parse_yaml(&content)
+        } else {
+            Self::parse_ini(&content)
+        };
+        parsed.map_err(|e| format!("Error in inventory {}: {}", path, e))
+    }
+
+    fn group_mut(&mut self, name: &str) -> &mut Group {
+        self.groups.entry(name.to_string()).or_default()
+    }
+
+    fn add_host(&mut self, group: &str, host: &str, vars: Vars) {
+        let host = convert_ranges(host);
+        if !self.host_order.contains(&host) {
+            self.host_order.push(host.clone());
+        }
+        self.host_vars.entry(host.clone()).or_default().extend(vars);
+        let group = self.group_mut(group);
+        if !group.hosts.contains(&host) {
+            group.hosts.push(host);
+        }
+    }
+
+    pub(crate) fn parse_ini(content: &str) -> Result<Self, String> {
+        enum Section {
+            Hosts(String),
+            Vars(String),
+            Children(String),
+        }
+
+        let mut inventory = Self::default();
+        let mut section = Section::Hosts("ungrouped".to_string());
+        for (nr, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let (name, kind) = header.split_once(':').unwrap_or((header, ""));
+                section = match kind {
+                    "" => Section::Hosts(name.to_string()),
+                    "vars" => Section::Vars(name.to_string()),
+                    "children" => Section::Children(name.to_string()),
+                    _ => return Err(format!("line {}: unknown section type: {}", nr + 1, kind)),
+                };
+                inventory.group_mut(name);
+                continue;
+            }
+
+            let words = shell_words::split(line).map_err(|e| format!("line {}: {}", nr + 1, e))?;
+            match &section {
+                Section::Hosts(group) => {
+                    let vars = parse_key_values(&words[1..]).map_err(|e| format!("line {}: {}", nr + 1, e))?;
+                    inventory.add_host(group, &words[0], vars);
+                }
+                Section::Vars(group) => {
+                    let vars = parse_key_values(&words).map_err(|e| format!("line {}: {}", nr + 1, e))?;
+                    inventory.group_mut(group).vars.extend(vars);
+                }
+                Section::Children(group) => {
+                    let child = words[0].clone();
+                    inventory.group_mut(&child);
+                    inventory.group_mut(group).children.push(child);
+                }
+            }
+        }
+        Ok(inventory)
+    }
+
+    pub(crate) fn parse_yaml(content: &str) -> Result<Self, String> {
+        let root: serde_yaml::Value = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+        // YAML and JSON share the same structure, so go through a single walker
+        let root: serde_json::Value = serde_json::to_value(root).map_err(|e| e.to_string())?;
+        let groups = root.as_object().ok_or("expected a mapping of groups")?;
+
+        let mut inventory = Self::default();
+        for (name, group) in groups {
+            inventory.walk_group(name, group)?;
+        }
+        Ok(inventory)
+    }
+
+    /// Walk an Ansible style group: `{hosts: {name: {vars}}, vars: {...}, children: {name: group}}`.
+    fn walk_group(&mut self, name: &str, group: &serde_json::Value) -> Result<(), String> {
+        self.group_mut(name);
+        if group.is_null() {
+            return Ok(());
+        }
+        let group = group.as_object().ok_or(format!("group {} is not a mapping", name))?;
+
+        match group.get("hosts") {
+            Some(serde_json::Value::Object(hosts)) => {
+                for (host, vars) in hosts {
+                    self.add_host(name, host, scalar_vars(vars));
+                }
+            }
+            Some(serde_json::Value::Array(hosts)) => {
+                for host in hosts.iter().filter_map(|h| h.as_str()) {
+                    self.add_host(name, host, Vars::new());
+                }
+            }
+            Some(serde_json::Value::Null) | None => {}
+            Some(_) => return Err(format!("hosts of group {} must be a mapping or a list", name)),
+        }
+        if let Some(vars) = group.get("vars") {
+            let vars = scalar_vars(vars);
+            self.group_mut(name).vars.extend(vars);
+        }
+        match group.get("children") {
+            Some(serde_json::Value::Object(children)) => {
+                for (child, child_group) in children {
+                    self.group_mut(name).children.push(child.clone());
+                    self.walk_group(child, child_group)?;
+                }
+            }
+            Some(serde_json::Value::Array(children)) => {
+                for child in children.iter().filter_map(|c| c.as_str()) {
+                    self.group_mut(name).children.push(child.to_string());
+                    self.group_mut(child);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// JSON inventory: `{"group": ["h1", "h2"]}`, or the Ansible dynamic inventory layout
+    /// with `{"group": {"hosts": [...], "vars": {...}, "children": [...]}, "_meta": {"hostvars": {...}}}`.
+    pub(crate) fn parse_json(content: &str) -> Result<Self, String> {
+        let root: serde_json::Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let groups = root.as_object().ok_or("expected an object of groups")?;
+
+        let mut inventory = Self::default();
+        for (name, group) in groups {
+            match group {
+                _ if name == "_meta" => {}
+                serde_json::Value::Array(hosts) => {
+                    for host in hosts.iter().filter_map(|h| h.as_str()) {
+                        inventory.add_host(name, host, Vars::new());
+                    }
+                }
+                _ => inventory.walk_group(name, group)?,
+            }
+        }
+        if let Some(hostvars) = root.pointer("/_meta/hostvars").and_then(|v| v.as_object()) {
+            for (host, vars) in hostvars {
+                inventory
+                    .host_vars
+                    .entry(host.clone())
+                    .or_default()
+                    .extend(scalar_vars(vars));
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// Groups containing `group` as a child.
+    fn parents(&self, group: &str) -> Vec<&str> {
+        self.groups
+            .iter()
+            .filter(|(_, g)| g.children.iter().any(|c| c == group))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Distance from the top of the group tree; shallower groups have lower variable precedence.
+    fn depth(&self, group: &str, seen: &mut Vec<String>) -> usize {
+        if seen.iter().any(|s| s == group) {
+            return 0;
+        }
+        seen.push(group.to_string());
+        let depth = self
+            .parents(group)
+            .into_iter()
+            .map(|parent| self.depth(parent, seen) + 1)
+            .max()
+            .unwrap_or(0);
+        seen.pop();
+        depth
+    }
+
+    /// All groups of a host, including the ones inherited through `children`.
+    fn host_groups(&self, host: &str) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, g)| g.hosts.iter().any(|h| h == host))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut idx = 0;
+        while idx < groups.len() {
+            for parent in self.parents(&groups[idx]) {
+                if !groups.iter().any(|g| g == parent) {
+                    groups.push(parent.to_string());
+                }
+            }
+            idx += 1;
+        }
+        groups
+    }
+
+    fn group_contains(&self, group: &str, host: &str) -> bool {
+        self.host_groups(host).iter().any(|g| g == group)
+    }
+
+    pub(crate) fn has_group(&self, group: &str) -> bool {
+        self.groups.contains_key(group)
+    }
+
+    /// Host entries, optionally limited to a group (`all` matches every host).
+    /// `ansible_host`, `ansible_port` and `ansible_user` map onto the host, port and user.
+    pub(crate) fn entries(&self, group: Option<&str>) -> Vec<HostEntry> {
+        self.host_order
+            .iter()
+            .filter(|host| match group {
+                None | Some("all") => true,
+                Some(group) => self.group_contains(group, host),
+            })
+            .map(|host| self.entry(host))
+            .collect()
+    }
+
+    fn entry(&self, host: &str) -> HostEntry {
+        let mut groups = self.host_groups(host);
+        groups.sort_by_cached_key(|g| (self.depth(g, &mut Vec::new()), g.clone()));
+
+        let mut vars = self.groups.get("all").map(|g| g.vars.clone()).unwrap_or_default();
+        for group in &groups {
+            vars.extend(self.groups[group].vars.clone());
+        }
+        if let Some(host_vars) = self.host_vars.get(host) {
+            vars.extend(host_vars.clone());
+        }
+
+        let get = |keys: &[&str]| keys.iter().find_map(|k| vars.get(*k).cloned());
+        let hostname = get(&["ansible_host", "ansible_ssh_host"]).unwrap_or_else(|| host.to_string());
+        let mut entry = HostEntry::new(match get(&["ansible_port", "ansible_ssh_port"]) {
+            Some(port) => format!("{}:{}", hostname, port),
+            None => hostname,
+        });
+        entry.user = get(&["ansible_user", "ansible_ssh_user"]);
+        entry.tags = groups.into_iter().filter(|g| g != "all" && g != "ungrouped").collect();
+        entry.vars = vars;
+        entry.vars.insert("inventory_hostname".to_string(), host.to_string());
+        entry
+    }
+}
+
+/// The scalar values of a mapping, as strings; nested values are ignored.
+fn scalar_vars(value: &serde_json::Value) -> Vars {
+    let mut vars = Vars::new();
+    if let Some(map) = value.as_object() {
+        for (key, value) in map {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                _ => continue,
+            };
+            vars.insert(key.clone(), value);
+        }
+    }
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(entries: &[HostEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.host.as_str()).collect()
+    }
+
+    #[test]
+    fn test_convert_ranges() {
+        assert_eq!(convert_ranges("web[01:50].example.com"), "web<01-50>.example.com");
+        assert_eq!(convert_ranges("db-[a:f]"), "db-<a-f>");
+        assert_eq!(convert_ranges("node[1:100:10]"), "node<1-100/10>");
+    }
+
+    #[test]
+    fn test_parse_ini() {
+        let content = "\
+bastion ansible_host=10.0.0.1
+[web]
+web1 ansible_port=2222
+web2 ansible_user=deploy
+
+[db]
+db1
+
+[prod:children]
+web
+db
+
+[prod:vars]
+ansible_user=admin
+dc=eu
+";
+        let inventory = Inventory::parse_ini(content).unwrap();
+        let all = inventory.entries(None);
+        assert_eq!(hosts(&all), vec!["10.0.0.1", "web1:2222", "web2", "db1"]);
+        assert_eq!(all[0].vars["inventory_hostname"], "bastion");
+        assert_eq!(all[1].user.as_deref(), Some("admin"));
+        // Host variables win over group variables
+        assert_eq!(all[2].user.as_deref(), Some("deploy"));
+        assert_eq!(all[3].vars["dc"], "eu");
+
+        assert_eq!(
+            hosts(&inventory.entries(Some("prod"))),
+            vec!["web1:2222", "web2", "db1"]
+        );
+        assert_eq!(hosts(&inventory.entries(Some("db"))), vec!["db1"]);
+        assert_eq!(all[3].tags, vec!["prod", "db"]);
+        assert!(inventory.entries(Some("nope")).is_empty());
+    }
+
+    #[test]
+    fn test_parse_ini_errors() {
+        assert!(Inventory::parse_ini("[web:bogus]\n").is_err());
+        assert!(Inventory::parse_ini("[web]\nweb1 novalue\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let content = "
+all:
+  vars:
+    dc: us
+  children:
+    web:
+      hosts:
+        web[1:2]:
+          ansible_user: www
+      vars:
+        dc: eu
+    db:
+      hosts:
+        db1:
+          ansible_host: 10.0.0.5
+          ansible_port: 2222
+";
+        let inventory = Inventory::parse_yaml(content).unwrap();
+        let all = inventory.entries(None);
+        assert_eq!(hosts(&all), vec!["web<1-2>", "10.0.0.5:2222"]);
+        assert_eq!(all[0].user.as_deref(), Some("www"));
+        // The more specific group wins
+        assert_eq!(all[0].vars["dc"], "eu");
+        assert_eq!(all[1].vars["dc"], "us");
+        assert_eq!(hosts(&inventory.entries(Some("db"))), vec!["10.0.0.5:2222"]);
+        assert!(inventory.has_group("web"));
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let simple = Inventory::parse_json(r#"{"web": ["web1", "web2"], "db": ["db1"]}"#).unwrap();
+        assert_eq!(hosts(&simple.entries(Some("web"))), vec!["web1", "web2"]);
+        assert_eq!(simple.entries(None).len(), 3);
+
+        let dynamic = Inventory::parse_json(
+            r#"{"web": {"hosts": ["web1"], "vars": {"role": "front"}},
+                "prod": {"children": ["web"]},
+                "_meta": {"hostvars": {"web1": {"ansible_host": "1.2.3.4"}}}}"#,
+        )
+        .unwrap();
+        let prod = dynamic.entries(Some("prod"));
+        assert_eq!(hosts(&prod), vec!["1.2.3.4"]);
+        assert_eq!(prod[0].vars["role"], "front");
+    }
+}
@@ -0,0 +1,397 @@
+use unicode_width::UnicodeWidthChar;
+use vte::{Params, Parser, Perform};
+
+/// What to do with the SGR (color and style) sequences remote programs print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RemoteColors {
+    Keep,
+    Strip,
+}
+
+impl RemoteColors {
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        match text {
+            "keep" => Some(Self::Keep),
+            "strip" => Some(Self::Strip),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with bytes that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InvalidUtf8 {
+    /// Replace them with U+FFFD
+    Lossy,
+    /// Show them as `\xNN`
+    Hex,
+    /// Pass them through unchanged
+    Raw,
+}
+
+impl InvalidUtf8 {
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        match text {
+            "lossy" => Some(Self::Lossy),
+            "hex" => Some(Self::Hex),
+            "raw" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Cell {
+    /// The character's bytes, empty for the second column of a wide character
+    text: Vec<u8>,
+    /// SGR sequences received since the previous character
+    sgr: String,
+}
+
+impl Cell {
+    fn blank() -> Self {
+        Self {
+            text: b" ".to_vec(),
+            sgr: String::new(),
+        }
+    }
+
+    fn is_continuation(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+/// The line being drawn by the remote side, as the terminal would show it.
+struct LineState {
+    remote_colors: RemoteColors,
+    /// One cell per terminal column
+    cells: Vec<Cell>,
+    cursor: usize,
+    /// SGR sequences waiting for the next character
+    pending_sgr: String,
+    /// Whether the line set any SGR attribute, so it needs a reset at the end
+    styled: bool,
+    /// Rendered lines, each terminated by `\n`
+    finished: Vec<u8>,
+}
+
+impl LineState {
+    fn render(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for cell in &self.cells {
+            out.extend_from_slice(cell.sgr.as_bytes());
+            out.extend_from_slice(&cell.text);
+        }
+        if self.styled {
+            out.extend_from_slice(b"\x1b[0m");
+        }
+        out
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+        self.cursor = 0;
+        self.pending_sgr.clear();
+        self.styled = false;
+    }
+
+    fn finish_line(&mut self) {
+        let line = self.render();
+        self.finished.extend_from_slice(&line);
+        self.finished.push(b'\n');
+        self.clear();
+    }
+
+    /// Write a cell at `idx`, blanking what is left of a wide character it overwrites.
+    fn set_cell(&mut self, idx: usize, cell: Cell) {
+        if idx == self.cells.len() {
+            self.cells.push(cell);
+            return;
+        }
+        if self.cells[idx].is_continuation() && idx > 0 {
+            self.cells[idx - 1] = Cell::blank();
+        }
+        if self.cells.get(idx + 1).is_some_and(Cell::is_continuation) {
+            self.cells[idx + 1] = Cell::blank();
+        }
+        self.cells[idx] = cell;
+    }
+
+    /// Draw a character taking `width` columns at the cursor.
+    fn put_text(&mut self, text: Vec<u8>, width: usize) {
+        if width == 0 {
+            // Combining characters join the previous cell
+            if let Some(cell) = self.cursor.checked_sub(1).and_then(|idx| self.cells.get_mut(idx)) {
+                cell.text.extend_from_slice(&text);
+            }
+            return;
+        }
+        if self.cursor > self.cells.len() {
+            self.cells.resize(self.cursor, Cell::blank());
+        }
+        let cell = Cell {
+            text,
+            sgr: std::mem::take(&mut self.pending_sgr),
+        };
+        self.set_cell(self.cursor, cell);
+        for idx in self.cursor + 1..self.cursor + width {
+            let continuation = Cell {
+                text: Vec::new(),
+                sgr: String::new(),
+            };
+            self.set_cell(idx, continuation);
+        }
+        self.cursor += width;
+    }
+
+    fn put_char(&mut self, ch: char) {
+        let width = UnicodeWidthChar::width(ch).unwrap_or(0);
+        self.put_text(ch.to_string().into_bytes(), width);
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        match mode {
+            // Cursor to end of line
+            0 => self.cells.truncate(self.cursor),
+            // Start of line to cursor
+            1 => {
+                let end = (self.cursor + 1).min(self.cells.len());
+                self.cells[..end].fill(Cell::blank());
+            }
+            _ => self.cells.clear(),
+        }
+    }
+}
+
+impl Perform for LineState {
+    fn print(&mut self, ch: char) {
+        self.put_char(ch);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' | 0x0b | 0x0c => self.finish_line(),
+            b'\r' => self.cursor = 0,
+            0x08 => self.cursor = self.cursor.saturating_sub(1),
+            b'\t' => self.put_text(b"\t".to_vec(), 1),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+        let first = params.iter().next().and_then(|p| p.first().copied()).unwrap_or(0);
+        // Movement counts default to 1 when omitted or zero
+        let count = first.max(1) as usize;
+        match action {
+            'K' => self.erase_in_line(first),
+            'D' => self.cursor = self.cursor.saturating_sub(count),
+            'C' => self.cursor += count,
+            'G' => self.cursor = count - 1,
+            'P' => {
+                let end = (self.cursor + count).min(self.cells.len());
+                if self.cursor < end {
+                    self.cells.drain(self.cursor..end);
+                }
+            }
+            '@' if self.cursor < self.cells.len() => {
+                let blanks = std::iter::repeat_n(Cell::blank(), count);
+                self.cells.splice(self.cursor..self.cursor, blanks);
+            }
+            'X' => {
+                let end = (self.cursor + count).min(self.cells.len());
+                if self.cursor < end {
+                    self.cells[self.cursor..end].fill(Cell::blank());
+                }
+            }
+            'm' if self.remote_colors == RemoteColors::Keep => {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|p| p.iter().map(u16::to_string).collect::<Vec<_>>().join(":"))
+                    .collect();
+                self.pending_sgr.push_str(&format!("\x1b[{}m", params.join(";")));
+                self.styled = true;
+            }
+            // Everything else (screen movement, modes, queries) has no meaning on a single line
+            _ => {}
+        }
+    }
+}
+
+/// Interprets the terminal control sequences in a shell's output and produces plain
+/// lines: carriage returns and erase sequences rewrite the current line (so progress
+/// bars collapse to their final state) and other escape sequences are dropped.
+pub(crate) struct LineDiscipline {
+    parser: Parser,
+    state: LineState,
+    invalid_utf8: InvalidUtf8,
+    /// The start of a UTF-8 sequence whose remaining bytes have not been read yet
+    partial_utf8: Vec<u8>,
+}
+
+impl LineDiscipline {
+    pub(crate) fn new(remote_colors: RemoteColors, invalid_utf8: InvalidUtf8) -> Self {
+        Self {
+            parser: Parser::new(),
+            state: LineState {
+                remote_colors,
+                cells: Vec::new(),
+                cursor: 0,
+                pending_sgr: String::new(),
+                styled: false,
+                finished: Vec::new(),
+            },
+            invalid_utf8,
+            partial_utf8: Vec::new(),
+        }
+    }
+
+    /// Feed raw output, returning the lines it completed, each terminated by `\n`.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.partial_utf8);
+        buf.extend_from_slice(data);
+        let mut rest = &buf[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(_) => {
+                    self.parser.advance(&mut self.state, rest);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    self.parser.advance(&mut self.state, valid);
+                    let Some(len) = e.error_len() else {
+                        // Truncated by the read, the rest of the sequence comes with the next one
+                        self.partial_utf8 = invalid.to_vec();
+                        break;
+                    };
+                    self.put_invalid(&invalid[..len]);
+                    rest = &invalid[len..];
+                }
+            }
+        }
+        std::mem::take(&mut self.state.finished)
+    }
+
+    fn put_invalid(&mut self, bytes: &[u8]) {
+        match self.invalid_utf8 {
+            InvalidUtf8::Lossy => self.state.put_char(char::REPLACEMENT_CHARACTER),
+            InvalidUtf8::Hex => {
+                for b in bytes {
+                    for ch in format!("\\x{:02x}", b).chars() {
+                        self.state.put_char(ch);
+                    }
+                }
+            }
+            InvalidUtf8::Raw => {
+                for &b in bytes {
+                    self.state.put_text(vec![b], 1);
+                }
+            }
+        }
+    }
+
+    /// The current unfinished line as it is displayed.
+    pub(crate) fn pending_line(&self) -> Vec<u8> {
+        self.state.render()
+    }
+
+    /// Take the current unfinished line, starting a new one. An incomplete UTF-8
+    /// sequence is kept for the next read rather than flushed in halves.
+    pub(crate) fn take_pending_line(&mut self) -> Vec<u8> {
+        let line = self.state.render();
+        self.state.clear();
+        line
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.state.remote_colors, self.invalid_utf8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(remote_colors: RemoteColors, data: &[u8]) -> String {
+        let mut ld = LineDiscipline::new(remote_colors, InvalidUtf8::Lossy);
+        let mut out = ld.feed(data);
+        out.extend(ld.take_pending_line());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_crlf_is_one_line_break() {
+        assert_eq!(render(RemoteColors::Strip, b"one\r\ntwo\r\n"), "one\ntwo\n");
+    }
+
+    #[test]
+    fn test_progress_bar_keeps_final_state() {
+        let data = b"Downloading  10%\rDownloading  55%\rDownloading 100%\r\ndone\n";
+        assert_eq!(render(RemoteColors::Strip, data), "Downloading 100%\ndone\n");
+        // A shorter redraw leaves the tail of the previous one, as on a terminal
+        assert_eq!(render(RemoteColors::Strip, b"abcdef\rxy"), "xycdef");
+    }
+
+    #[test]
+    fn test_backspace_and_erase() {
+        assert_eq!(render(RemoteColors::Strip, b"abc\x08\x08X"), "aXc");
+        assert_eq!(render(RemoteColors::Strip, b"abcdef\x1b[3D\x1b[K!"), "abc!");
+        assert_eq!(render(RemoteColors::Strip, b"abcdef\x1b[2K\rnew"), "new");
+        assert_eq!(render(RemoteColors::Strip, b"abcdef\r\x1b[2Cxy"), "abxyef");
+        assert_eq!(render(RemoteColors::Strip, b"abcdef\x1b[1G\x1b[2P"), "cdef");
+    }
+
+    #[test]
+    fn test_other_sequences_are_dropped() {
+        let data = b"\x1b[?2004hbash$ \x1b]0;title\x07ls\x1b[?2004l\r\n";
+        assert_eq!(render(RemoteColors::Strip, data), "bash$ ls\n");
+    }
+
+    #[test]
+    fn test_remote_colors() {
+        let data = b"\x1b[01;34mdir\x1b[0m file\n";
+        assert_eq!(render(RemoteColors::Strip, data), "dir file\n");
+        assert_eq!(render(RemoteColors::Keep, data), "\x1b[1;34mdir\x1b[0m file\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_partial_sequences_across_feeds() {
+        let mut ld = LineDiscipline::new(RemoteColors::Strip, InvalidUtf8::Lossy);
+        assert_eq!(ld.feed(b"50%\x1b["), b"");
+        assert_eq!(ld.feed(b"2K\r100%"), b"");
+        assert_eq!(ld.pending_line(), b"100%");
+        assert_eq!(ld.feed(b"\r\n"), b"100%\n");
+        assert_eq!(ld.pending_line(), b"");
+    }
+
+    #[test]
+    fn test_utf8_split_across_reads() {
+        let mut ld = LineDiscipline::new(RemoteColors::Strip, InvalidUtf8::Lossy);
+        let data = "naïve 日本\n".as_bytes();
+        // Split inside both the two-byte and a three-byte sequence
+        assert_eq!(ld.feed(&data[..3]), b"");
+        assert_eq!(ld.take_pending_line(), b"na");
+        assert_eq!(ld.feed(&data[3..10]), b"");
+        assert_eq!(ld.feed(&data[10..]), "ïve 日本\n".as_bytes());
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let data = b"a\xffb\xc3(\n";
+        let mut ld = LineDiscipline::new(RemoteColors::Strip, InvalidUtf8::Lossy);
+        assert_eq!(ld.feed(data), "a\u{fffd}b\u{fffd}(\n".as_bytes());
+        let mut ld = LineDiscipline::new(RemoteColors::Strip, InvalidUtf8::Hex);
+        assert_eq!(ld.feed(data), b"a\\xffb\\xc3(\n");
+        let mut ld = LineDiscipline::new(RemoteColors::Strip, InvalidUtf8::Raw);
+        assert_eq!(ld.feed(data), data);
+    }
+
+    #[test]
+    fn test_wide_characters_take_two_columns() {
+        assert_eq!(render(RemoteColors::Strip, "日本語\x08\x08\x08x".as_bytes()), "日 x語");
+        assert_eq!(render(RemoteColors::Strip, "e\u{301}!\x08?".as_bytes()), "e\u{301}?");
+    }
+}
@@ -1,22 +1,39 @@
+mod attach;
 mod callbacks;
+mod capture;
 mod cli;
+mod colors;
 mod completion;
 mod console;
 mod control_commands;
+mod control_socket;
 mod display_names;
+mod durations;
+mod history;
 mod host_syntax;
+mod hosts;
 mod input;
+mod inventory;
+mod line_discipline;
 mod pty_spawn;
+mod reconnect;
+mod remote_completion;
+mod saved_session;
+mod session;
 mod shell;
 mod shell_manager;
 mod signals;
+mod ssh_config;
+mod summary;
+mod template;
 
 use std::io::IsTerminal;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-use color_eyre::eyre::{self, Context, bail};
+use color_eyre::eyre::{self, Context, bail, eyre};
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
 use owo_colors::OwoColorize;
@@ -25,18 +42,26 @@ use tokio::io::unix::AsyncFd;
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 
+use attach::AttachTarget;
+use capture::FinishedCapture;
 use cli::parse_args;
 use console::Console;
+use control_socket::{ControlSocket, Request, SessionEvent, SocketRequest};
 use display_names::DisplayNameRegistry;
-use host_syntax::expand_syntax;
+use hosts::HostEntry;
 use input::{InputEvent, InputRequest};
-use shell::{ShellId, ShellState};
-use shell_manager::ShellManager;
+use line_discipline::{InvalidUtf8, RemoteColors};
+use pty_spawn::{local_term_size, shell_term_size};
+use reconnect::ReconnectPolicy;
+use remote_completion::{RemoteCompleter, RemoteQuery};
+use shell::{ShellId, ShellState, TimeoutAction};
+use shell_manager::{BlockOrder, OutputMode, ShellManager};
 use signals::SignalEvent;
+use summary::{HostOutcome, RunSummary};
 
 enum ShellEvent {
     Data { id: ShellId, data: Vec<u8> },
-    Closed { id: ShellId, exit_code: i32 },
+    Closed { id: ShellId, pid: i32, exit_code: i32 },
 }
 
 async fn pty_reader_task(id: ShellId, master_fd: OwnedFd, pid: i32, event_tx: mpsc::Sender<ShellEvent>) {
@@ -53,7 +78,13 @@ async fn pty_reader_task(id: ShellId, master_fd: OwnedFd, pid: i32, event_tx: mp
     let async_fd = match AsyncFd::new(raw_fd) {
         Ok(fd) => fd,
         Err(_) => {
-            let _ = event_tx.send(ShellEvent::Closed { id, exit_code: 255 }).await;
+            let _ = event_tx
+                .send(ShellEvent::Closed {
+                    id,
+                    pid,
+                    exit_code: 255,
+                })
+                .await;
             return;
         }
     };
@@ -76,12 +107,7 @@ async fn pty_reader_task(id: ShellId, master_fd: OwnedFd, pid: i32, event_tx: mp
         }) {
             Ok(Ok(0)) => break,
             Ok(Ok(n)) => {
-                let mut data = buf[..n].to_vec();
-                for b in data.iter_mut() {
-                    if *b == b'\r' {
-                        *b = b'\n';
-                    }
-                }
+                let data = buf[..n].to_vec();
                 if event_tx.send(ShellEvent::Data { id, data }).await.is_err() {
                     break;
                 }
@@ -104,7 +130,7 @@ async fn pty_reader_task(id: ShellId, master_fd: OwnedFd, pid: i32, event_tx: mp
         _ => 255,
     };
 
-    let _ = event_tx.send(ShellEvent::Closed { id, exit_code }).await;
+    let _ = event_tx.send(ShellEvent::Closed { id, pid, exit_code }).await;
 }
 
 fn kill_all(mgr: &ShellManager) {
@@ -114,7 +140,7 @@ fn kill_all(mgr: &ShellManager) {
 }
 
 fn spawn_shell(
-    host_str: &str,
+    entry: &HostEntry,
     args: &cli::Args,
     command: &Option<String>,
     password: &Option<String>,
@@ -122,9 +148,12 @@ fn spawn_shell(
     display_names: &mut DisplayNameRegistry,
     shell_event_tx: &mpsc::Sender<ShellEvent>,
 ) -> eyre::Result<()> {
-    let (hostname, port) = host_syntax::split_port(host_str);
-    let child = pty_spawn::spawn_ssh(&hostname, &port, &args.ssh, args.user.as_deref())
-        .wrap_err_with(|| format!("Failed to spawn ssh to {}", host_str))?;
+    let (hostname, port) = host_syntax::split_port(&entry.host);
+    let user = entry.user.as_deref().or(args.user.as_deref());
+    let ssh = entry.ssh.as_deref().unwrap_or(&args.ssh);
+    let size = shell_term_size(display_names.max_display_name_length);
+    let child = pty_spawn::spawn_ssh(&hostname, &port, ssh, user, size)
+        .wrap_err_with(|| format!("Failed to spawn ssh to {}", entry.host))?;
 
     let master_fd_for_reader = child.master_fd.try_clone().wrap_err("Failed to clone master fd")?;
 
@@ -136,8 +165,84 @@ fn spawn_shell(
         args.debug,
         command.clone(),
         password.clone(),
+        entry.color,
+        entry.name.as_deref(),
         display_names,
     );
+    if let Some(shell) = mgr.get_shell_mut(id) {
+        shell.auto_reconnect = args.auto_reconnect;
+        shell.vars = entry.vars.clone();
+        shell.user = entry.user.clone();
+        shell.ssh = entry.ssh.clone();
+        shell.tags = entry.tags.clone();
+        if !entry.enabled {
+            shell.enabled = false;
+            shell.enabled_before_disconnect = false;
+            display_names.set_enabled(&shell.display_name, false);
+        }
+    }
+    let tx = shell_event_tx.clone();
+    tokio::spawn(pty_reader_task(id, master_fd_for_reader, child.pid, tx));
+    Ok(())
+}
+
+/// Feed the output collected by a `:pipe` to the local command and print its result.
+async fn run_pipe(local_command: &str, input: Vec<u8>, console: &mut Console) {
+    use tokio::io::AsyncWriteExt;
+
+    let child = tokio::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(local_command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            console.output(format!("Error: {}\n", e).as_bytes()).await;
+            return;
+        }
+    };
+    // Written from a task so a command that outputs before reading all of its input can't deadlock
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    match child.wait_with_output().await {
+        Ok(output) => {
+            console.output(&output.stdout).await;
+            if let Some(code) = output.status.code() {
+                if code > 0 {
+                    console.output(format!("Child returned {}\n", code).as_bytes()).await;
+                }
+            } else {
+                console.output(b"Child was terminated by signal\n").await;
+            }
+        }
+        Err(e) => {
+            console.output(format!("Error: {}\n", e).as_bytes()).await;
+        }
+    }
+}
+
+/// Start a new ssh process for an existing (dead) shell, keeping its identity.
+fn respawn_shell(
+    id: ShellId,
+    args: &cli::Args,
+    mgr: &mut ShellManager,
+    max_name_len: usize,
+    shell_event_tx: &mpsc::Sender<ShellEvent>,
+) -> eyre::Result<()> {
+    let shell = mgr.get_shell_mut(id).ok_or_else(|| eyre!("Unknown shell {:?}", id))?;
+    let user = shell.user.as_deref().or(args.user.as_deref());
+    let ssh = shell.ssh.as_deref().unwrap_or(&args.ssh);
+    let size = shell.fixed_size.unwrap_or_else(|| shell_term_size(max_name_len));
+    let child = pty_spawn::spawn_ssh(&shell.hostname, &shell.port, ssh, user, size)
+        .wrap_err_with(|| format!("Failed to spawn ssh to {}", shell.host_spec()))?;
+
+    let master_fd_for_reader = child.master_fd.try_clone().wrap_err("Failed to clone master fd")?;
+    shell.reattach(child.pid, child.master_fd);
     let tx = shell_event_tx.clone();
     tokio::spawn(pty_reader_task(id, master_fd_for_reader, child.pid, tx));
     Ok(())
@@ -151,10 +256,28 @@ async fn main() -> eyre::Result<()> {
         signal::signal(Signal::SIGPIPE, signal::SigHandler::SigDfl).ok();
     }
 
-    let args = parse_args();
+    // `mash attach [SESSION]` and `mash ls` talk to --detachable sessions
+    let mut raw_args = std::env::args().skip(1);
+    match raw_args.next().as_deref() {
+        Some("attach") => std::process::exit(session::attach_command(raw_args.next()).await?),
+        Some("ls") => std::process::exit(session::list_command().await?),
+        _ => {}
+    }
+    if let Some(name) = session::server_name() {
+        std::process::exit(session::run_server(&name).await?);
+    }
+
+    let (args, host_entries, exclusions) = parse_args();
 
     let interactive = args.command.is_none() && std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
 
+    if args.detachable && !session::in_session() {
+        if !interactive {
+            bail!("--detachable needs an interactive terminal");
+        }
+        std::process::exit(session::start(args.session_name.as_deref()).await?);
+    }
+
     let command = if !std::io::stdin().is_terminal() && args.command.is_none() {
         let mut stdin_data = String::new();
         tokio::io::stdin()
@@ -170,10 +293,7 @@ async fn main() -> eyre::Result<()> {
     };
 
     // Expand hosts
-    let mut hosts: Vec<String> = Vec::new();
-    for host in &args.host_names {
-        hosts.extend(expand_syntax(host));
-    }
+    let hosts: Vec<HostEntry> = hosts::expand_hosts(&host_entries, &exclusions);
 
     if hosts.is_empty() {
         bail!("No hosts given");
@@ -216,8 +336,53 @@ async fn main() -> eyre::Result<()> {
     let use_color = !args.no_color && std::io::stdout().is_terminal();
     let mut display_names = DisplayNameRegistry::new();
     let mut mgr = ShellManager::new(use_color);
+    mgr.color_overrides = cli::host_colors(&args);
+    mgr.render_templates = args.templates;
+    mgr.default_user = args.user.clone();
+    if let Some(ref dir) = args.output_dir {
+        mgr.set_output_dir(Some(PathBuf::from(dir)))
+            .wrap_err_with(|| format!("Failed to create output directory {}", dir))?;
+    }
+    if args.group_output {
+        let order = BlockOrder::parse(&args.group_order).ok_or_else(|| {
+            eyre!(
+                "Invalid --group-order {}, expected completion or name",
+                args.group_order
+            )
+        })?;
+        mgr.set_output_mode(OutputMode::Grouped(order));
+    }
+    mgr.remote_colors = RemoteColors::parse(&args.remote_colors)
+        .ok_or_else(|| eyre!("Invalid --remote-colors {}, expected keep or strip", args.remote_colors))?;
+    mgr.invalid_utf8 = InvalidUtf8::parse(&args.invalid_utf8).ok_or_else(|| {
+        eyre!(
+            "Invalid --invalid-utf8 {}, expected lossy, hex or raw",
+            args.invalid_utf8
+        )
+    })?;
+    mgr.command_timeout = args
+        .command_timeout
+        .filter(|secs| *secs > 0.0)
+        .map(Duration::from_secs_f64);
     let mut console = Console::new(interactive, args.log_file.clone()).await;
     let mut exit_code: i32 = 0;
+    let mut run_summary = RunSummary::new();
+    let reconnect_policy = ReconnectPolicy::new(
+        args.reconnect_attempts,
+        Duration::from_secs_f64(args.reconnect_delay.max(0.0)),
+    );
+
+    // Before spawning, so every shell publishes its events
+    let (control_socket, mut socket_rx) = match args.control_socket {
+        Some(ref path) => {
+            let (events, _) = tokio::sync::broadcast::channel::<SessionEvent>(1024);
+            mgr.set_event_sender(events.clone());
+            let (socket, rx) = ControlSocket::listen(Path::new(path), events)
+                .wrap_err_with(|| format!("Failed to listen on control socket {}", path))?;
+            (Some(socket), Some(rx))
+        }
+        None => (None, None),
+    };
 
     let (shell_event_tx, mut shell_event_rx) = mpsc::channel::<ShellEvent>(256);
     let (signal_tx, mut signal_rx) = mpsc::channel::<SignalEvent>(16);
@@ -225,12 +390,12 @@ async fn main() -> eyre::Result<()> {
     tokio::spawn(signals::signal_listener(signal_tx));
 
     // Spawn SSH processes
-    for (i, host_str) in hosts.iter().enumerate() {
+    for (i, entry) in hosts.iter().enumerate() {
         if interactive {
             eprint!("Started {}/{} remote processes\r", i, hosts.len());
         }
         if let Err(e) = spawn_shell(
-            host_str,
+            entry,
             &args,
             &command,
             &password,
@@ -239,6 +404,7 @@ async fn main() -> eyre::Result<()> {
             &shell_event_tx,
         ) {
             eprintln!("{:#}", e);
+            run_summary.record(&entry.host, entry.host.clone(), HostOutcome::ConnectionFailed, None);
             if args.abort_errors {
                 bail!("Aborting due to --abort-errors");
             }
@@ -247,6 +413,10 @@ async fn main() -> eyre::Result<()> {
     if interactive && !hosts.is_empty() {
         eprint!("{}\r", " ".repeat(40));
     }
+    // The name column only reached its final width once every shell was added
+    resize_shells(&mgr, &display_names, None);
+    // Like the rank, `--command` templates need the full list of shells
+    mgr.render_commands();
 
     // Keep a clone for dynamic :add/:reconnect
     let persistent_shell_tx = shell_event_tx.clone();
@@ -254,18 +424,34 @@ async fn main() -> eyre::Result<()> {
 
     // Input setup
     let completion_state = Arc::new(RwLock::new(completion::CompletionState::from_manager(&mgr)));
-    let (input_req_tx, mut input_resp_rx) = if interactive {
-        let (req_tx, resp_rx) = input::spawn_input_thread(completion_state.clone());
-        (Some(req_tx), Some(resp_rx))
+    let (input_req_tx, mut input_resp_rx, mut remote_query_rx) = if interactive {
+        // Tab asks idle shells for remote commands and paths through the main loop
+        let (query_tx, query_rx) = mpsc::unbounded_channel::<RemoteQuery>();
+        let (req_tx, resp_rx) = input::spawn_input_thread(
+            completion_state.clone(),
+            RemoteCompleter::new(query_tx),
+            input::history_file(hosts.iter().map(|h| h.host.as_str())),
+        );
+        (Some(req_tx), Some(resp_rx), Some(query_rx))
     } else {
-        (None, None)
+        (None, None, None)
     };
 
+    let keepalive_timeout = Duration::from_secs_f64(args.keepalive_timeout.max(0.0));
+    let mut keepalive = args
+        .keepalive
+        .filter(|_| interactive)
+        .map(|secs| tokio::time::interval(Duration::from_secs_f64(secs.max(0.1))));
+
     let mut input_requested = false;
+    // The shell the terminal is passed through to by `:attach`
+    let mut attached: Option<attach::Attachment> = None;
+    let attach_escape = attach::parse_escape(&args.attach_escape);
     let mut next_signal: Option<SignalEvent> = None;
     let mut drain_deadline: Option<Instant> = None;
     let mut drain_flushed = false;
     const DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+    const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
 
     loop {
         // Handle pending signal
@@ -295,29 +481,47 @@ async fn main() -> eyre::Result<()> {
                     console.output(b"").await;
                 }
                 SignalEvent::Winch => {
-                    let (cols, rows) = terminal_size::terminal_size()
-                        .map(|(w, h)| (w.0, h.0))
-                        .unwrap_or((80, 25));
-                    let adjusted_cols = std::cmp::max(
-                        cols as i32 - display_names.max_display_name_length as i32 - 2,
-                        std::cmp::min(cols as i32, 10),
-                    ) as u16;
-                    for shell in mgr.all_shells_mut() {
-                        if shell.enabled {
-                            shell.set_term_size(adjusted_cols, rows);
-                        }
-                    }
+                    resize_shells(&mgr, &display_names, attached.as_ref().and_then(|a| a.shell()));
                 }
             }
         }
 
-        if mgr.all_terminated() {
+        mgr.flush_output_blocks(&mut console, false).await;
+        mgr.update_history();
+
+        match mgr.finish_capture() {
+            Some(FinishedCapture::Pipe { local_command, input }) => {
+                run_pipe(&local_command, input, &mut console).await;
+            }
+            Some(FinishedCapture::Buffer { name, hosts, lines }) => {
+                let msg = format!("Captured {} lines from {} hosts into {}\n", lines, hosts, name);
+                console.output(msg.as_bytes()).await;
+            }
+            Some(FinishedCapture::Socket { responder, outputs }) => {
+                let results: Vec<serde_json::Value> = outputs
+                    .iter()
+                    .map(|o| {
+                        serde_json::json!({
+                            "name": o.name,
+                            "output": String::from_utf8_lossy(&o.output),
+                            "exit_code": o.exit_code,
+                        })
+                    })
+                    .collect();
+                let mut fields = serde_json::Map::new();
+                fields.insert("results".to_string(), serde_json::Value::Array(results));
+                responder.ok(fields);
+            }
+            None => {}
+        }
+
+        if mgr.all_terminated() && mgr.next_reconnect_at().is_none() {
             console.output(b"").await;
             break;
         }
 
         // Request input when all shells idle, or after a drain timeout while running
-        if interactive && !input_requested {
+        if interactive && !input_requested && attached.is_none() {
             let (awaiting, _) = mgr.count_awaited_processes();
             if awaiting == 0 {
                 // All shells idle: flush and prompt immediately
@@ -329,8 +533,9 @@ async fn main() -> eyre::Result<()> {
                 }
 
                 let (idle, running, pending, dead, disabled) = mgr.count_by_state();
-                let prompt = build_prompt(idle, running, pending, dead, disabled, use_color);
-                let visible = build_prompt(idle, running, pending, dead, disabled, false);
+                let unresponsive = mgr.count_unresponsive();
+                let prompt = build_prompt(idle, running, pending, dead, disabled, unresponsive, use_color);
+                let visible = build_prompt(idle, running, pending, dead, disabled, unresponsive, false);
                 console.set_last_status_length(visible.len());
                 if let Some(ref tx) = input_req_tx {
                     let _ = tx.send(InputRequest::ReadLine { prompt }).await;
@@ -342,9 +547,21 @@ async fn main() -> eyre::Result<()> {
             }
         }
 
+        mgr.finish_completion();
+
+        let next_reconnect = mgr.next_reconnect_at();
+        let next_timeout = mgr.next_timeout_at(TIMEOUT_GRACE);
+        let completion_deadline = mgr.completion_deadline();
+
         tokio::select! {
             Some(shell_evt) = shell_event_rx.recv() => {
                 match shell_evt {
+                    ShellEvent::Data { id, data } if attached.as_ref().is_some_and(|a| a.shell() == Some(id)) => {
+                        attach::write_output(&data);
+                        if let Some(shell) = mgr.get_shell_mut(id) {
+                            shell.record_attached_output(&data);
+                        }
+                    }
                     ShellEvent::Data { id, data } => {
                         // Reset drain timer: new data arrived, wait for output to settle
                         if drain_deadline.is_some() {
@@ -353,8 +570,17 @@ async fn main() -> eyre::Result<()> {
                         }
                         let max_name_len = display_names.max_display_name_length;
                         let abort = args.abort_errors;
+                        let command_timeout = mgr.command_timeout;
                         if let Some(shell) = mgr.get_shell_mut(id) {
-                            if let Some(new_name) = shell.handle_data(&data, &mut console, max_name_len, interactive, abort).await {
+                            let new_name = shell.handle_data(&data, &mut console, max_name_len, interactive, abort).await;
+                            if attached.is_some() {
+                                // Raw mode: show prompts waiting for a key once the output settles
+                                drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+                            }
+                            if !interactive {
+                                shell.arm_timeout(command_timeout);
+                            }
+                            if let Some(new_name) = new_name {
                                 let new_name_str = String::from_utf8_lossy(&new_name).to_string();
                                 let prev = shell.display_name.clone();
                                 if let Some(name) = display_names.change(Some(&prev), Some(&new_name_str)) {
@@ -363,19 +589,44 @@ async fn main() -> eyre::Result<()> {
                             }
                         }
                     }
-                    ShellEvent::Closed { id, exit_code: code } => {
+                    ShellEvent::Closed { id, pid, exit_code: code } => {
+                        // Ignore the exit of a process that has since been replaced by a reconnect
+                        if mgr.get_shell(id).is_some_and(|s| s.pid != pid) {
+                            continue;
+                        }
                         // Shell state changed; let top-of-loop logic re-evaluate
                         drain_deadline = None;
                         exit_code = std::cmp::max(exit_code, code);
+                        if attached.as_ref().is_some_and(|a| a.shell() == Some(id)) {
+                            detach(attached.take(), &mut mgr, &mut console, &display_names).await;
+                        }
                         let max_name_len = display_names.max_display_name_length;
+                        let output_dir = mgr.output_dir().map(Path::to_path_buf);
                         if let Some(shell) = mgr.get_shell_mut(id) {
+                            if !interactive {
+                                let outcome = HostOutcome::classify(shell.command_sent, shell.timed_out, code);
+                                let duration = shell.command_sent.then(|| shell.spawned_at.elapsed());
+                                run_summary.record(&shell.display_name, shell.host_spec(), outcome, duration);
+                            }
                             if code != 0 && interactive {
                                 let msg = format!("Error talking to {}\n", shell.display_name);
                                 console.output(msg.as_bytes()).await;
                             }
+                            let connection_error =
+                                code == 255 || (shell.state == ShellState::NotStarted && !shell.command_sent);
                             shell.disconnect(&mut console, max_name_len, args.abort_errors).await;
+                            if let Some(ref dir) = output_dir {
+                                if let Err(e) = shell.write_status(dir, code, connection_error) {
+                                    console.output(
+                                        format!("Failed to write status of {}: {}\n", shell.display_name, e).as_bytes(),
+                                    ).await;
+                                }
+                            }
                             if interactive {
                                 display_names.set_enabled(&shell.display_name, false);
+                                if code != 0 && shell.auto_reconnect {
+                                    schedule_reconnect(shell, &reconnect_policy, &mut console).await;
+                                }
                             }
                         }
                     }
@@ -394,9 +645,21 @@ async fn main() -> eyre::Result<()> {
                         InputEvent::Line(line) => {
                             console.log(format!("> {}\n", line).as_bytes()).await;
 
-                            if let Some(cmd_line) = line.strip_prefix(':') {
+                            // `@PATTERN command` is shorthand for `:on PATTERN command`,
+                            // `command |> local` for `:pipe local -- command`
+                            let control_line = match line.strip_prefix('@') {
+                                Some(rest) => Some(format!("on {}", rest)),
+                                None => match line.strip_prefix(':') {
+                                    Some(rest) => Some(rest.to_string()),
+                                    None if !line.starts_with('!') => line
+                                        .split_once(" |> ")
+                                        .map(|(remote, local)| format!("pipe {} -- {}", shell_words::quote(local), remote)),
+                                    None => None,
+                                },
+                            };
+                            if let Some(cmd_line) = control_line {
                                 let result = control_commands::dispatch(
-                                    cmd_line,
+                                    &cmd_line,
                                     &mut mgr,
                                     &mut console,
                                     &mut display_names,
@@ -409,17 +672,24 @@ async fn main() -> eyre::Result<()> {
                                     control_commands::CmdResult::Error(msg) => {
                                         console.output(format!("{}\n", msg).as_bytes()).await;
                                     }
-                                    control_commands::CmdResult::AddHosts(new_hosts) => {
-                                        for h in &new_hosts {
-                                            if let Err(e) = spawn_shell(
-                                                h, &args, &command, &password,
-                                                &mut mgr, &mut display_names,
-                                                &persistent_shell_tx,
-                                            ) {
-                                                console.output(format!("{:#}\n", e).as_bytes()).await;
-                                            }
+                                    control_commands::CmdResult::Reconnect(ids) => {
+                                        for id in ids {
+                                            reconnect_shell(
+                                                id, &args, &mut mgr, &mut display_names,
+                                                &persistent_shell_tx, &reconnect_policy, &mut console,
+                                            ).await;
                                         }
                                     }
+                                    control_commands::CmdResult::Attach(target) => {
+                                        attached = attach(target, &attach_escape, &args, &mut mgr, &mut console).await;
+                                    }
+                                    control_commands::CmdResult::AddHosts(new_hosts) => {
+                                        add_hosts(
+                                            &new_hosts, &args, &command, &password,
+                                            &mut mgr, &mut display_names,
+                                            &persistent_shell_tx, &mut console,
+                                        ).await;
+                                    }
                                 }
                             } else if let Some(cmd) = line.strip_prefix('!') {
                                 match tokio::process::Command::new("/bin/sh")
@@ -450,10 +720,7 @@ async fn main() -> eyre::Result<()> {
                                     }
                                 }
                             } else {
-                                let cmd = format!("{}\n", line);
-                                for shell in mgr.all_shells_mut() {
-                                    shell.dispatch_command(cmd.as_bytes()).await;
-                                }
+                                mgr.dispatch_line(&line, mgr.command_timeout).await;
                             }
 
                             if let Ok(mut cs) = completion_state.write() {
@@ -463,6 +730,10 @@ async fn main() -> eyre::Result<()> {
                                 }
                             }
                         }
+                        InputEvent::RawMode => {
+                            attached =
+                                attach(AttachTarget::Broadcast, &attach_escape, &args, &mut mgr, &mut console).await;
+                        }
                         InputEvent::Eof => break,
                         InputEvent::Interrupted => {
                             // Forward Ctrl-C to running shells
@@ -475,16 +746,134 @@ async fn main() -> eyre::Result<()> {
                     }
                 }
             }
+            input = async {
+                match attached.as_mut() {
+                    Some(attachment) => attachment.read_input().await,
+                    None => std::future::pending::<Option<Vec<u8>>>().await,
+                }
+            } => {
+                let (forward, escaped) = match (&input, attached.as_mut()) {
+                    (Some(data), Some(attachment)) => attachment.escape.feed(data),
+                    _ => (Vec::new(), true),
+                };
+                if let Some(attachment) = attached.as_ref().filter(|_| !forward.is_empty()) {
+                    for shell in mgr.all_shells_mut() {
+                        if !attachment.receives_input(shell) {
+                            continue;
+                        }
+                        match attachment.target {
+                            // An attached shell may be disabled
+                            AttachTarget::Shell(_) => shell.write_to_pty(&forward),
+                            // Running, so a prompt waiting for a key gets flushed
+                            AttachTarget::Broadcast => shell.dispatch_command(&forward).await,
+                        }
+                    }
+                }
+                if escaped {
+                    detach(attached.take(), &mut mgr, &mut console, &display_names).await;
+                }
+            }
+            Some(query) = async {
+                match remote_query_rx.as_mut() {
+                    Some(rx) => rx.recv().await,
+                    None => std::future::pending::<Option<RemoteQuery>>().await,
+                }
+            } => {
+                mgr.start_completion(query);
+            }
+            // Reply with the answers the shells gave so far
+            _ = tokio::time::sleep_until(completion_deadline.map(Instant::from_std).unwrap_or_else(Instant::now)),
+                if completion_deadline.is_some() => {}
+            Some(request) = async {
+                match socket_rx.as_mut() {
+                    Some(rx) => rx.recv().await,
+                    None => std::future::pending::<Option<SocketRequest>>().await,
+                }
+            } => {
+                let quit = answer_socket_request(
+                    request, &args, &command, &password, interactive,
+                    &mut mgr, &mut display_names, &persistent_shell_tx, &reconnect_policy, &mut console,
+                ).await;
+                if quit {
+                    break;
+                }
+                if let Ok(mut cs) = completion_state.write() {
+                    cs.update_from_manager(&mgr);
+                }
+            }
             Some(sig) = signal_rx.recv() => {
                 next_signal = Some(sig);
             }
+            _ = async {
+                match keepalive.as_mut() {
+                    Some(interval) => {
+                        interval.tick().await;
+                    }
+                    None => std::future::pending::<()>().await,
+                }
+            } => {
+                for shell in mgr.all_shells_mut() {
+                    if !shell.enabled {
+                        continue;
+                    }
+                    // Probes would be typed into whatever reads the keystrokes
+                    if attached.as_ref().is_some_and(|a| a.receives_input(shell)) {
+                        continue;
+                    }
+                    if shell.check_keepalive(keepalive_timeout) {
+                        let msg = format!("{} is unresponsive\n", shell.display_name);
+                        console.output(msg.as_bytes()).await;
+                    }
+                    shell.send_keepalive();
+                }
+            }
+            _ = tokio::time::sleep_until(next_timeout.map(Instant::from_std).unwrap_or_else(Instant::now)),
+                if next_timeout.is_some() => {
+                let now = std::time::Instant::now();
+                let max_name_len = display_names.max_display_name_length;
+                for shell in mgr.all_shells_mut() {
+                    match shell.check_timeout(now, TIMEOUT_GRACE) {
+                        Some(TimeoutAction::Interrupted) => {
+                            let msg = format!("{} timed out, sent ^C\n", shell.display_name);
+                            console.output(msg.as_bytes()).await;
+                        }
+                        Some(TimeoutAction::Disconnect) => {
+                            let msg = format!("{} timed out and did not stop, disconnecting\n", shell.display_name);
+                            console.output(msg.as_bytes()).await;
+                            // The Closed event that follows updates the display name registry
+                            shell.disconnect(&mut console, max_name_len, args.abort_errors).await;
+                        }
+                        None => {}
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(next_reconnect.map(Instant::from_std).unwrap_or_else(Instant::now)),
+                if next_reconnect.is_some() => {
+                for id in mgr.due_reconnects(std::time::Instant::now()) {
+                    if let Some(shell) = mgr.get_shell_mut(id) {
+                        shell.reconnect_attempts += 1;
+                        let msg = format!(
+                            "Reconnecting to {} (attempt {}/{})\n",
+                            shell.display_name, shell.reconnect_attempts, reconnect_policy.max_attempts,
+                        );
+                        console.output(msg.as_bytes()).await;
+                    }
+                    reconnect_shell(
+                        id, &args, &mut mgr, &mut display_names,
+                        &persistent_shell_tx, &reconnect_policy, &mut console,
+                    ).await;
+                }
+            }
             _ = tokio::time::sleep(DRAIN_TIMEOUT), if drain_deadline.is_some() && !input_requested => {
                 let max_name_len = display_names.max_display_name_length;
                 for shell in mgr.all_shells_mut() {
                     shell.print_unfinished_line(&mut console, max_name_len).await;
                 }
 
-                if !drain_flushed {
+                if attached.is_some() {
+                    // No line to read while keystrokes are passed through
+                    drain_deadline = None;
+                } else if !drain_flushed {
                     // Phase 1: flush only, re-arm for phase 2
                     drain_flushed = true;
                     drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
@@ -493,8 +882,9 @@ async fn main() -> eyre::Result<()> {
                     drain_deadline = None;
                     drain_flushed = false;
                     let (idle, running, pending, dead, disabled) = mgr.count_by_state();
-                    let prompt = build_prompt(idle, running, pending, dead, disabled, use_color);
-                    let visible = build_prompt(idle, running, pending, dead, disabled, false);
+                    let unresponsive = mgr.count_unresponsive();
+                    let prompt = build_prompt(idle, running, pending, dead, disabled, unresponsive, use_color);
+                    let visible = build_prompt(idle, running, pending, dead, disabled, unresponsive, false);
                     console.set_last_status_length(visible.len());
                     if let Some(ref tx) = input_req_tx {
                         let _ = tx.send(InputRequest::ReadLine { prompt }).await;
@@ -506,8 +896,12 @@ async fn main() -> eyre::Result<()> {
         }
     }
 
+    mgr.flush_output_blocks(&mut console, true).await;
+
     // Cleanup
     kill_all(&mgr);
+    // process::exit skips destructors
+    drop(control_socket);
 
     if let Some(tx) = input_req_tx {
         let _ = tx.send(InputRequest::Shutdown).await;
@@ -518,10 +912,297 @@ async fn main() -> eyre::Result<()> {
     }
 
     console.output(b"").await;
+
+    if !interactive {
+        if !args.no_summary {
+            eprint!("{}", run_summary.format());
+        }
+        if let Some(ref path) = args.failed_hosts_file {
+            if let Err(e) = run_summary.write_failed_hosts(path) {
+                eprintln!("Failed to write failed hosts file {}: {}", path, e);
+            }
+        }
+    }
     std::process::exit(exit_code);
 }
 
-fn build_prompt(idle: usize, running: usize, pending: usize, dead: usize, disabled: usize, color: bool) -> String {
+/// Spawn shells for the hosts added with `:add` or from the control socket.
+#[allow(clippy::too_many_arguments)]
+async fn add_hosts(
+    new_hosts: &[HostEntry],
+    args: &cli::Args,
+    command: &Option<String>,
+    password: &Option<String>,
+    mgr: &mut ShellManager,
+    display_names: &mut DisplayNameRegistry,
+    shell_event_tx: &mpsc::Sender<ShellEvent>,
+    console: &mut Console,
+) {
+    for entry in hosts::expand_hosts(new_hosts, &[]) {
+        if let Err(e) = spawn_shell(&entry, args, command, password, mgr, display_names, shell_event_tx) {
+            console.output(format!("{:#}\n", e).as_bytes()).await;
+        }
+    }
+    resize_shells(mgr, display_names, None);
+}
+
+/// Answer a control socket request with the same commands as the prompt. Returns true to quit.
+#[allow(clippy::too_many_arguments)]
+async fn answer_socket_request(
+    SocketRequest { request, responder }: SocketRequest,
+    args: &cli::Args,
+    command: &Option<String>,
+    password: &Option<String>,
+    interactive: bool,
+    mgr: &mut ShellManager,
+    display_names: &mut DisplayNameRegistry,
+    shell_event_tx: &mpsc::Sender<ShellEvent>,
+    reconnect_policy: &ReconnectPolicy,
+    console: &mut Console,
+) -> bool {
+    let control_line = match request {
+        Request::List => {
+            let shells: Vec<serde_json::Value> = mgr
+                .all_shells()
+                .iter()
+                .map(|s| {
+                    serde_json::json!({
+                        "name": s.display_name,
+                        "hostname": s.hostname,
+                        "state": s.state.name(),
+                        "enabled": s.enabled,
+                        "exit_code": s.last_exit_code,
+                    })
+                })
+                .collect();
+            let mut fields = serde_json::Map::new();
+            fields.insert("shells".to_string(), serde_json::Value::Array(shells));
+            responder.ok(fields);
+            return false;
+        }
+        Request::Run {
+            command,
+            pattern,
+            timeout,
+        } => {
+            if mgr.has_pending_capture() {
+                responder.error("Still waiting for the shells of the previous run, :pipe or :capture");
+                return false;
+            }
+            let ids = match pattern {
+                Some(ref pattern) => control_commands::targeted_shell_ids(pattern, mgr, console).await,
+                None => mgr.enabled_shell_ids(),
+            };
+            let connecting = ids
+                .iter()
+                .filter_map(|id| mgr.get_shell(*id))
+                .filter(|s| s.state == ShellState::NotStarted)
+                .count();
+            if connecting > 0 {
+                responder.error(&format!("{} shells are still connecting", connecting));
+                return false;
+            }
+            let target = capture::CaptureTarget::Socket {
+                responder: responder.clone(),
+            };
+            let timeout = timeout.or(mgr.command_timeout);
+            if !mgr.start_capture(ids, &command, target, true, timeout).await {
+                responder.error("No shell to run the command on");
+            }
+            return false;
+        }
+        Request::Enable { pattern } => format!("enable {}", pattern),
+        Request::Disable { pattern } => format!("disable {}", pattern),
+        Request::Control { command } => command.trim_start_matches(':').to_string(),
+        // Answered by the client's connection
+        Request::Subscribe => return false,
+    };
+    // The client gets the command's output rather than the local terminal
+    console.collect();
+    let result = control_commands::dispatch(&control_line, mgr, console, display_names, interactive, args).await;
+    let quit = matches!(result, control_commands::CmdResult::Quit);
+    let error = match result {
+        control_commands::CmdResult::Ok | control_commands::CmdResult::Quit => None,
+        control_commands::CmdResult::Error(msg) => Some(msg),
+        control_commands::CmdResult::Reconnect(ids) => {
+            for id in ids {
+                reconnect_shell(id, args, mgr, display_names, shell_event_tx, reconnect_policy, console).await;
+            }
+            None
+        }
+        control_commands::CmdResult::AddHosts(new_hosts) => {
+            add_hosts(
+                &new_hosts,
+                args,
+                command,
+                password,
+                mgr,
+                display_names,
+                shell_event_tx,
+                console,
+            )
+            .await;
+            None
+        }
+        control_commands::CmdResult::Attach(_) => Some("The terminal can't be attached from the socket".to_string()),
+    };
+    let output = console.take_collected();
+    let mut fields = serde_json::Map::new();
+    fields.insert(
+        "output".to_string(),
+        serde_json::Value::String(String::from_utf8_lossy(&output).into_owned()),
+    );
+    match error {
+        None => responder.ok(fields),
+        Some(msg) => responder.error_with(&msg, fields),
+    }
+    quit
+}
+
+/// Size every shell's window for the terminal; an attached shell gets all of it.
+fn resize_shells(mgr: &ShellManager, display_names: &DisplayNameRegistry, attached: Option<ShellId>) {
+    let (cols, rows) = shell_term_size(display_names.max_display_name_length);
+    mgr.resize_all(cols, rows);
+    if let Some(shell) = attached.and_then(|id| mgr.get_shell(id)) {
+        let (cols, rows) = local_term_size();
+        shell.set_term_size(cols, rows);
+    }
+}
+
+/// Pass the terminal through to the target shells, None if it cannot be put in raw mode.
+async fn attach(
+    target: AttachTarget,
+    escape: &[u8],
+    args: &cli::Args,
+    mgr: &mut shell_manager::ShellManager,
+    console: &mut Console,
+) -> Option<attach::Attachment> {
+    let msg = match target {
+        AttachTarget::Shell(id) => {
+            let name = mgr.get_shell(id).map(|s| s.display_name.clone()).unwrap_or_default();
+            format!(
+                "Attached to {}, type {} at the start of a line to detach\n",
+                name, args.attach_escape
+            )
+        }
+        AttachTarget::Broadcast => {
+            let nr_shells = mgr.all_shells().iter().filter(|s| s.enabled).count();
+            format!(
+                "Raw mode: keys go to {} shells, type {} at the start of a line to return to line mode\n",
+                nr_shells, args.attach_escape
+            )
+        }
+    };
+    console.output(msg.as_bytes()).await;
+    let attachment = match attach::Attachment::start(target, escape.to_vec()) {
+        Ok(attachment) => attachment,
+        Err(e) => {
+            console
+                .output(format!("Cannot pass the terminal through: {}\n", e).as_bytes())
+                .await;
+            return None;
+        }
+    };
+    if let Some(shell) = attachment.shell().and_then(|id| mgr.get_shell_mut(id)) {
+        let (cols, rows) = local_term_size();
+        shell.set_term_size(cols, rows);
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm".into());
+        shell.prepare_attach(&term);
+        console.hold();
+    }
+    Some(attachment)
+}
+
+/// Give the terminal back to mash. After `:attach`, show the output held meanwhile and
+/// re-send the init string so the shell's prompt is recognized again.
+async fn detach(
+    attachment: Option<attach::Attachment>,
+    mgr: &mut shell_manager::ShellManager,
+    console: &mut Console,
+    display_names: &DisplayNameRegistry,
+) {
+    let Some(attachment) = attachment else {
+        return;
+    };
+    let target = attachment.target;
+    // Dropping the attachment restores the terminal mode
+    drop(attachment);
+    console.output(b"\r\n").await;
+    console.release().await;
+    let AttachTarget::Shell(id) = target else {
+        console.output(b"Back to line mode\n").await;
+        return;
+    };
+    if let Some(shell) = mgr.get_shell_mut(id) {
+        let (cols, rows) = shell_term_size(display_names.max_display_name_length);
+        shell.resize(cols, rows);
+        console
+            .output(format!("Detached from {}\n", shell.display_name).as_bytes())
+            .await;
+        if shell.state != ShellState::Dead && !shell.reset_after_attach().await {
+            let msg = format!(
+                "{} is still busy, run :reset_prompt {} once it is back at its prompt\n",
+                shell.display_name, shell.display_name
+            );
+            console.output(msg.as_bytes()).await;
+        }
+    }
+}
+
+async fn schedule_reconnect(shell: &mut shell::RemoteShell, policy: &ReconnectPolicy, console: &mut Console) {
+    match policy.delay(shell.reconnect_attempts) {
+        Some(delay) => {
+            shell.next_reconnect_at = Some(std::time::Instant::now() + delay);
+        }
+        None => {
+            let msg = format!(
+                "Giving up reconnecting to {} after {} attempts\n",
+                shell.display_name, shell.reconnect_attempts
+            );
+            console.output(msg.as_bytes()).await;
+        }
+    }
+}
+
+/// Respawn a dead shell; a failed spawn counts as a failed attempt under the auto-reconnect policy.
+async fn reconnect_shell(
+    id: ShellId,
+    args: &cli::Args,
+    mgr: &mut ShellManager,
+    display_names: &mut DisplayNameRegistry,
+    shell_event_tx: &mpsc::Sender<ShellEvent>,
+    policy: &ReconnectPolicy,
+    console: &mut Console,
+) {
+    match respawn_shell(id, args, mgr, display_names.max_display_name_length, shell_event_tx) {
+        Ok(()) => {
+            if let Some(shell) = mgr.get_shell(id) {
+                if shell.enabled {
+                    display_names.set_enabled(&shell.display_name, true);
+                }
+            }
+        }
+        Err(e) => {
+            console.output(format!("{:#}\n", e).as_bytes()).await;
+            if let Some(shell) = mgr.get_shell_mut(id) {
+                shell.next_reconnect_at = None;
+                if shell.auto_reconnect {
+                    schedule_reconnect(shell, policy, console).await;
+                }
+            }
+        }
+    }
+}
+
+fn build_prompt(
+    idle: usize,
+    running: usize,
+    pending: usize,
+    dead: usize,
+    disabled: usize,
+    unresponsive: usize,
+    color: bool,
+) -> String {
     let mut status_parts: Vec<String> = Vec::new();
 
     if idle > 0 {
@@ -560,6 +1241,14 @@ fn build_prompt(idle: usize, running: usize, pending: usize, dead: usize, disabl
         }
     }
 
+    if unresponsive > 0 {
+        if color {
+            status_parts.push(format!("{} {}", "⚠".magenta(), unresponsive));
+        } else {
+            status_parts.push(format!("⚠ {}", unresponsive));
+        }
+    }
+
     let status = status_parts.join(" ");
     if color {
         format!("mash [{}] {}{}{} ", status, "❯".red(), "❯".yellow(), "❯".green())
@@ -574,7 +1263,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_all_idle() {
-        let p = build_prompt(5, 0, 0, 0, 0, false);
+        let p = build_prompt(5, 0, 0, 0, 0, 0, false);
         assert!(p.contains("● 5"));
         assert!(p.starts_with("mash ["));
         assert!(p.ends_with("❯❯❯ "));
@@ -582,7 +1271,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_mixed_states() {
-        let p = build_prompt(3, 1, 2, 0, 0, false);
+        let p = build_prompt(3, 1, 2, 0, 0, 0, false);
         assert!(p.contains("● 3"));
         assert!(p.contains("◉ 1"));
         assert!(p.contains("◌ 2"));
@@ -592,7 +1281,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_dead_and_disabled() {
-        let p = build_prompt(0, 0, 0, 2, 1, false);
+        let p = build_prompt(0, 0, 0, 2, 1, 0, false);
         assert!(p.contains("✕ 2"));
         assert!(p.contains("○ 1"));
         assert!(!p.contains("●"));
@@ -600,7 +1289,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_all_states() {
-        let p = build_prompt(1, 2, 3, 4, 5, false);
+        let p = build_prompt(1, 2, 3, 4, 5, 0, false);
         assert!(p.contains("● 1"));
         assert!(p.contains("◉ 2"));
         assert!(p.contains("◌ 3"));
@@ -608,9 +1297,17 @@ mod tests {
         assert!(p.contains("○ 5"));
     }
 
+    #[test]
+    fn test_build_prompt_unresponsive() {
+        let p = build_prompt(2, 0, 0, 0, 0, 1, false);
+        assert!(p.contains("● 2"));
+        assert!(p.contains("⚠ 1"));
+        assert!(!build_prompt(2, 0, 0, 0, 0, 0, false).contains("⚠"));
+    }
+
     #[test]
     fn test_build_prompt_colored_has_ansi() {
-        let p = build_prompt(3, 0, 0, 0, 0, true);
+        let p = build_prompt(3, 0, 0, 0, 0, 0, true);
         // Should contain ANSI escape codes
         assert!(p.contains("\x1b["));
         assert!(p.contains("mash"));
@@ -618,7 +1315,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_no_color_no_ansi() {
-        let p = build_prompt(3, 0, 0, 0, 0, false);
+        let p = build_prompt(3, 0, 0, 0, 0, 0, false);
         assert!(!p.contains("\x1b["));
     }
 }
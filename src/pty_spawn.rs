@@ -2,7 +2,7 @@ use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
 use std::os::unix::process::CommandExt;
 
 use color_eyre::eyre::{self, Context};
-use nix::pty::openpty;
+use nix::pty::{Winsize, openpty};
 use nix::sys::termios;
 use nix::unistd::ForkResult;
 
@@ -13,8 +13,67 @@ pub(crate) struct PtyChild {
     pub(crate) pid: i32,
 }
 
-pub(crate) fn spawn_ssh(hostname: &str, port: &str, ssh_template: &str, user: Option<&str>) -> eyre::Result<PtyChild> {
-    let pty_result = openpty(None, None).wrap_err("openpty failed")?;
+/// The local terminal's size, 80x25 when stdout is not a terminal or reports no size.
+pub(crate) fn local_term_size() -> (u16, u16) {
+    terminal_size::terminal_size()
+        .map(|(w, h)| (w.0, h.0))
+        .filter(|&(cols, rows)| cols > 0 && rows > 0)
+        .unwrap_or((80, 25))
+}
+
+/// The window size given to shells: the terminal minus the name prefix.
+pub(crate) fn shell_term_size(max_name_len: usize) -> (u16, u16) {
+    let (cols, rows) = local_term_size();
+    let adjusted_cols = std::cmp::max(cols as i32 - max_name_len as i32 - 2, std::cmp::min(cols as i32, 10)) as u16;
+    (adjusted_cols, rows)
+}
+
+pub(crate) fn spawn_ssh(
+    hostname: &str,
+    port: &str,
+    ssh_template: &str,
+    user: Option<&str>,
+    size: (u16, u16),
+) -> eyre::Result<PtyChild> {
+    let name = match user {
+        Some(u) => format!("{}@{}", u, hostname),
+        None => hostname.to_string(),
+    };
+    let port_arg = if port != "22" {
+        format!("-p {}", port)
+    } else {
+        String::new()
+    };
+
+    let mut evaluated = ssh_template.replace("%(host)s", &name).replace("%(port)s", &port_arg);
+
+    // If template didn't contain %(host)s, append the host
+    if evaluated == ssh_template.replace("%(port)s", &port_arg) && !evaluated.contains(&name) {
+        evaluated = format!("{} {}", evaluated, name);
+    }
+
+    let mut command = std::process::Command::new("/bin/sh");
+    command.arg("-c").arg(&evaluated);
+    let child = spawn_in_pty(command, size)?;
+
+    // Configure master PTY: disable echo, disable ONLCR
+    if let Ok(mut attrs) = termios::tcgetattr(child.master_fd.as_fd()) {
+        attrs.output_flags.remove(termios::OutputFlags::ONLCR);
+        attrs.local_flags.remove(termios::LocalFlags::ECHO);
+        let _ = termios::tcsetattr(child.master_fd.as_fd(), termios::SetArg::TCSANOW, &attrs);
+    }
+    Ok(child)
+}
+
+/// Run `command` as the leader of a new session, with a new pty of the given size as its terminal.
+pub(crate) fn spawn_in_pty(mut command: std::process::Command, (cols, rows): (u16, u16)) -> eyre::Result<PtyChild> {
+    let winsize = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty_result = openpty(Some(&winsize), None).wrap_err("openpty failed")?;
 
     match unsafe { nix::unistd::fork().wrap_err("fork failed")? } {
         ForkResult::Child => {
@@ -33,24 +92,7 @@ pub(crate) fn spawn_ssh(hostname: &str, port: &str, ssh_template: &str, user: Op
                 std::mem::forget(pty_result.slave);
             }
 
-            let name = match user {
-                Some(u) => format!("{}@{}", u, hostname),
-                None => hostname.to_string(),
-            };
-            let port_arg = if port != "22" {
-                format!("-p {}", port)
-            } else {
-                String::new()
-            };
-
-            let mut evaluated = ssh_template.replace("%(host)s", &name).replace("%(port)s", &port_arg);
-
-            // If template didn't contain %(host)s, append the host
-            if evaluated == ssh_template.replace("%(port)s", &port_arg) && !evaluated.contains(&name) {
-                evaluated = format!("{} {}", evaluated, name);
-            }
-
-            let err = std::process::Command::new("/bin/sh").arg("-c").arg(&evaluated).exec();
+            let err = command.exec();
 
             eprintln!("exec failed: {}", err);
             std::process::exit(1);
@@ -59,13 +101,6 @@ pub(crate) fn spawn_ssh(hostname: &str, port: &str, ssh_template: &str, user: Op
             // Drop slave in parent
             drop(pty_result.slave);
 
-            // Configure master PTY: disable echo, disable ONLCR
-            if let Ok(mut attrs) = termios::tcgetattr(pty_result.master.as_fd()) {
-                attrs.output_flags.remove(termios::OutputFlags::ONLCR);
-                attrs.local_flags.remove(termios::LocalFlags::ECHO);
-                let _ = termios::tcsetattr(pty_result.master.as_fd(), termios::SetArg::TCSANOW, &attrs);
-            }
-
             Ok(PtyChild {
                 master_fd: pty_result.master,
                 pid: child.as_raw(),
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How often and how quickly dead shells are automatically reconnected.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReconnectPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) base_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    pub(crate) fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+        }
+    }
+
+    /// Delay before the next attempt, doubling each time, or None once attempts are exhausted.
+    pub(crate) fn delay(&self, attempts: u32) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempts);
+        Some(self.base_delay.saturating_mul(factor).min(MAX_RECONNECT_DELAY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles() {
+        let policy = ReconnectPolicy::new(4, Duration::from_secs(1));
+        assert_eq!(policy.delay(0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(1), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(3), Some(Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn test_delay_exhausted() {
+        let policy = ReconnectPolicy::new(2, Duration::from_secs(1));
+        assert!(policy.delay(2).is_none());
+        assert!(ReconnectPolicy::new(0, Duration::from_secs(1)).delay(0).is_none());
+    }
+
+    #[test]
+    fn test_delay_capped() {
+        let policy = ReconnectPolicy::new(100, Duration::from_secs(5));
+        assert_eq!(policy.delay(10), Some(MAX_RECONNECT_DELAY));
+        assert_eq!(policy.delay(40), Some(MAX_RECONNECT_DELAY));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+/// How long Tab waits for the shells before completing without them.
+const QUERY_TIMEOUT: Duration = Duration::from_millis(800);
+/// How long the main loop waits for all the shells asked, before replying with the answers it has.
+pub(crate) const SHELL_WAIT: Duration = Duration::from_millis(500);
+/// How long an answer is reused for the same or a longer prefix.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+/// Candidates kept from each shell, an empty command prefix lists thousands.
+const MAX_CANDIDATES: usize = 1000;
+/// Idle shells asked at once, their answers are intersected.
+pub(crate) const MAX_SHELLS: usize = 3;
+
+/// What to complete on the remote side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RemoteKind {
+    /// A command name, from `compgen -c`
+    Command,
+    /// A path, from `compgen -f` or `ls -d prefix*`, with a `/` after directories
+    Path,
+}
+
+/// A Tab press waiting for the shells, answered by the main loop.
+pub(crate) struct RemoteQuery {
+    pub(crate) kind: RemoteKind,
+    pub(crate) prefix: String,
+    pub(crate) reply: std_mpsc::Sender<Vec<String>>,
+}
+
+/// A shell's progress with a query, between the markers framing its answer.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RemoteAnswer {
+    Sent,
+    Collecting(Vec<String>),
+    Done(Vec<String>),
+}
+
+/// The line typed into a shell for a query. The candidates are printed one per line between
+/// the `start` and `end` markers, given split in two so that no echo can be mistaken for them.
+/// The leading space keeps the line out of the shell's history.
+pub(crate) fn query_command(kind: RemoteKind, prefix: &str, start: (&[u8], &[u8]), end: (&[u8], &[u8])) -> String {
+    let quoted = shell_words::quote(prefix);
+    let query = match kind {
+        RemoteKind::Command => format!("compgen -c -- {} 2>/dev/null | head -n {}", quoted, MAX_CANDIDATES),
+        RemoteKind::Path => format!(
+            concat!(
+                "{{ compgen -f -- {q} || ls -1dp -- {q}*; }} 2>/dev/null | head -n {max} | ",
+                "while IFS= read -r w; do d=$w; case $w in \"~/\"*) d=$HOME/${{w#\"~/\"}};; esac; ",
+                "case $w in */) ;; *) [ -d \"$d\" ] && w=$w/;; esac; printf '%s\\n' \"$w\"; done"
+            ),
+            q = quoted,
+            max = MAX_CANDIDATES
+        ),
+    };
+    format!(
+        " echo \"{}\"\"{}\";{};echo \"{}\"\"{}\"\n",
+        String::from_utf8_lossy(start.0),
+        String::from_utf8_lossy(start.1),
+        query,
+        String::from_utf8_lossy(end.0),
+        String::from_utf8_lossy(end.1),
+    )
+}
+
+/// The candidates every shell offered, in the order of the first one.
+pub(crate) fn intersect(answers: Vec<Vec<String>>, prefix: &str) -> Vec<String> {
+    let mut answers = answers.into_iter();
+    let Some(first) = answers.next() else {
+        return Vec::new();
+    };
+    let others: Vec<Vec<String>> = answers.collect();
+    let mut words: Vec<String> = Vec::new();
+    for word in first {
+        if word.starts_with(prefix) && !words.contains(&word) && others.iter().all(|other| other.contains(&word)) {
+            words.push(word);
+        }
+    }
+    words
+}
+
+struct CachedAnswer {
+    at: Instant,
+    words: Vec<String>,
+}
+
+/// The input thread's side: sends the queries to the main loop and caches the answers.
+pub(crate) struct RemoteCompleter {
+    tx: mpsc::UnboundedSender<RemoteQuery>,
+    cache: Mutex<HashMap<(RemoteKind, String), CachedAnswer>>,
+}
+
+impl RemoteCompleter {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<RemoteQuery>) -> Self {
+        Self {
+            tx,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Candidates starting with `prefix`, empty when no shell answered in time.
+    pub(crate) fn complete(&self, kind: RemoteKind, prefix: &str) -> Vec<String> {
+        if let Some(words) = self.cached(kind, prefix) {
+            return words;
+        }
+        let (reply, answer) = std_mpsc::channel();
+        let query = RemoteQuery {
+            kind,
+            prefix: prefix.to_string(),
+            reply,
+        };
+        if self.tx.send(query).is_err() {
+            return Vec::new();
+        }
+        let Ok(words) = answer.recv_timeout(QUERY_TIMEOUT) else {
+            return Vec::new();
+        };
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.retain(|_, cached| cached.at.elapsed() < CACHE_TTL);
+            cache.insert(
+                (kind, prefix.to_string()),
+                CachedAnswer {
+                    at: Instant::now(),
+                    words: words.clone(),
+                },
+            );
+        }
+        words
+    }
+
+    fn cached(&self, kind: RemoteKind, prefix: &str) -> Option<Vec<String>> {
+        let cache = self.cache.lock().ok()?;
+        cache
+            .iter()
+            .filter(|((k, p), cached)| *k == kind && cached.at.elapsed() < CACHE_TTL && covers(kind, p, prefix))
+            // A truncated answer only stands for its own prefix
+            .find(|((_, p), cached)| p == prefix || cached.words.len() < MAX_CANDIDATES)
+            .map(|(_, cached)| {
+                cached
+                    .words
+                    .iter()
+                    .filter(|word| word.starts_with(prefix))
+                    .cloned()
+                    .collect()
+            })
+    }
+}
+
+/// Whether the answer for `cached` holds every candidate for `prefix`: commands always, paths only
+/// within the same directory.
+fn covers(kind: RemoteKind, cached: &str, prefix: &str) -> bool {
+    match prefix.strip_prefix(cached) {
+        Some(rest) => kind == RemoteKind::Command || !rest.contains('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_query_command() {
+        let cmd = query_command(RemoteKind::Command, "sys", (b"S1", b"S2"), (b"E1", b"E2"));
+        assert_eq!(
+            cmd,
+            " echo \"S1\"\"S2\";compgen -c -- sys 2>/dev/null | head -n 1000;echo \"E1\"\"E2\"\n"
+        );
+        let cmd = query_command(RemoteKind::Path, "/var/my dir", (b"S1", b"S2"), (b"E1", b"E2"));
+        assert!(cmd.contains("compgen -f -- '/var/my dir' || ls -1dp -- '/var/my dir'*;"));
+        assert!(!cmd.contains("S1S2"));
+    }
+
+    #[test]
+    fn test_query_command_runs() {
+        let dir = std::env::temp_dir().join(format!("mash-complete-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("subfile"), "").unwrap();
+        let prefix = format!("{}/sub", dir.display());
+        let cmd = query_command(RemoteKind::Path, &prefix, (b"<", b"<"), (b">", b">"));
+        for shell in ["bash", "sh"] {
+            let Ok(output) = std::process::Command::new(shell).arg("-c").arg(&cmd).output() else {
+                continue;
+            };
+            let mut lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(String::from)
+                .collect();
+            assert_eq!(lines.first().map(String::as_str), Some("<<"));
+            assert_eq!(lines.pop().as_deref(), Some(">>"));
+            lines.remove(0);
+            lines.sort();
+            assert_eq!(lines, vec![format!("{}dir/", prefix), format!("{}file", prefix)]);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_intersect() {
+        let answers = vec![
+            words(&["git", "gitk", "git", "gzip"]),
+            words(&["gzip", "git"]),
+            words(&["git", "gzip", "gpg"]),
+        ];
+        assert_eq!(intersect(answers, "g"), words(&["git", "gzip"]));
+        assert_eq!(intersect(vec![words(&["git", "ls"])], "g"), words(&["git"]));
+        assert!(intersect(Vec::new(), "g").is_empty());
+    }
+
+    #[test]
+    fn test_covers() {
+        assert!(covers(RemoteKind::Command, "sy", "sys"));
+        assert!(covers(RemoteKind::Path, "/et", "/etc"));
+        assert!(!covers(RemoteKind::Path, "/et", "/etc/"));
+        assert!(covers(RemoteKind::Command, "", "ls"));
+        assert!(!covers(RemoteKind::Command, "ls", "l"));
+    }
+
+    #[test]
+    fn test_cache() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let completer = RemoteCompleter::new(tx);
+        let thread = std::thread::spawn(move || {
+            let query = rx.blocking_recv().unwrap();
+            assert_eq!((query.kind, query.prefix.as_str()), (RemoteKind::Command, "sy"));
+            query.reply.send(words(&["sync", "sysctl", "systemctl"])).unwrap();
+            // No further query reaches the shells
+            assert!(rx.blocking_recv().is_none());
+        });
+        assert_eq!(completer.complete(RemoteKind::Command, "sy").len(), 3);
+        assert_eq!(
+            completer.complete(RemoteKind::Command, "sys"),
+            words(&["sysctl", "systemctl"])
+        );
+        drop(completer);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+use crate::hosts::HostEntry;
+use crate::shell::{RemoteShell, ShellState};
+
+/// The hosts of a session and how they were set up, written by `:save_session` and read by `--session`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SavedSession {
+    /// `--ssh` template
+    pub(crate) ssh: String,
+    /// `--user`
+    pub(crate) user: Option<String>,
+    pub(crate) hosts: Vec<SavedHost>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct SavedHost {
+    pub(crate) host: String,
+    pub(crate) port: String,
+    pub(crate) user: Option<String>,
+    /// Name given with `:rename` or `name=`, None when it is still the hostname
+    pub(crate) name: Option<String>,
+    pub(crate) enabled: bool,
+    pub(crate) tags: Vec<String>,
+    pub(crate) ssh: Option<String>,
+    pub(crate) vars: BTreeMap<String, String>,
+}
+
+impl SavedHost {
+    pub(crate) fn from_shell(shell: &RemoteShell) -> Self {
+        // The `#N` of a duplicate name is handed out again when restoring
+        let name = shell
+            .display_name
+            .split_once('#')
+            .map_or(shell.display_name.as_str(), |(name, _)| name);
+        Self {
+            host: shell.hostname.clone(),
+            port: shell.port.clone(),
+            user: shell.user.clone(),
+            name: (name != shell.hostname).then(|| name.to_string()),
+            // A dead shell is reconnected with the flag it had before dying
+            enabled: if shell.state == ShellState::Dead {
+                shell.enabled_before_disconnect
+            } else {
+                shell.enabled
+            },
+            tags: shell.tags.clone(),
+            ssh: shell.ssh.clone(),
+            vars: shell.vars.clone(),
+        }
+    }
+
+    pub(crate) fn to_entry(&self) -> HostEntry {
+        let mut entry = HostEntry::new(format!("{}:{}", self.host, self.port));
+        entry.user = self.user.clone();
+        entry.name = self.name.clone();
+        entry.ssh = self.ssh.clone();
+        entry.tags = self.tags.clone();
+        entry.vars = self.vars.clone();
+        entry.enabled = self.enabled;
+        entry
+    }
+
+    fn to_json(&self) -> Value {
+        let mut json = Map::new();
+        json.insert("host".to_string(), Value::String(self.host.clone()));
+        json.insert("port".to_string(), Value::String(self.port.clone()));
+        if let Some(ref user) = self.user {
+            json.insert("user".to_string(), Value::String(user.clone()));
+        }
+        if let Some(ref name) = self.name {
+            json.insert("name".to_string(), Value::String(name.clone()));
+        }
+        json.insert("enabled".to_string(), Value::Bool(self.enabled));
+        if !self.tags.is_empty() {
+            let tags = self.tags.iter().cloned().map(Value::String).collect();
+            json.insert("tags".to_string(), Value::Array(tags));
+        }
+        if let Some(ref ssh) = self.ssh {
+            json.insert("ssh".to_string(), Value::String(ssh.clone()));
+        }
+        if !self.vars.is_empty() {
+            let vars = self
+                .vars
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                .collect();
+            json.insert("vars".to_string(), Value::Object(vars));
+        }
+        Value::Object(json)
+    }
+
+    fn parse(json: &Value) -> Result<Self, String> {
+        let field = |name: &str| match json.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            // Ports are often written as numbers by hand
+            Some(Value::Number(value)) if name == "port" => Ok(Some(value.to_string())),
+            Some(_) => Err(format!("\"{}\" must be a string", name)),
+        };
+        let host = field("host")?.ok_or("Missing \"host\"")?;
+        let tags = match json.get("tags") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(tags)) => tags
+                .iter()
+                .map(|tag| tag.as_str().map(str::to_string).ok_or("\"tags\" must be strings"))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("\"tags\" must be a list".to_string()),
+        };
+        let vars = match json.get("vars") {
+            None | Some(Value::Null) => BTreeMap::new(),
+            Some(Value::Object(vars)) => vars
+                .iter()
+                .map(|(key, value)| {
+                    let value = value.as_str().ok_or(format!("\"vars\".{} must be a string", key))?;
+                    Ok((key.clone(), value.to_string()))
+                })
+                .collect::<Result<_, String>>()?,
+            Some(_) => return Err("\"vars\" must be an object".to_string()),
+        };
+        Ok(Self {
+            port: field("port")?.unwrap_or_else(|| "22".to_string()),
+            user: field("user")?,
+            name: field("name")?.filter(|name| !name.is_empty()),
+            enabled: json.get("enabled").and_then(Value::as_bool).unwrap_or(true),
+            tags,
+            ssh: field("ssh")?,
+            vars,
+            host,
+        })
+    }
+}
+
+impl SavedSession {
+    pub(crate) fn to_json(&self) -> Value {
+        let mut json = Map::new();
+        json.insert("ssh".to_string(), Value::String(self.ssh.clone()));
+        if let Some(ref user) = self.user {
+            json.insert("user".to_string(), Value::String(user.clone()));
+        }
+        let hosts = self.hosts.iter().map(SavedHost::to_json).collect();
+        json.insert("hosts".to_string(), Value::Array(hosts));
+        Value::Object(json)
+    }
+
+    pub(crate) fn parse(json: &Value) -> Result<Self, String> {
+        let ssh = json
+            .get("ssh")
+            .and_then(Value::as_str)
+            .ok_or("Missing \"ssh\" template")?;
+        let hosts = json
+            .get("hosts")
+            .and_then(Value::as_array)
+            .ok_or("Missing \"hosts\" list")?
+            .iter()
+            .enumerate()
+            .map(|(i, host)| SavedHost::parse(host).map_err(|e| format!("host {}: {}", i + 1, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            ssh: ssh.to_string(),
+            user: json.get("user").and_then(Value::as_str).map(str::to_string),
+            hosts,
+        })
+    }
+
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("Error reading session file {}: {}", path, e))?;
+        let json: Value =
+            serde_json::from_str(&content).map_err(|e| format!("Error in session file {}: {}", path, e))?;
+        Self::parse(&json).map_err(|e| format!("Error in session file {}: {}", path, e))
+    }
+
+    pub(crate) fn save(&self, path: &str) -> Result<(), String> {
+        let mut text = serde_json::to_string_pretty(&self.to_json()).unwrap_or_default();
+        text.push('\n');
+        std::fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+
+    /// The hosts to spawn, carrying their saved attributes.
+    pub(crate) fn entries(&self) -> Vec<HostEntry> {
+        self.hosts.iter().map(SavedHost::to_entry).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> SavedSession {
+        SavedSession {
+            ssh: "exec ssh -t %(host)s %(port)s".to_string(),
+            user: Some("oncall".to_string()),
+            hosts: vec![
+                SavedHost {
+                    host: "web1".to_string(),
+                    port: "22".to_string(),
+                    user: None,
+                    name: Some("frontend".to_string()),
+                    enabled: true,
+                    tags: vec!["prod".to_string(), "web".to_string()],
+                    ssh: None,
+                    vars: BTreeMap::new(),
+                },
+                SavedHost {
+                    host: "db1".to_string(),
+                    port: "2222".to_string(),
+                    user: Some("postgres".to_string()),
+                    name: None,
+                    enabled: false,
+                    tags: Vec::new(),
+                    ssh: Some("exec ssh -J bastion %(host)s %(port)s".to_string()),
+                    vars: BTreeMap::from([("role".to_string(), "primary".to_string())]),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let session = session();
+        let json = session.to_json();
+        assert_eq!(json["hosts"][0]["name"], "frontend");
+        assert_eq!(json["hosts"][1]["enabled"], false);
+        assert!(json["hosts"][0].get("user").is_none());
+        assert_eq!(SavedSession::parse(&json), Ok(session));
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let json = serde_json::json!({"ssh": "exec ssh %(host)s", "hosts": [{"host": "web1", "port": 2200}]});
+        let session = SavedSession::parse(&json).unwrap();
+        assert_eq!(session.user, None);
+        assert_eq!(session.hosts[0].port, "2200");
+        assert!(session.hosts[0].enabled);
+        assert!(session.hosts[0].tags.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |text: &str| SavedSession::parse(&serde_json::from_str(text).unwrap());
+        assert!(parse(r#"{"hosts": []}"#).is_err());
+        assert!(parse(r#"{"ssh": "ssh"}"#).is_err());
+        assert_eq!(
+            parse(r#"{"ssh": "ssh", "hosts": [{"port": "22"}]}"#),
+            Err("host 1: Missing \"host\"".to_string())
+        );
+        assert!(parse(r#"{"ssh": "ssh", "hosts": [{"host": "a", "tags": "prod"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_entries() {
+        let entries = session().entries();
+        assert_eq!(entries[0].host, "web1:22");
+        assert_eq!(entries[0].name.as_deref(), Some("frontend"));
+        assert!(entries[0].enabled);
+        assert_eq!(entries[1].host, "db1:2222");
+        assert_eq!(entries[1].user.as_deref(), Some("postgres"));
+        assert!(!entries[1].enabled);
+        assert_eq!(entries[1].vars["role"], "primary");
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::VecDeque;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{self, Context, bail};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::Pid;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::attach::{self, RawTerminal};
+use crate::durations::format_uptime;
+use crate::pty_spawn::{self, local_term_size};
+
+/// Set on the background server, holding the session name.
+const SERVER_ENV: &str = "MASH_SESSION_SERVER";
+/// Set on the mash running inside a session, which then ignores `--detachable`.
+const SESSION_ENV: &str = "MASH_SESSION";
+/// Output kept for the next client to attach
+const REPLAY_BYTES: usize = 256 * 1024;
+/// Ctrl-\ detaches the client
+const DETACH_KEY: u8 = 0x1c;
+/// Longest frame accepted from the peer, well above the replay sent on attach
+const MAX_FRAME_BYTES: usize = 4 * 1024 * 1024;
+
+/// Messages between a client and the session server: a type byte, a big endian length, the payload.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    /// Keystrokes to the server, output to the client
+    Data(Vec<u8>),
+    /// The client's terminal size, first frame of an attach
+    Resize { cols: u16, rows: u16 },
+    /// mash exited with this code
+    Exit(i32),
+    /// Empty from `mash ls`, answered with the session's details as JSON
+    Info(Vec<u8>),
+}
+
+impl Frame {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Frame::Data(data) => (0u8, data.clone()),
+            Frame::Resize { cols, rows } => (1, [cols.to_be_bytes(), rows.to_be_bytes()].concat()),
+            Frame::Exit(code) => (2, code.to_be_bytes().to_vec()),
+            Frame::Info(info) => (3, info.clone()),
+        };
+        let mut out = Vec::with_capacity(5 + payload.len());
+        out.push(kind);
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    /// The next frame, None at the end of the stream.
+    pub(crate) async fn read(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Frame>> {
+        let mut header = [0u8; 5];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid session frame");
+        if len > MAX_FRAME_BYTES {
+            return Err(invalid());
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        match header[0] {
+            0 => Ok(Some(Frame::Data(payload))),
+            1 if len == 4 => Ok(Some(Frame::Resize {
+                cols: u16::from_be_bytes([payload[0], payload[1]]),
+                rows: u16::from_be_bytes([payload[2], payload[3]]),
+            })),
+            2 if len == 4 => Ok(Some(Frame::Exit(i32::from_be_bytes([
+                payload[0], payload[1], payload[2], payload[3],
+            ])))),
+            3 => Ok(Some(Frame::Info(payload))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Name of the session this process serves, when it is a session server.
+pub(crate) fn server_name() -> Option<String> {
+    std::env::var(SERVER_ENV).ok()
+}
+
+/// Whether this mash runs inside a detachable session.
+pub(crate) fn in_session() -> bool {
+    std::env::var_os(SESSION_ENV).is_some()
+}
+
+/// `$XDG_RUNTIME_DIR/mash`, or a per-user directory in /tmp.
+fn sessions_dir() -> std::io::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("mash"),
+        None => std::env::temp_dir().join(format!("mash-{}", nix::unistd::getuid())),
+    };
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    // An existing directory is left as it is, it could belong to another user in /tmp
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || meta.mode() & 0o077 != 0 {
+        return Err(std::io::Error::other(format!(
+            "{} must be a directory owned by the current user, not accessible to others",
+            dir.display()
+        )));
+    }
+    Ok(dir)
+}
+
+fn socket_path(name: &str) -> eyre::Result<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid session name: {:?}", name);
+    }
+    let dir = sessions_dir().wrap_err("Failed to create the sessions directory")?;
+    Ok(dir.join(format!("{}.sock", name)))
+}
+
+/// Start mash in a background server and attach to it. Returns the exit code.
+pub(crate) async fn start(name: Option<&str>) -> eyre::Result<i32> {
+    let name = name
+        .map(str::to_string)
+        .unwrap_or_else(|| std::process::id().to_string());
+    let path = socket_path(&name)?;
+    if UnixStream::connect(&path).await.is_ok() {
+        bail!("Session {} already exists, use mash attach {}", name, name);
+    }
+
+    let exe = std::env::current_exe().wrap_err("Failed to find the mash executable")?;
+    let mut server = std::process::Command::new(exe);
+    server
+        .args(std::env::args_os().skip(1))
+        .env(SERVER_ENV, &name)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+    // Its own session, so it outlives the terminal
+    unsafe {
+        server.pre_exec(|| nix::unistd::setsid().map(|_| ()).map_err(std::io::Error::from));
+    }
+    server.spawn().wrap_err("Failed to start the session server")?;
+
+    for _ in 0..50 {
+        if let Ok(stream) = UnixStream::connect(&path).await {
+            return attach_stream(&name, stream).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bail!("Session server for {} did not start", name)
+}
+
+/// `mash attach [SESSION]`
+pub(crate) async fn attach_command(name: Option<String>) -> eyre::Result<i32> {
+    if in_session() {
+        bail!("Already inside a mash session");
+    }
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let sessions = live_sessions().await?;
+            match sessions.as_slice() {
+                [] => bail!("No mash sessions"),
+                [(name, _)] => name.clone(),
+                _ => {
+                    let names: Vec<&str> = sessions.iter().map(|(name, _)| name.as_str()).collect();
+                    bail!("Several mash sessions, pick one of: {}", names.join(", "))
+                }
+            }
+        }
+    };
+    let stream = UnixStream::connect(socket_path(&name)?)
+        .await
+        .wrap_err_with(|| format!("No mash session named {}", name))?;
+    attach_stream(&name, stream).await
+}
+
+/// `mash ls`
+pub(crate) async fn list_command() -> eyre::Result<i32> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let sessions = live_sessions().await?;
+    if sessions.is_empty() {
+        println!("No mash sessions");
+    }
+    let width = sessions.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, info) in sessions {
+        let attached = if info["attached"].as_bool() == Some(true) {
+            "attached"
+        } else {
+            "detached"
+        };
+        let started = info["started"].as_u64().unwrap_or(now);
+        println!(
+            "{:width$}  {}  up {}  {}",
+            name,
+            attached,
+            format_uptime(now.saturating_sub(started)),
+            info["args"].as_str().unwrap_or_default(),
+        );
+    }
+    Ok(0)
+}
+
+/// Running sessions with their details, removing the sockets of dead ones.
+async fn live_sessions() -> eyre::Result<Vec<(String, serde_json::Value)>> {
+    let dir = sessions_dir().wrap_err("Failed to read the sessions directory")?;
+    let mut sockets: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_socket()))
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sock"))
+        .collect();
+    sockets.sort();
+
+    let mut sessions = Vec::new();
+    for path in sockets {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        match query_info(&path).await {
+            Some(info) => sessions.push((name, info)),
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+async fn query_info(path: &Path) -> Option<serde_json::Value> {
+    let mut stream = UnixStream::connect(path).await.ok()?;
+    stream.write_all(&Frame::Info(Vec::new()).encode()).await.ok()?;
+    match tokio::time::timeout(Duration::from_secs(2), Frame::read(&mut stream)).await {
+        Ok(Ok(Some(Frame::Info(info)))) => serde_json::from_slice(&info).ok(),
+        _ => None,
+    }
+}
+
+/// Pass the terminal through to the session until it exits or Ctrl-\ is typed.
+async fn attach_stream(name: &str, stream: UnixStream) -> eyre::Result<i32> {
+    // A server going away must not kill the client with the terminal still raw
+    unsafe {
+        signal::signal(Signal::SIGPIPE, signal::SigHandler::SigIgn).ok();
+    }
+    let (mut reader, mut writer) = stream.into_split();
+    let (cols, rows) = local_term_size();
+    writer.write_all(&Frame::Resize { cols, rows }.encode()).await?;
+
+    let mut winch = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?;
+    let mut terminal = RawTerminal::start(false).wrap_err("Failed to put the terminal in raw mode")?;
+    let exit_code = loop {
+        tokio::select! {
+            input = terminal.read_input() => {
+                let Some(input) = input else { break None };
+                let (keys, detach) = match input.iter().position(|&b| b == DETACH_KEY) {
+                    Some(pos) => (&input[..pos], true),
+                    None => (&input[..], false),
+                };
+                if !keys.is_empty() && writer.write_all(&Frame::Data(keys.to_vec()).encode()).await.is_err() {
+                    break None;
+                }
+                if detach {
+                    break None;
+                }
+            }
+            frame = Frame::read(&mut reader) => match frame {
+                Ok(Some(Frame::Data(data))) => attach::write_output(&data),
+                Ok(Some(Frame::Exit(code))) => break Some(code),
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break None,
+            },
+            _ = winch.recv() => {
+                let (cols, rows) = local_term_size();
+                let _ = writer.write_all(&Frame::Resize { cols, rows }.encode()).await;
+            }
+        }
+    };
+    drop(terminal);
+    match exit_code {
+        Some(code) => Ok(code),
+        None => {
+            eprintln!(
+                "\r\n[detached from session {}, resume with: mash attach {}]",
+                name, name
+            );
+            Ok(0)
+        }
+    }
+}
+
+/// Events from the clients' connections.
+enum ClientEvent {
+    Attach {
+        client: usize,
+        writer: OwnedWriteHalf,
+        size: (u16, u16),
+    },
+    Info {
+        writer: OwnedWriteHalf,
+    },
+    Frame {
+        client: usize,
+        frame: Frame,
+    },
+    Gone {
+        client: usize,
+    },
+}
+
+/// Run mash on a pty and serve it to clients until it exits.
+pub(crate) async fn run_server(name: &str) -> eyre::Result<i32> {
+    unsafe {
+        signal::signal(Signal::SIGPIPE, signal::SigHandler::SigIgn).ok();
+    }
+    let path = socket_path(name)?;
+    if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path).wrap_err_with(|| format!("Failed to listen on {}", path.display()))?;
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let exe = std::env::current_exe().wrap_err("Failed to find the mash executable")?;
+    let mut command = std::process::Command::new(exe);
+    command.args(&args).env_remove(SERVER_ENV).env(SESSION_ENV, name);
+    let child = pty_spawn::spawn_in_pty(command, local_term_size())?;
+    let pid = Pid::from_raw(child.pid);
+    let master = child.master_fd;
+    let flags = nix::fcntl::fcntl(master.as_fd(), nix::fcntl::FcntlArg::F_GETFL).unwrap_or(0);
+    let mut oflags = nix::fcntl::OFlag::from_bits_truncate(flags);
+    oflags.insert(nix::fcntl::OFlag::O_NONBLOCK);
+    let _ = nix::fcntl::fcntl(master.as_fd(), nix::fcntl::FcntlArg::F_SETFL(oflags));
+    let master = AsyncFd::new(master)?;
+
+    let (event_tx, mut event_rx) = mpsc::channel::<ClientEvent>(64);
+    let mut next_client = 0usize;
+    let mut attached: Option<(usize, OwnedWriteHalf)> = None;
+    let mut replay: VecDeque<u8> = VecDeque::new();
+    // Keystrokes the pty did not take yet, such as a long paste
+    let mut input: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        tokio::select! {
+            ready = master.readable() => {
+                let mut ready = ready?;
+                let read = ready.try_io(|fd| {
+                    nix::unistd::read(fd.get_ref().as_fd(), &mut buf).map_err(std::io::Error::from)
+                });
+                let n = match read {
+                    Ok(Ok(0)) | Ok(Err(_)) => break,
+                    Ok(Ok(n)) => n,
+                    Err(_would_block) => continue,
+                };
+                replay.extend(&buf[..n]);
+                if replay.len() > REPLAY_BYTES {
+                    replay.drain(..replay.len() - REPLAY_BYTES);
+                }
+                if let Some((_, ref mut writer)) = attached
+                    && writer.write_all(&Frame::Data(buf[..n].to_vec()).encode()).await.is_err()
+                {
+                    attached = None;
+                }
+            }
+            ready = master.writable(), if !input.is_empty() => {
+                let mut ready = ready?;
+                match ready.try_io(|fd| nix::unistd::write(fd.get_ref(), &input).map_err(std::io::Error::from)) {
+                    Ok(Ok(n)) => {
+                        input.drain(..n);
+                    }
+                    // The pty is gone, the read side ends the loop
+                    Ok(Err(_)) => input.clear(),
+                    Err(_would_block) => {}
+                }
+            }
+            Ok((stream, _)) = listener.accept() => {
+                tokio::spawn(serve_client(next_client, stream, event_tx.clone()));
+                next_client += 1;
+            }
+            Some(event) = event_rx.recv() => match event {
+                ClientEvent::Attach { client, mut writer, size } => {
+                    // The replay starts at a line, not in the middle of an escape sequence
+                    let skip = if replay.len() == REPLAY_BYTES {
+                        replay.iter().position(|&b| b == b'\n').map_or(0, |p| p + 1)
+                    } else {
+                        0
+                    };
+                    let history: Vec<u8> = replay.iter().skip(skip).copied().collect();
+                    if writer.write_all(&Frame::Data(history).encode()).await.is_ok() {
+                        // Replaces (and so disconnects) the previous client
+                        attached = Some((client, writer));
+                        set_size(&master, size);
+                    }
+                }
+                ClientEvent::Info { mut writer } => {
+                    let info = serde_json::json!({
+                        "attached": attached.is_some(),
+                        "started": started,
+                        "args": shell_words::join(&args),
+                    });
+                    let _ = writer.write_all(&Frame::Info(info.to_string().into_bytes()).encode()).await;
+                }
+                ClientEvent::Frame { client, frame } => {
+                    if attached.as_ref().is_none_or(|(current, _)| *current != client) {
+                        continue;
+                    }
+                    match frame {
+                        Frame::Data(data) => input.extend_from_slice(&data),
+                        Frame::Resize { cols, rows } => set_size(&master, (cols, rows)),
+                        Frame::Exit(_) | Frame::Info(_) => {}
+                    }
+                }
+                ClientEvent::Gone { client } => {
+                    if attached.as_ref().is_some_and(|(current, _)| *current == client) {
+                        attached = None;
+                    }
+                }
+            },
+        }
+    }
+
+    let code = match waitpid(pid, None) {
+        Ok(WaitStatus::Exited(_, code)) => code,
+        Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
+        _ => 1,
+    };
+    if let Some((_, mut writer)) = attached {
+        let _ = writer.write_all(&Frame::Exit(code).encode()).await;
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(code)
+}
+
+fn set_size(master: &AsyncFd<OwnedFd>, (cols, rows): (u16, u16)) {
+    let winsize = nix::pty::Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // The kernel sends SIGWINCH to mash, which resizes its shells
+    unsafe {
+        nix::libc::ioctl(master.get_ref().as_raw_fd(), nix::libc::TIOCSWINSZ, &winsize);
+    }
+}
+
+async fn serve_client(client: usize, stream: UnixStream, events: mpsc::Sender<ClientEvent>) {
+    let (mut reader, writer) = stream.into_split();
+    match Frame::read(&mut reader).await {
+        Ok(Some(Frame::Resize { cols, rows })) => {
+            let attach = ClientEvent::Attach {
+                client,
+                writer,
+                size: (cols, rows),
+            };
+            if events.send(attach).await.is_err() {
+                return;
+            }
+        }
+        Ok(Some(Frame::Info(_))) => {
+            let _ = events.send(ClientEvent::Info { writer }).await;
+            return;
+        }
+        _ => return,
+    }
+    while let Ok(Some(frame)) = Frame::read(&mut reader).await {
+        if events.send(ClientEvent::Frame { client, frame }).await.is_err() {
+            return;
+        }
+    }
+    let _ = events.send(ClientEvent::Gone { client }).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let frames = [
+            Frame::Data(b"uptime\r".to_vec()),
+            Frame::Resize { cols: 200, rows: 50 },
+            Frame::Exit(-1),
+            Frame::Info(Vec::new()),
+        ];
+        let encoded: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        let mut reader = encoded.as_slice();
+        for frame in frames {
+            assert_eq!(Frame::read(&mut reader).await.unwrap(), Some(frame));
+        }
+        assert_eq!(Frame::read(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_frame_rejects_oversized_length() {
+        let mut reader: &[u8] = &[0, 0xff, 0xff, 0xff, 0xff];
+        let err = Frame::read(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_frame_rejects_unknown_type() {
+        let mut reader: &[u8] = &[9, 0, 0, 0, 0];
+        assert!(Frame::read(&mut reader).await.is_err());
+    }
+}
@@ -1,13 +1,26 @@
+use std::collections::BTreeMap;
 use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use nix::pty::Winsize;
-use owo_colors::{AnsiColors, OwoColorize, Style};
+use owo_colors::{DynColors, OwoColorize, Style};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
 
 use crate::callbacks::{CallbackAction, CallbackRegistry};
 use crate::console::Console;
+use crate::control_socket::SessionEvent;
+use crate::display_names::display_width;
+use crate::line_discipline::{InvalidUtf8, LineDiscipline, RemoteColors};
+use crate::remote_completion::{self, RemoteAnswer, RemoteKind};
+use crate::template;
 
 nix::ioctl_write_ptr_bad!(set_winsize, nix::libc::TIOCSWINSZ, Winsize);
 
+/// Output kept while attached, enough to end with the prompt set by `prepare_attach`.
+const ATTACH_TAIL_LEN: usize = 4096;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub(crate) struct ShellId(pub(crate) usize);
 
@@ -32,16 +45,12 @@ impl ShellState {
     }
 }
 
-const COLORS: &[AnsiColors] = &[
-    AnsiColors::BrightBlack,
-    AnsiColors::Red,
-    AnsiColors::Green,
-    AnsiColors::Yellow,
-    AnsiColors::Blue,
-    AnsiColors::Magenta,
-    AnsiColors::Cyan,
-    AnsiColors::Default,
-];
+/// What `check_timeout` did to a shell whose command overran its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeoutAction {
+    Interrupted,
+    Disconnect,
+}
 
 pub(crate) struct RemoteShell {
     pub(crate) id: ShellId,
@@ -63,6 +72,55 @@ pub(crate) struct RemoteShell {
     pub(crate) command: Option<String>,
     pub(crate) password: Option<String>,
     pub(crate) callbacks: CallbackRegistry,
+    pub(crate) spawned_at: Instant,
+    pub(crate) command_sent: bool,
+    pub(crate) timed_out: bool,
+    pub(crate) enabled_before_disconnect: bool,
+    pub(crate) auto_reconnect: bool,
+    pub(crate) reconnect_attempts: u32,
+    pub(crate) next_reconnect_at: Option<Instant>,
+    pub(crate) probe_sent_at: Option<Instant>,
+    /// Prompts following a keepalive probe or a completion query, not a command finishing
+    pub(crate) prompts_to_skip: u32,
+    pub(crate) last_probe_latency: Option<Duration>,
+    pub(crate) unresponsive: bool,
+    pub(crate) command_deadline: Option<Instant>,
+    pub(crate) interrupt_sent_at: Option<Instant>,
+    pub(crate) vars: BTreeMap<String, String>,
+    /// Remote user from the hosts file or inventory, overriding `--user`
+    pub(crate) user: Option<String>,
+    /// ssh command template from the hosts file, overriding `--ssh`
+    pub(crate) ssh: Option<String>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) targeted: bool,
+    /// Buffer command output and print it as one block once the command finishes
+    pub(crate) group_output: bool,
+    output_block: Vec<u8>,
+    pub(crate) block_finished_at: Option<Instant>,
+    /// `<display_name>.out` in the output directory, receiving unprefixed output
+    output_file: Option<tokio::fs::File>,
+    /// Output collected for a `:pipe` or `:capture`
+    pub(crate) capture: Option<Vec<u8>>,
+    /// Whether captured output is printed as well
+    pub(crate) print_captured: bool,
+    /// Window size set by `:set_size`, None to follow the terminal
+    pub(crate) fixed_size: Option<(u16, u16)>,
+    /// Renders the raw pty output into lines
+    pub(crate) line_discipline: LineDiscipline,
+    /// Exit code of the last command, read from the prompt
+    pub(crate) last_exit_code: Option<i32>,
+    /// Control socket subscribers
+    pub(crate) events: Option<broadcast::Sender<SessionEvent>>,
+    /// Answer to the completion query sent to this shell
+    pub(crate) remote_answer: Option<RemoteAnswer>,
+    /// Number of the last completion query sent, telling its answer from a late one
+    completion_query: u32,
+    /// Inside the answer to a query given up on, whose lines are dropped
+    discarding_answer: bool,
+    /// Prompt set by `prepare_attach`, None when the shell was busy at `:attach`
+    attach_prompt: Option<Vec<u8>>,
+    /// End of the output shown while attached
+    attach_tail: Vec<u8>,
 }
 
 impl RemoteShell {
@@ -77,15 +135,9 @@ impl RemoteShell {
         debug: bool,
         command: Option<String>,
         password: Option<String>,
-        color_idx: usize,
-        use_color: bool,
+        color: Option<DynColors>,
     ) -> Self {
-        let color_style = if use_color {
-            let color = COLORS[color_idx % COLORS.len()];
-            Some(Style::new().color(color).bold())
-        } else {
-            None
-        };
+        let color_style = color.map(|c| Style::new().color(c).bold());
 
         let mut callbacks = CallbackRegistry::new();
         let init_string = Self::build_init_string(id, &mut callbacks);
@@ -110,6 +162,39 @@ impl RemoteShell {
             command,
             password,
             callbacks,
+            spawned_at: Instant::now(),
+            command_sent: false,
+            timed_out: false,
+            enabled_before_disconnect: true,
+            auto_reconnect: false,
+            reconnect_attempts: 0,
+            next_reconnect_at: None,
+            probe_sent_at: None,
+            prompts_to_skip: 0,
+            last_probe_latency: None,
+            unresponsive: false,
+            command_deadline: None,
+            interrupt_sent_at: None,
+            vars: BTreeMap::new(),
+            user: None,
+            ssh: None,
+            tags: Vec::new(),
+            targeted: false,
+            group_output: false,
+            output_block: Vec::new(),
+            block_finished_at: None,
+            output_file: None,
+            capture: None,
+            print_captured: false,
+            fixed_size: None,
+            line_discipline: LineDiscipline::new(RemoteColors::Keep, InvalidUtf8::Lossy),
+            last_exit_code: None,
+            events: None,
+            remote_answer: None,
+            completion_query: 0,
+            discarding_answer: false,
+            attach_prompt: None,
+            attach_tail: Vec::new(),
         }
     }
 
@@ -128,14 +213,21 @@ impl RemoteShell {
         // Disable zsh's partial-line marker (the % at end of output without trailing newline)
         // unsetopt/PROMPT_EOL_MARK are zsh-only but harmless on other shells (silent no-op or unused var)
         init.extend_from_slice(b"unsetopt PROMPT_CR PROMPT_SP 2>/dev/null;PROMPT_EOL_MARK=;");
+        // zsh only expands the exit code in the prompt below with PROMPT_SUBST
+        init.extend_from_slice(b"setopt PROMPT_SUBST 2>/dev/null;");
         init.extend_from_slice(b"PS2=;RPS1=;RPROMPT=;PROMPT_COMMAND=;TERM=ansi;unset HISTFILE;");
+        // Lines starting with a space, like the completion queries, stay out of the history
+        init.extend_from_slice(
+            b"HISTCONTROL=${HISTCONTROL:+$HISTCONTROL:}ignorespace;setopt HIST_IGNORE_SPACE 2>/dev/null;",
+        );
 
         let (p1, p2) = callbacks.add(b"prompt", CallbackAction::SeenPrompt, true);
         init.extend_from_slice(b"PS1=\"");
         init.extend_from_slice(&p1);
         init.extend_from_slice(b"\"\"");
         init.extend_from_slice(&p2);
-        init.extend_from_slice(b"\n\"\n");
+        // The exit code of the last command follows the marker, expanded at each prompt
+        init.extend_from_slice(b"\"'$?'\"\n\"\n");
         init
     }
 
@@ -143,6 +235,151 @@ impl RemoteShell {
         self.init_string = Self::build_init_string(self.id, &mut self.callbacks);
     }
 
+    /// Undo the init string's terminal settings for direct use; `reset_prompt` restores them.
+    pub(crate) fn prepare_attach(&mut self, term: &str) {
+        self.attach_prompt = None;
+        self.attach_tail.clear();
+        if self.state != ShellState::Idle {
+            // Something is running, typing the settings would go to it
+            return;
+        }
+        let prompt = format!("{}$ ", self.display_name);
+        let setup = format!(
+            "stty echo onlcr ctlecho 2>/dev/null;TERM={};PS1={}\n",
+            shell_words::quote(term),
+            shell_words::quote(&prompt)
+        );
+        self.write_to_pty(setup.as_bytes());
+        self.attach_prompt = Some(prompt.into_bytes());
+    }
+
+    /// Keep the end of the output shown while attached, to tell whether the shell is back at its prompt.
+    pub(crate) fn record_attached_output(&mut self, data: &[u8]) {
+        self.attach_tail.extend_from_slice(data);
+        let excess = self.attach_tail.len().saturating_sub(ATTACH_TAIL_LEN);
+        self.attach_tail.drain(..excess);
+    }
+
+    /// Re-send the init string after `:attach`. Echo is still on, so the shell goes back to
+    /// NotStarted, keeping the echoed init lines out of the output as at startup. A shell still
+    /// busy with a program is left Running instead, as the init string would be typed into it,
+    /// and false is returned.
+    pub(crate) async fn reset_after_attach(&mut self) -> bool {
+        let at_prompt = match self.attach_prompt.take() {
+            // A program still running on the remote side shows something else last
+            Some(prompt) => self.attach_tail.ends_with(&prompt),
+            // Busy at `:attach`, the shell was left untouched
+            None => return true,
+        };
+        self.attach_tail.clear();
+        if !at_prompt {
+            if self.state == ShellState::Idle {
+                self.change_state(ShellState::Running, None).await;
+            }
+            return false;
+        }
+        self.read_buffer.clear();
+        self.line_discipline.reset();
+        self.change_state(ShellState::NotStarted, None).await;
+        self.rebuild_init_string();
+        self.write_to_pty(&self.init_string);
+        true
+    }
+
+    /// Re-send the init string, for shells whose prompt was lost or changed.
+    pub(crate) async fn reset_prompt(&mut self) {
+        self.rebuild_init_string();
+        let init = self.init_string.clone();
+        self.dispatch_command(&init).await;
+    }
+
+    /// Attach a freshly spawned ssh process, keeping the display name, color and settings.
+    pub(crate) fn reattach(&mut self, pid: i32, master_fd: OwnedFd) {
+        self.pid = pid;
+        self.master_fd = master_fd;
+        self.state = ShellState::NotStarted;
+        self.enabled = self.enabled_before_disconnect;
+        self.read_buffer.clear();
+        self.line_discipline.reset();
+        self.write_buffer.clear();
+        self.read_in_state_not_started.clear();
+        self.callbacks = CallbackRegistry::new();
+        self.init_string = Self::build_init_string(self.id, &mut self.callbacks);
+        self.init_string_sent = false;
+        self.spawned_at = Instant::now();
+        self.command_sent = false;
+        self.next_reconnect_at = None;
+        self.probe_sent_at = None;
+        self.prompts_to_skip = 0;
+        self.remote_answer = None;
+        self.discarding_answer = false;
+        self.unresponsive = false;
+        self.command_deadline = None;
+        self.interrupt_sent_at = None;
+        self.targeted = false;
+    }
+
+    /// Send a no-op marker echo to an idle shell to check that the connection is still alive.
+    pub(crate) fn send_keepalive(&mut self) {
+        if self.state != ShellState::Idle || self.probe_sent_at.is_some() {
+            return;
+        }
+        let (p1, p2) = self.callbacks.add(b"keepalive", CallbackAction::Keepalive, false);
+        let cmd = format!(
+            "echo \"{}\"\"{}\"\n",
+            String::from_utf8_lossy(&p1),
+            String::from_utf8_lossy(&p2),
+        );
+        self.write_to_pty(cmd.as_bytes());
+        self.probe_sent_at = Some(Instant::now());
+        // The echo is followed by a fresh prompt that must not be mistaken for a command finishing
+        self.prompts_to_skip += 1;
+    }
+
+    /// Ask an idle shell for completion candidates, collected into `remote_answer`.
+    /// Returns false when the shell is busy.
+    pub(crate) fn send_completion_query(&mut self, kind: RemoteKind, prefix: &str) -> bool {
+        if self.state != ShellState::Idle || self.remote_answer.is_some() {
+            return false;
+        }
+        self.completion_query += 1;
+        let query = self.completion_query;
+        let start = self
+            .callbacks
+            .add(b"completion start", CallbackAction::CompletionStart(query), false);
+        let end = self
+            .callbacks
+            .add(b"completion end", CallbackAction::CompletionEnd(query), false);
+        let cmd = remote_completion::query_command(kind, prefix, (&start.0, &start.1), (&end.0, &end.1));
+        self.write_to_pty(cmd.as_bytes());
+        self.remote_answer = Some(RemoteAnswer::Sent);
+        self.prompts_to_skip += 1;
+        true
+    }
+
+    /// Give up on the completion query that was not answered in time, so that the shell is
+    /// asked again next time. The rest of a late answer is dropped.
+    pub(crate) fn expire_completion_query(&mut self) {
+        match self.remote_answer {
+            Some(RemoteAnswer::Sent) => self.remote_answer = None,
+            Some(RemoteAnswer::Collecting(_)) => {
+                self.remote_answer = None;
+                self.discarding_answer = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Mark the shell unresponsive if its keepalive marker is overdue. Returns true when newly marked.
+    pub(crate) fn check_keepalive(&mut self, timeout: Duration) -> bool {
+        let overdue = self.probe_sent_at.is_some_and(|t| t.elapsed() > timeout);
+        if overdue && !self.unresponsive && self.state != ShellState::Dead {
+            self.unresponsive = true;
+            return true;
+        }
+        false
+    }
+
     async fn change_state(&mut self, new_state: ShellState, console: Option<&mut Console>) {
         if new_state != self.state {
             if self.debug {
@@ -154,7 +391,32 @@ impl RemoteShell {
             if self.state == ShellState::NotStarted {
                 self.read_in_state_not_started.clear();
             }
+            if new_state == ShellState::Running {
+                self.last_exit_code = None;
+            }
+            if new_state == ShellState::Idle {
+                self.command_deadline = None;
+                self.interrupt_sent_at = None;
+                self.targeted = false;
+            }
+            if matches!(new_state, ShellState::Idle | ShellState::Dead) && !self.output_block.is_empty() {
+                self.block_finished_at.get_or_insert_with(Instant::now);
+            }
             self.state = new_state;
+            self.send_event(|shell| SessionEvent::State {
+                name: shell.display_name.clone(),
+                state: new_state,
+                exit_code: shell.last_exit_code.filter(|_| new_state == ShellState::Idle),
+            });
+        }
+    }
+
+    /// Publish an event, built only when the control socket has subscribers.
+    fn send_event(&self, event: impl FnOnce(&Self) -> SessionEvent) {
+        if let Some(ref tx) = self.events
+            && tx.receiver_count() > 0
+        {
+            let _ = tx.send(event(self));
         }
     }
 
@@ -177,10 +439,90 @@ impl RemoteShell {
         }
     }
 
+    /// Start the deadline for the command that was just dispatched, unless one is already running.
+    pub(crate) fn arm_timeout(&mut self, timeout: Option<Duration>) {
+        let busy = self.state == ShellState::Running || (self.state == ShellState::NotStarted && self.command_sent);
+        if let Some(timeout) = timeout {
+            if busy && self.command_deadline.is_none() {
+                self.command_deadline = Some(Instant::now() + timeout);
+                self.interrupt_sent_at = None;
+                self.timed_out = false;
+            }
+        }
+    }
+
+    /// Next instant at which `check_timeout` has something to do.
+    pub(crate) fn timeout_due_at(&self, grace: Duration) -> Option<Instant> {
+        if self.state == ShellState::Idle || self.state == ShellState::Dead {
+            return None;
+        }
+        match self.interrupt_sent_at {
+            Some(sent) => Some(sent + grace),
+            None => self.command_deadline,
+        }
+    }
+
+    /// Interrupt a command that overran its deadline, or ask for a disconnect once the grace period is over.
+    pub(crate) fn check_timeout(&mut self, now: Instant, grace: Duration) -> Option<TimeoutAction> {
+        if self.timeout_due_at(grace)? > now {
+            return None;
+        }
+        if self.interrupt_sent_at.is_none() {
+            self.write_to_pty(b"\x03");
+            self.interrupt_sent_at = Some(now);
+            self.timed_out = true;
+            Some(TimeoutAction::Interrupted)
+        } else {
+            Some(TimeoutAction::Disconnect)
+        }
+    }
+
+    /// The display name in the host color, for messages and listings.
+    pub(crate) fn colored_name(&self) -> String {
+        match self.color_style {
+            Some(style) => format!("{}", self.display_name.style(style)),
+            None => self.display_name.clone(),
+        }
+    }
+
+    /// Match a glob against the display name or the last printed line, or against the tags with `tag:GLOB`.
+    pub(crate) fn matches_pattern(&self, pattern: &str) -> bool {
+        let glob_pat = glob::Pattern::new(pattern.strip_prefix("tag:").unwrap_or(pattern));
+        let matches = |text: &str| match &glob_pat {
+            Ok(p) => p.matches(text),
+            Err(_) => text == pattern,
+        };
+        if pattern.starts_with("tag:") {
+            return self.tags.iter().any(|tag| matches(tag));
+        }
+        matches(&self.display_name) || matches(&String::from_utf8_lossy(&self.last_printed_line))
+    }
+
+    /// Like `dispatch_command`, but also reaches a disabled shell, which is then awaited
+    /// until the command finishes without changing its enabled flag. The timeout only applies
+    /// when this starts a command: a line typed into a running job is input for it.
+    pub(crate) async fn dispatch_targeted(&mut self, command: &[u8], timeout: Option<Duration>) {
+        if self.state == ShellState::Dead {
+            return;
+        }
+        self.write_to_pty(command);
+        if !self.enabled {
+            self.targeted = true;
+        }
+        if self.state == ShellState::Idle {
+            self.change_state(ShellState::Running, None).await;
+            self.arm_timeout(timeout);
+        }
+    }
+
     pub(crate) async fn disconnect(&mut self, console: &mut Console, max_name_len: usize, _abort_error: bool) {
         let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(-self.pid), nix::sys::signal::Signal::SIGKILL);
         self.read_buffer.clear();
+        self.line_discipline.reset();
         self.write_buffer.clear();
+        if self.state != ShellState::Dead {
+            self.enabled_before_disconnect = self.enabled;
+        }
         self.enabled = false;
 
         if !self.read_in_state_not_started.is_empty() {
@@ -198,11 +540,37 @@ impl RemoteShell {
             return;
         }
 
-        let indent = if max_name_len >= self.display_name.len() {
-            max_name_len - self.display_name.len()
-        } else {
-            0
-        };
+        for line in String::from_utf8_lossy(&cleaned).lines() {
+            self.send_event(|shell| SessionEvent::Output {
+                name: shell.display_name.clone(),
+                line: line.to_string(),
+            });
+        }
+
+        if let Some(ref mut file) = self.output_file {
+            let _ = file.write_all(&cleaned).await;
+            let _ = file.write_all(b"\n").await;
+            // tokio writes in the background, flush so the file is complete if mash exits
+            let _ = file.flush().await;
+        }
+
+        // Output arriving while idle (e.g. from background jobs) has no command to group under
+        if let Some(capture) = self.capture.as_mut().filter(|_| self.state != ShellState::Idle) {
+            capture.extend_from_slice(&cleaned);
+            capture.push(b'\n');
+            if !self.print_captured {
+                self.track_last_printed_line(cleaned);
+                return;
+            }
+        }
+        if self.group_output && self.state != ShellState::Idle {
+            self.output_block.extend_from_slice(&cleaned);
+            self.output_block.push(b'\n');
+            self.track_last_printed_line(cleaned);
+            return;
+        }
+
+        let indent = max_name_len.saturating_sub(display_width(&self.display_name));
 
         let log_prefix = format!("{}{} : ", self.display_name, " ".repeat(indent));
         let console_prefix = match self.color_style {
@@ -234,8 +602,10 @@ impl RemoteShell {
         log_data.push(b'\n');
 
         console.output_with_log(&console_data, Some(&log_data)).await;
+        self.track_last_printed_line(cleaned);
+    }
 
-        // Track last printed line
+    fn track_last_printed_line(&mut self, cleaned: Vec<u8>) {
         if let Some(pos) = cleaned.iter().rposition(|&b| b == b'\n') {
             self.last_printed_line = cleaned[pos + 1..].to_vec();
         } else {
@@ -243,6 +613,50 @@ impl RemoteShell {
         }
     }
 
+    /// Start writing output to `dir/<display_name>.out`, or stop with None.
+    pub(crate) fn set_output_dir(&mut self, dir: Option<&Path>) -> std::io::Result<()> {
+        self.output_file = match dir {
+            Some(dir) => {
+                let file = std::fs::File::create(output_path(dir, &self.display_name, "out"))?;
+                Some(tokio::fs::File::from_std(file))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Write `dir/<display_name>.status`: the exit code, then the connection error if any.
+    pub(crate) fn write_status(&self, dir: &Path, exit_code: i32, connection_error: bool) -> std::io::Result<()> {
+        let mut status = format!("{}\n", exit_code);
+        if connection_error {
+            let error = String::from_utf8_lossy(trim_ascii_bytes(&self.last_printed_line));
+            let error = if error.is_empty() { "connection failed" } else { &error };
+            status.push_str(&format!("{}\n", error));
+        }
+        std::fs::write(output_path(dir, &self.display_name, "status"), status)
+    }
+
+    /// Take the buffered output as a block under a header line, for the console and the log.
+    pub(crate) fn take_output_block(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.block_finished_at = None;
+        if self.output_block.is_empty() {
+            return None;
+        }
+        let body = std::mem::take(&mut self.output_block);
+        let header = format!("── {} ──", self.display_name);
+        let console_header = match self.color_style {
+            Some(style) => format!("{}", header.style(style)),
+            None => header.clone(),
+        };
+        let block = |header: String| {
+            let mut out = header.into_bytes();
+            out.push(b'\n');
+            out.extend_from_slice(&body);
+            out
+        };
+        Some((block(console_header), block(header)))
+    }
+
     /// Process incoming data. Returns Some(new_name) if a rename callback was triggered.
     pub(crate) async fn handle_data(
         &mut self,
@@ -261,7 +675,9 @@ impl RemoteShell {
             self.print_debug(&[b"==> ", new_data].concat(), console).await;
         }
 
-        self.read_buffer.extend_from_slice(new_data);
+        // Only complete lines reach the read buffer, the line being drawn stays in the line discipline
+        let lines = self.line_discipline.feed(new_data);
+        self.read_buffer.extend_from_slice(&lines);
 
         // Fast path: running state, no callback markers, has newline
         if self.state == ShellState::Running && !self.callbacks.any_in(&self.read_buffer) {
@@ -275,12 +691,19 @@ impl RemoteShell {
 
         // Check for password prompt in NOT_STARTED state
         if self.state == ShellState::NotStarted && self.password.is_some() {
-            let lower: Vec<u8> = self.read_buffer.iter().map(|b| b.to_ascii_lowercase()).collect();
+            let pending = self.line_discipline.pending_line();
+            let lower: Vec<u8> = self
+                .read_buffer
+                .iter()
+                .chain(&pending)
+                .map(|b| b.to_ascii_lowercase())
+                .collect();
             if lower.windows(9).any(|w| w == b"password:") {
                 if let Some(ref pw) = self.password {
                     let pw_cmd = format!("{}\n", pw);
                     self.write_to_pty(pw_cmd.as_bytes());
                     self.read_buffer.clear();
+                    self.line_discipline.take_pending_line();
                     return None;
                 }
             }
@@ -294,7 +717,13 @@ impl RemoteShell {
             if let Some(action) = self.callbacks.process(&line) {
                 match action {
                     CallbackAction::SeenPrompt => {
-                        if interactive {
+                        if self.prompts_to_skip > 0 {
+                            self.prompts_to_skip -= 1;
+                        } else if interactive {
+                            self.reconnect_attempts = 0;
+                            if self.state == ShellState::Running {
+                                self.last_exit_code = prompt_exit_code(&line);
+                            }
                             self.change_state(ShellState::Idle, Some(console)).await;
                         } else if let Some(cmd) = self.command.take() {
                             // Non-interactive: send command, then exit
@@ -307,6 +736,7 @@ impl RemoteShell {
                             self.write_to_pty(ps1_cmd.as_bytes());
                             self.write_to_pty(cmd.as_bytes());
                             self.write_to_pty(b"exit 2>/dev/null\n");
+                            self.command_sent = true;
                         }
                     }
                     CallbackAction::Rename { new_name } => {
@@ -316,8 +746,42 @@ impl RemoteShell {
                             pending_rename = Some(self.hostname.as_bytes().to_vec());
                         }
                     }
+                    CallbackAction::Keepalive => {
+                        if let Some(sent) = self.probe_sent_at.take() {
+                            self.last_probe_latency = Some(sent.elapsed());
+                        }
+                        if self.unresponsive {
+                            self.unresponsive = false;
+                            let msg = format!("{} is responsive again\n", self.display_name);
+                            console.output(msg.as_bytes()).await;
+                        }
+                    }
+                    CallbackAction::CompletionStart(query) => {
+                        if query == self.completion_query && self.remote_answer == Some(RemoteAnswer::Sent) {
+                            self.remote_answer = Some(RemoteAnswer::Collecting(Vec::new()));
+                        } else {
+                            // The answer to a query given up on
+                            self.discarding_answer = true;
+                        }
+                    }
+                    CallbackAction::CompletionEnd(query) => {
+                        if self.discarding_answer {
+                            self.discarding_answer = false;
+                        } else if query == self.completion_query
+                            && let Some(RemoteAnswer::Collecting(words)) = self.remote_answer.take()
+                        {
+                            self.remote_answer = Some(RemoteAnswer::Done(words));
+                        }
+                    }
                     CallbackAction::None => {}
                 }
+            } else if self.discarding_answer {
+                // Part of a late completion answer
+            } else if let Some(RemoteAnswer::Collecting(words)) = self.remote_answer.as_mut() {
+                let word = String::from_utf8_lossy(trim_ascii_bytes(&line)).into_owned();
+                if !word.is_empty() {
+                    words.push(word);
+                }
             } else if self.state == ShellState::Idle || self.state == ShellState::Running {
                 self.print_lines(&line, console, max_name_len).await;
             } else if self.state == ShellState::NotStarted {
@@ -361,14 +825,22 @@ impl RemoteShell {
     }
 
     pub(crate) async fn print_unfinished_line(&mut self, console: &mut Console, max_name_len: usize) {
-        if self.state == ShellState::Running && !self.read_buffer.is_empty() {
-            let buf = std::mem::take(&mut self.read_buffer);
-            if self.callbacks.process(&buf).is_none() {
-                self.print_lines(&buf, console, max_name_len).await;
-            }
+        if self.state != ShellState::Running {
+            return;
+        }
+        let mut buf = std::mem::take(&mut self.read_buffer);
+        buf.extend(self.line_discipline.take_pending_line());
+        if !buf.is_empty() && self.callbacks.process(&buf).is_none() {
+            self.print_lines(&buf, console, max_name_len).await;
         }
     }
 
+    /// Follow the terminal's size, unless `:set_size` fixed this shell's.
+    pub(crate) fn resize(&self, cols: u16, rows: u16) {
+        let (cols, rows) = self.fixed_size.unwrap_or((cols, rows));
+        self.set_term_size(cols, rows);
+    }
+
     pub(crate) fn set_term_size(&self, cols: u16, rows: u16) {
         let wsz = Winsize {
             ws_row: rows,
@@ -379,6 +851,37 @@ impl RemoteShell {
         unsafe { set_winsize(self.master_fd.as_raw_fd(), &wsz) }.ok();
     }
 
+    /// Render `{{...}}` placeholders in a command line with this shell's values. `default_user`
+    /// (`--user`) stands for `{{user}}` when the host has no user of its own.
+    pub(crate) fn render_template(
+        &self,
+        line: &str,
+        rank: Option<usize>,
+        nr_shells: usize,
+        default_user: Option<&str>,
+    ) -> String {
+        template::render(line, |name| match name {
+            "host" => Some(self.hostname.clone()),
+            "name" => Some(self.display_name.clone()),
+            "port" => Some(self.port.clone()),
+            "user" if self.user.is_some() || default_user.is_some() => {
+                self.user.clone().or_else(|| default_user.map(str::to_string))
+            }
+            "rank" => rank.map(|r| r.to_string()),
+            "nr_shells" => Some(nr_shells.to_string()),
+            _ => self.vars.get(name).cloned(),
+        })
+    }
+
+    /// Host in the `host[:port]` form accepted on the command line and in hosts files.
+    pub(crate) fn host_spec(&self) -> String {
+        if self.port == "22" {
+            self.hostname.clone()
+        } else {
+            format!("{}:{}", self.hostname, self.port)
+        }
+    }
+
     pub(crate) fn get_info(&self) -> Vec<Vec<u8>> {
         vec![
             self.display_name.as_bytes().to_vec(),
@@ -388,10 +891,22 @@ impl RemoteShell {
                 b"disabled".to_vec()
             },
             format!("{}:", self.state.name()).into_bytes(),
+            self.probe_info().into_bytes(),
             self.last_printed_line.clone(),
         ]
     }
 
+    fn probe_info(&self) -> String {
+        if self.unresponsive {
+            "unresponsive".to_string()
+        } else {
+            match self.last_probe_latency {
+                Some(latency) => format!("{}ms", latency.as_millis()),
+                None => String::new(),
+            }
+        }
+    }
+
     async fn print_debug(&self, msg: &[u8], console: &mut Console) {
         let mut out = Vec::new();
         out.extend_from_slice(b"[dbg] ");
@@ -405,6 +920,17 @@ impl RemoteShell {
     }
 }
 
+/// Per-host file in an output directory; `/` can't appear in a file name.
+fn output_path(dir: &Path, display_name: &str, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", display_name.replace('/', "_"), extension))
+}
+
+/// The exit code expanded after the prompt marker, which ends with `/`.
+fn prompt_exit_code(line: &[u8]) -> Option<i32> {
+    let start = line.iter().rposition(|&b| b == b'/')? + 1;
+    std::str::from_utf8(trim_ascii_bytes(&line[start..])).ok()?.parse().ok()
+}
+
 /// Trim ASCII whitespace from both ends of a byte slice.
 fn trim_ascii_bytes(data: &[u8]) -> &[u8] {
     let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
@@ -475,6 +1001,16 @@ mod tests {
         assert_eq!(trim_ascii_bytes(b"\t\nhello\n\t"), b"hello");
     }
 
+    // --- prompt_exit_code tests ---
+
+    #[test]
+    fn test_prompt_exit_code() {
+        assert_eq!(prompt_exit_code(b"mash-abcde:prompt:xyz12:0/0\n"), Some(0));
+        assert_eq!(prompt_exit_code(b"mash-abcde:prompt:xyz12:0/127\n"), Some(127));
+        // zsh without PROMPT_SUBST leaves it unexpanded
+        assert_eq!(prompt_exit_code(b"mash-abcde:prompt:xyz12:0/$?\n"), None);
+    }
+
     // --- strip_newlines tests ---
 
     #[test]
@@ -541,8 +1077,7 @@ mod tests {
             false,
             None,
             None,
-            0,
-            false,
+            None,
         );
         (shell, read_fd)
     }
@@ -637,6 +1172,263 @@ mod tests {
         assert!(nix::unistd::read(read_fd.as_fd(), &mut buf).is_err());
     }
 
+    // --- render_template tests ---
+
+    #[test]
+    fn test_render_template_builtins_and_vars() {
+        let (mut shell, _read_fd) = make_test_shell();
+        shell.vars.insert("role".into(), "db".into());
+        shell.vars.insert("host".into(), "ignored".into());
+        assert_eq!(
+            shell.render_template(
+                "{{name}}/{{host}}:{{port}} {{rank}}/{{nr_shells}} {{role}} {{other}}",
+                Some(1),
+                4,
+                None
+            ),
+            "testhost/testhost:22 1/4 db {{other}}"
+        );
+    }
+
+    #[test]
+    fn test_render_template_user() {
+        let (mut shell, _read_fd) = make_test_shell();
+        assert_eq!(shell.render_template("{{user}}", None, 1, None), "{{user}}");
+        assert_eq!(shell.render_template("{{user}}", None, 1, Some("ops")), "ops");
+        shell.user = Some("postgres".into());
+        assert_eq!(shell.render_template("{{user}}", None, 1, Some("ops")), "postgres");
+    }
+
+    // --- command timeout tests ---
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_then_disconnects() {
+        let (mut shell, read_fd) = make_test_shell();
+        let grace = std::time::Duration::from_secs(5);
+
+        shell.state = ShellState::Idle;
+        shell.dispatch_command(b"sleep 100\n").await;
+        shell.arm_timeout(Some(std::time::Duration::from_secs(10)));
+        let deadline = shell.timeout_due_at(grace).unwrap();
+
+        assert_eq!(
+            shell.check_timeout(deadline - std::time::Duration::from_secs(1), grace),
+            None
+        );
+        assert_eq!(shell.check_timeout(deadline, grace), Some(TimeoutAction::Interrupted));
+        assert!(shell.timed_out);
+
+        let mut buf = [0u8; 64];
+        let n = nix::unistd::read(read_fd.as_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"sleep 100\n\x03");
+
+        assert_eq!(
+            shell.check_timeout(deadline + grace, grace),
+            Some(TimeoutAction::Disconnect)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_cleared_when_idle() {
+        let (mut shell, _read_fd) = make_test_shell();
+
+        shell.state = ShellState::Idle;
+        shell.dispatch_command(b"true\n").await;
+        shell.arm_timeout(Some(std::time::Duration::from_secs(10)));
+        assert!(shell.command_deadline.is_some());
+
+        shell.change_state(ShellState::Idle, None).await;
+        assert!(shell.command_deadline.is_none());
+        assert!(shell.timeout_due_at(std::time::Duration::from_secs(5)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reset_after_attach_waits_for_prompt() {
+        let (mut shell, _read_fd) = make_test_shell();
+
+        shell.state = ShellState::Idle;
+        shell.prepare_attach("xterm");
+        shell.record_attached_output(b"testhost$ vim notes\r\n\x1b[?1049h~\r\n");
+        assert!(!shell.reset_after_attach().await);
+        assert_eq!(shell.state, ShellState::Running);
+
+        shell.state = ShellState::Idle;
+        shell.prepare_attach("xterm");
+        shell.record_attached_output(b"testhost$ ls\r\nnotes\r\ntesthost$ ");
+        assert!(shell.reset_after_attach().await);
+        assert_eq!(shell.state, ShellState::NotStarted);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_targeted_arms_only_new_commands() {
+        let (mut shell, _read_fd) = make_test_shell();
+        let timeout = Some(std::time::Duration::from_secs(10));
+
+        // Input for a job started without a timeout, like `tail -f` or a y/n prompt
+        shell.state = ShellState::Running;
+        shell.dispatch_targeted(b"y\n", timeout).await;
+        assert!(shell.command_deadline.is_none());
+
+        shell.change_state(ShellState::Idle, None).await;
+        shell.dispatch_targeted(b"uptime\n", timeout).await;
+        assert!(shell.command_deadline.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_targeted_reaches_disabled_shell() {
+        let (mut shell, read_fd) = make_test_shell();
+
+        shell.state = ShellState::Idle;
+        shell.enabled = false;
+        shell.dispatch_targeted(b"uptime\n", None).await;
+
+        assert_eq!(shell.state, ShellState::Running);
+        assert!(!shell.enabled);
+        assert!(shell.targeted);
+
+        let mut buf = [0u8; 64];
+        let n = nix::unistd::read(read_fd.as_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"uptime\n");
+
+        shell.change_state(ShellState::Idle, None).await;
+        assert!(!shell.targeted);
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        let (mut shell, _read_fd) = make_test_shell();
+        shell.display_name = "db1".into();
+        shell.last_printed_line = b"Continue? [y/N]".to_vec();
+        shell.tags = vec!["db".into(), "primary".into()];
+
+        assert!(shell.matches_pattern("db*"));
+        assert!(shell.matches_pattern("Continue*"));
+        assert!(!shell.matches_pattern("web*"));
+        assert!(shell.matches_pattern("tag:db"));
+        assert!(shell.matches_pattern("tag:prim*"));
+        assert!(!shell.matches_pattern("tag:db1"));
+    }
+
+    #[tokio::test]
+    async fn test_group_output_buffers_until_idle() {
+        let (mut shell, _read_fd) = make_test_shell();
+        let mut console = Console::new(false, None).await;
+
+        shell.group_output = true;
+        shell.state = ShellState::Running;
+        shell.print_lines(b"line1\nline2\n", &mut console, 8).await;
+        shell.print_lines(b"line3\n", &mut console, 8).await;
+        assert_eq!(shell.last_printed_line, b"line3");
+        assert!(shell.block_finished_at.is_none());
+
+        shell.change_state(ShellState::Idle, None).await;
+        assert!(shell.block_finished_at.is_some());
+
+        let (console_block, log_block) = shell.take_output_block().unwrap();
+        assert_eq!(console_block, log_block);
+        assert_eq!(log_block, "── testhost ──\nline1\nline2\nline3\n".as_bytes());
+        assert!(shell.take_output_block().is_none());
+        assert!(shell.block_finished_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_output_dir_files() {
+        let dir = std::env::temp_dir().join(format!("mash-output-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut shell, _read_fd) = make_test_shell();
+        let mut console = Console::new(false, None).await;
+        shell.display_name = "web/1".into();
+
+        shell.set_output_dir(Some(&dir)).unwrap();
+        shell
+            .print_lines(
+                b"line1\nssh: connect to host web1 port 22: Connection refused\n",
+                &mut console,
+                8,
+            )
+            .await;
+        shell.write_status(&dir, 255, true).unwrap();
+        let out = std::fs::read_to_string(dir.join("web_1.out")).unwrap();
+        let status = std::fs::read_to_string(dir.join("web_1.status")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(out, "line1\nssh: connect to host web1 port 22: Connection refused\n");
+        assert_eq!(status, "255\nssh: connect to host web1 port 22: Connection refused\n");
+    }
+
+    /// Read the query typed into the shell and return its start and end markers
+    fn read_query_markers(read_fd: &std::os::fd::OwnedFd) -> (Vec<u8>, Vec<u8>) {
+        let mut buf = [0u8; 4096];
+        let n = nix::unistd::read(read_fd.as_fd(), &mut buf).unwrap();
+        let cmd = String::from_utf8_lossy(&buf[..n]).into_owned();
+        assert!(cmd.starts_with(' '));
+        let parts: Vec<&str> = cmd.split('"').collect();
+        (
+            [parts[1], parts[3]].concat().into_bytes(),
+            [parts[5], parts[7]].concat().into_bytes(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_expired_completion_answer_is_discarded() {
+        let (mut shell, read_fd) = make_test_shell();
+        let mut console = Console::new(false, None).await;
+
+        shell.state = ShellState::Idle;
+        assert!(shell.send_completion_query(RemoteKind::Command, "sys"));
+        let (start1, end1) = read_query_markers(&read_fd);
+        shell.expire_completion_query();
+        assert_eq!(shell.remote_answer, None);
+
+        // The late answer to the first query must not be taken for the answer to the second
+        assert!(shell.send_completion_query(RemoteKind::Command, "sys"));
+        let (start2, end2) = read_query_markers(&read_fd);
+        let data = [
+            &start1[..],
+            b"\nlate\n",
+            &end1,
+            b"\n",
+            &start2,
+            b"\nsystemctl\n",
+            &end2,
+            b"\n",
+        ]
+        .concat();
+        shell.handle_data(&data, &mut console, 8, true, false).await;
+        assert_eq!(
+            shell.remote_answer,
+            Some(RemoteAnswer::Done(vec!["systemctl".to_string()]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_capture_takes_output() {
+        let (mut shell, _read_fd) = make_test_shell();
+        let mut console = Console::new(false, None).await;
+
+        shell.capture = Some(Vec::new());
+        shell.state = ShellState::Running;
+        shell.print_lines(b"line1\nline2\n", &mut console, 8).await;
+        // Once idle, output is printed again rather than captured
+        shell.state = ShellState::Idle;
+        shell.print_lines(b"background\n", &mut console, 8).await;
+        assert_eq!(shell.capture.as_deref(), Some(&b"line1\nline2\n"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_capture_printed_output_is_grouped_too() {
+        let (mut shell, _read_fd) = make_test_shell();
+        let mut console = Console::new(false, None).await;
+
+        shell.capture = Some(Vec::new());
+        shell.print_captured = true;
+        shell.group_output = true;
+        shell.state = ShellState::Running;
+        shell.print_lines(b"line1\n", &mut console, 8).await;
+        assert_eq!(shell.capture.as_deref(), Some(&b"line1\n"[..]));
+        assert_eq!(shell.output_block, b"line1\n");
+    }
+
     // --- write_to_pty tests (used for Ctrl-C forwarding) ---
 
     #[test]
@@ -652,8 +1444,7 @@ mod tests {
             false,
             None,
             None,
-            0,
-            false,
+            None,
         );
 
         shell.write_to_pty(b"\x03");
@@ -676,8 +1467,7 @@ mod tests {
             false,
             None,
             None,
-            0,
-            false,
+            None,
         );
 
         shell.state = ShellState::Running;
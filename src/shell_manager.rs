@@ -1,14 +1,90 @@
 use std::collections::BTreeMap;
 use std::os::fd::OwnedFd;
-
-use crate::display_names::DisplayNameRegistry;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use owo_colors::DynColors;
+use tokio::sync::broadcast;
+
+use crate::capture::{self, CaptureBuffer, CaptureTarget, CapturedOutput, FinishedCapture};
+use crate::colors::Palette;
+use crate::console::Console;
+use crate::control_socket::SessionEvent;
+use crate::display_names::{DisplayNameRegistry, display_width};
+use crate::history::CommandHistory;
+use crate::line_discipline::{InvalidUtf8, LineDiscipline, RemoteColors};
+use crate::remote_completion::{self, RemoteAnswer, RemoteQuery};
 use crate::shell::{RemoteShell, ShellId, ShellState};
+use crate::template;
+
+/// How command output from several shells is put on the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputMode {
+    /// Print lines as they arrive, interleaved between shells
+    Stream,
+    /// Print each shell's output as one block once its command finishes
+    Grouped(BlockOrder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockOrder {
+    /// As soon as each shell finishes
+    Completion,
+    /// Sorted by display name, once every shell has finished
+    Name,
+}
+
+impl BlockOrder {
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        match text {
+            "completion" => Some(Self::Completion),
+            "name" => Some(Self::Name),
+            _ => None,
+        }
+    }
+}
+
+/// A `:pipe` or `:capture` waiting for its shells to finish the command.
+struct PendingCapture {
+    target: CaptureTarget,
+    command: String,
+    shells: Vec<ShellId>,
+}
+
+/// A completion query sent to some idle shells, answered once they all replied.
+struct PendingCompletion {
+    prefix: String,
+    reply: std::sync::mpsc::Sender<Vec<String>>,
+    shells: Vec<ShellId>,
+    /// Past it, the shells that answered are enough
+    deadline: Instant,
+}
 
 pub(crate) struct ShellManager {
     shells: BTreeMap<ShellId, RemoteShell>,
     next_id: usize,
-    color_rotation: usize,
-    use_color: bool,
+    /// None when colors are disabled
+    palette: Option<Palette>,
+    /// `--host-color` overrides, as (hostname glob, color)
+    pub(crate) color_overrides: Vec<(glob::Pattern, DynColors)>,
+    /// `--remote-colors`: whether the SGR sequences in the shells' output are kept
+    pub(crate) remote_colors: RemoteColors,
+    /// `--invalid-utf8`: how bytes that are not UTF-8 are shown
+    pub(crate) invalid_utf8: InvalidUtf8,
+    pub(crate) command_timeout: Option<Duration>,
+    pub(crate) render_templates: bool,
+    /// `--user`, for the `{{user}}` of hosts without a user of their own
+    pub(crate) default_user: Option<String>,
+    output_mode: OutputMode,
+    output_dir: Option<PathBuf>,
+    pending_capture: Option<PendingCapture>,
+    /// `:capture` buffers by name
+    buffers: BTreeMap<String, CaptureBuffer>,
+    /// Control socket subscribers, given to every shell
+    events: Option<broadcast::Sender<SessionEvent>>,
+    /// Commands sent to the shells, for `:history` and `:rerun`
+    history: CommandHistory,
+    pending_completion: Option<PendingCompletion>,
 }
 
 impl ShellManager {
@@ -16,8 +92,20 @@ impl ShellManager {
         Self {
             shells: BTreeMap::new(),
             next_id: 0,
-            color_rotation: 0,
-            use_color,
+            palette: use_color.then(Palette::detect),
+            color_overrides: Vec::new(),
+            remote_colors: RemoteColors::Keep,
+            invalid_utf8: InvalidUtf8::Lossy,
+            command_timeout: None,
+            render_templates: false,
+            default_user: None,
+            output_mode: OutputMode::Stream,
+            output_dir: None,
+            pending_capture: None,
+            buffers: BTreeMap::new(),
+            events: None,
+            history: CommandHistory::new(),
+            pending_completion: None,
         }
     }
 
@@ -31,19 +119,31 @@ impl ShellManager {
         debug: bool,
         command: Option<String>,
         password: Option<String>,
+        color: Option<DynColors>,
+        name: Option<&str>,
         display_names: &mut DisplayNameRegistry,
     ) -> ShellId {
         let id = ShellId(self.next_id);
         self.next_id += 1;
 
+        let name = name.unwrap_or(&hostname);
         let display_name = display_names
-            .change(None, Some(&hostname))
-            .unwrap_or_else(|| hostname.clone());
-
-        let color_idx = self.color_rotation;
-        self.color_rotation += 1;
-
-        let shell = RemoteShell::new(
+            .change(None, Some(name))
+            .unwrap_or_else(|| name.to_string());
+
+        // An explicit color wins over `--host-color`, which wins over the hostname hash
+        let color = self.palette.map(|palette| {
+            color
+                .or_else(|| {
+                    self.color_overrides
+                        .iter()
+                        .find(|(pattern, _)| pattern.matches(&hostname))
+                        .map(|(_, color)| *color)
+                })
+                .unwrap_or_else(|| palette.host_color(&hostname))
+        });
+
+        let mut shell = RemoteShell::new(
             id,
             hostname,
             port,
@@ -53,14 +153,92 @@ impl ShellManager {
             debug,
             command,
             password,
-            color_idx,
-            self.use_color,
+            color,
         );
 
+        shell.group_output = self.output_mode != OutputMode::Stream;
+        shell.line_discipline = LineDiscipline::new(self.remote_colors, self.invalid_utf8);
+        shell.events = self.events.clone();
+        if let Err(e) = shell.set_output_dir(self.output_dir.as_deref()) {
+            eprintln!("Failed to open output file for {}: {}", shell.display_name, e);
+        }
         self.shells.insert(id, shell);
         id
     }
 
+    /// Publish the shells' output and state changes to control socket subscribers.
+    pub(crate) fn set_event_sender(&mut self, events: broadcast::Sender<SessionEvent>) {
+        for shell in self.shells.values_mut() {
+            shell.events = Some(events.clone());
+        }
+        self.events = Some(events);
+    }
+
+    /// Resize every shell, disabled ones included, so they are right when enabled again.
+    pub(crate) fn resize_all(&self, cols: u16, rows: u16) {
+        for shell in self.shells.values() {
+            if shell.state != ShellState::Dead {
+                shell.resize(cols, rows);
+            }
+        }
+    }
+
+    pub(crate) fn output_dir(&self) -> Option<&Path> {
+        self.output_dir.as_deref()
+    }
+
+    /// Write each shell's output to `dir/<display_name>.out` (creating `dir`), or stop with None.
+    pub(crate) fn set_output_dir(&mut self, dir: Option<PathBuf>) -> std::io::Result<()> {
+        if let Some(ref dir) = dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        for shell in self.shells.values_mut() {
+            shell.set_output_dir(dir.as_deref())?;
+        }
+        self.output_dir = dir;
+        Ok(())
+    }
+
+    pub(crate) fn output_mode(&self) -> OutputMode {
+        self.output_mode
+    }
+
+    pub(crate) fn set_output_mode(&mut self, mode: OutputMode) {
+        self.output_mode = mode;
+        for shell in self.shells.values_mut() {
+            shell.group_output = mode != OutputMode::Stream;
+        }
+    }
+
+    /// Print the output blocks of shells whose command finished. With `force`, print every
+    /// buffered block, finished or not (e.g. when leaving grouped mode or exiting).
+    pub(crate) async fn flush_output_blocks(&mut self, console: &mut Console, force: bool) {
+        let mut ready: Vec<(Instant, String, ShellId)> = self
+            .shells
+            .values()
+            .filter_map(|s| {
+                let finished = s.block_finished_at.or(force.then(Instant::now))?;
+                Some((finished, s.display_name.clone(), s.id))
+            })
+            .collect();
+        match self.output_mode {
+            OutputMode::Grouped(BlockOrder::Name) => {
+                if !force && self.count_awaited_processes().0 > 0 {
+                    return;
+                }
+                ready.sort_by(|a, b| a.1.cmp(&b.1));
+            }
+            _ => ready.sort_by_key(|r| r.0),
+        }
+        for (_, _, id) in ready {
+            if let Some(shell) = self.shells.get_mut(&id) {
+                if let Some((console_block, log_block)) = shell.take_output_block() {
+                    console.output_with_log(&console_block, Some(&log_block)).await;
+                }
+            }
+        }
+    }
+
     pub(crate) fn get_shell(&self, id: ShellId) -> Option<&RemoteShell> {
         self.shells.get(&id)
     }
@@ -87,12 +265,254 @@ impl ShellManager {
         shells
     }
 
-    /// Returns (awaiting_count, total_enabled_count)
+    /// Send a command line to all enabled shells.
+    pub(crate) async fn dispatch_line(&mut self, line: &str, timeout: Option<Duration>) {
+        let enabled: Vec<ShellId> = self.all_shells().iter().filter(|s| s.enabled).map(|s| s.id).collect();
+        self.dispatch_line_to(&enabled, line, timeout).await;
+    }
+
+    /// Send a command line to the given shells only, leaving their enabled flags alone.
+    /// With templates on, `{{...}}` placeholders are rendered separately for each shell,
+    /// ranking the shells in the order given.
+    pub(crate) async fn dispatch_line_to(&mut self, ids: &[ShellId], line: &str, timeout: Option<Duration>) {
+        let render = self.render_templates && template::has_placeholders(line);
+        let plain = format!("{}\n", line);
+        let hosts = ids
+            .iter()
+            .filter_map(|id| self.shells.get(id))
+            .filter(|s| s.state != ShellState::Dead)
+            .map(|s| (s.id, s.display_name.clone()))
+            .collect();
+        self.history.record(line, hosts);
+
+        for (rank, id) in ids.iter().enumerate() {
+            if let Some(shell) = self.shells.get_mut(id) {
+                let cmd = if render {
+                    format!(
+                        "{}\n",
+                        shell.render_template(line, Some(rank), ids.len(), self.default_user.as_deref())
+                    )
+                } else {
+                    plain.clone()
+                };
+                shell.dispatch_targeted(cmd.as_bytes(), timeout).await;
+            }
+        }
+    }
+
+    /// Render the `--command` of every shell with templates on, ranking the shells as `dispatch_line` does.
+    pub(crate) fn render_commands(&mut self) {
+        if !self.render_templates {
+            return;
+        }
+        let enabled: Vec<ShellId> = self.all_shells().iter().filter(|s| s.enabled).map(|s| s.id).collect();
+        for (rank, id) in enabled.iter().enumerate() {
+            if let Some(shell) = self.shells.get_mut(id)
+                && let Some(cmd) = shell.command.take()
+            {
+                shell.command = Some(if template::has_placeholders(&cmd) {
+                    shell.render_template(&cmd, Some(rank), enabled.len(), self.default_user.as_deref())
+                } else {
+                    cmd
+                });
+            }
+        }
+    }
+
+    /// Live shells that commands are sent to.
+    pub(crate) fn enabled_shell_ids(&self) -> Vec<ShellId> {
+        self.all_shells()
+            .iter()
+            .filter(|s| s.enabled && s.state != ShellState::Dead)
+            .map(|s| s.id)
+            .collect()
+    }
+
+    /// Run a command on the shells, collecting their output for a `:pipe`, `:capture` or socket `run`.
+    /// Returns false when there is no shell to run it on.
+    pub(crate) async fn start_capture(
+        &mut self,
+        ids: Vec<ShellId>,
+        line: &str,
+        target: CaptureTarget,
+        print: bool,
+        timeout: Option<Duration>,
+    ) -> bool {
+        if ids.is_empty() {
+            return false;
+        }
+        for id in &ids {
+            if let Some(shell) = self.shells.get_mut(id) {
+                shell.capture = Some(Vec::new());
+                shell.print_captured = print;
+            }
+        }
+        self.dispatch_line_to(&ids, line, timeout).await;
+        self.pending_capture = Some(PendingCapture {
+            target,
+            command: line.to_string(),
+            shells: ids,
+        });
+        true
+    }
+
+    pub(crate) fn has_pending_capture(&self) -> bool {
+        self.pending_capture.is_some()
+    }
+
+    /// Once every shell of the pending capture is done, store its buffer or hand out the pipe input.
+    pub(crate) fn finish_capture(&mut self) -> Option<FinishedCapture> {
+        let pending = self.pending_capture.as_ref()?;
+        let running = pending
+            .shells
+            .iter()
+            .filter_map(|id| self.shells.get(id))
+            .any(|s| s.state == ShellState::Running);
+        if running {
+            return None;
+        }
+        let pending = self.pending_capture.take()?;
+        let mut outputs = Vec::new();
+        for id in &pending.shells {
+            if let Some(shell) = self.shells.get_mut(id)
+                && let Some(output) = shell.capture.take()
+            {
+                outputs.push(CapturedOutput {
+                    id: *id,
+                    name: shell.display_name.clone(),
+                    output,
+                    exit_code: shell.last_exit_code.filter(|_| shell.state == ShellState::Idle),
+                });
+            }
+        }
+        match pending.target {
+            CaptureTarget::Pipe { local_command, raw } => {
+                let input = if raw {
+                    outputs.into_iter().flat_map(|o| o.output).collect()
+                } else {
+                    capture::prefixed_lines(outputs.iter().map(|o| (o.name.as_str(), o.output.as_slice())))
+                };
+                Some(FinishedCapture::Pipe { local_command, input })
+            }
+            CaptureTarget::Buffer { name } => {
+                let buffer = CaptureBuffer {
+                    command: pending.command,
+                    outputs,
+                };
+                let finished = FinishedCapture::Buffer {
+                    name: name.clone(),
+                    hosts: buffer.outputs.len(),
+                    lines: buffer.line_count(),
+                };
+                self.buffers.insert(name, buffer);
+                Some(finished)
+            }
+            CaptureTarget::Socket { responder } => Some(FinishedCapture::Socket { responder, outputs }),
+        }
+    }
+
+    pub(crate) fn buffers(&self) -> &BTreeMap<String, CaptureBuffer> {
+        &self.buffers
+    }
+
+    /// Send a completion query to a few idle enabled shells, replacing an unanswered one.
+    pub(crate) fn start_completion(&mut self, query: RemoteQuery) {
+        let mut shells = Vec::new();
+        for shell in self.shells.values_mut() {
+            if shells.len() == remote_completion::MAX_SHELLS {
+                break;
+            }
+            if shell.enabled && shell.send_completion_query(query.kind, &query.prefix) {
+                shells.push(shell.id);
+            }
+        }
+        if shells.is_empty() {
+            let _ = query.reply.send(Vec::new());
+            return;
+        }
+        self.pending_completion = Some(PendingCompletion {
+            prefix: query.prefix,
+            reply: query.reply,
+            shells,
+            deadline: Instant::now() + remote_completion::SHELL_WAIT,
+        });
+    }
+
+    pub(crate) fn completion_deadline(&self) -> Option<Instant> {
+        self.pending_completion.as_ref().map(|p| p.deadline)
+    }
+
+    /// Reply to the pending completion query once its shells answered, or with the answers
+    /// received so far past its deadline.
+    pub(crate) fn finish_completion(&mut self) {
+        // Answers that came too late for their query, the shells can take the next one
+        let pending_shells = self
+            .pending_completion
+            .as_ref()
+            .map(|p| p.shells.as_slice())
+            .unwrap_or_default();
+        for shell in self.shells.values_mut() {
+            if matches!(shell.remote_answer, Some(RemoteAnswer::Done(_))) && !pending_shells.contains(&shell.id) {
+                shell.remote_answer = None;
+            }
+        }
+
+        let Some(pending) = self.pending_completion.as_ref() else {
+            return;
+        };
+        let waiting = pending.shells.iter().any(|id| {
+            self.shells
+                .get(id)
+                .is_some_and(|s| s.state != ShellState::Dead && !matches!(s.remote_answer, Some(RemoteAnswer::Done(_))))
+        });
+        if waiting && Instant::now() < pending.deadline {
+            return;
+        }
+        let Some(pending) = self.pending_completion.take() else {
+            return;
+        };
+        let mut answers = Vec::new();
+        for id in &pending.shells {
+            let Some(shell) = self.shells.get_mut(id) else {
+                continue;
+            };
+            if matches!(shell.remote_answer, Some(RemoteAnswer::Done(_)))
+                && let Some(RemoteAnswer::Done(words)) = shell.remote_answer.take()
+            {
+                answers.push(words);
+            } else {
+                // Too slow or hung, not to be waited for again
+                shell.expire_completion_query();
+            }
+        }
+        let _ = pending
+            .reply
+            .send(remote_completion::intersect(answers, &pending.prefix));
+    }
+
+    pub(crate) fn history(&self) -> &CommandHistory {
+        &self.history
+    }
+
+    /// Record the outcome of the shells that finished their command since the last call.
+    pub(crate) fn update_history(&mut self) {
+        let shells = &self.shells;
+        self.history.update(|id| match shells.get(&id) {
+            Some(shell) => match shell.state {
+                ShellState::Idle | ShellState::Terminated => Some(shell.last_exit_code),
+                ShellState::Dead => Some(None),
+                ShellState::NotStarted | ShellState::Running => None,
+            },
+            None => Some(None),
+        });
+    }
+
+    /// Returns (awaiting_count, total_enabled_count); disabled shells running a targeted command count as enabled
     pub(crate) fn count_awaited_processes(&self) -> (usize, usize) {
         let mut awaited = 0;
         let mut total = 0;
         for shell in self.shells.values() {
-            if shell.enabled {
+            if shell.enabled || shell.targeted {
                 total += 1;
                 if shell.state != ShellState::Idle {
                     awaited += 1;
@@ -120,6 +540,29 @@ impl ShellManager {
         (idle, running, not_started, dead, disabled)
     }
 
+    /// Earliest time a dead shell is scheduled to be reconnected
+    pub(crate) fn next_reconnect_at(&self) -> Option<Instant> {
+        self.shells.values().filter_map(|s| s.next_reconnect_at).min()
+    }
+
+    /// Shells whose scheduled reconnect time has passed
+    pub(crate) fn due_reconnects(&self, now: Instant) -> Vec<ShellId> {
+        self.shells
+            .values()
+            .filter(|s| s.next_reconnect_at.is_some_and(|t| t <= now))
+            .map(|s| s.id)
+            .collect()
+    }
+
+    /// Earliest time a running command overruns its deadline or timeout grace period
+    pub(crate) fn next_timeout_at(&self, grace: Duration) -> Option<Instant> {
+        self.shells.values().filter_map(|s| s.timeout_due_at(grace)).min()
+    }
+
+    pub(crate) fn count_unresponsive(&self) -> usize {
+        self.shells.values().filter(|s| s.enabled && s.unresponsive).count()
+    }
+
     pub(crate) fn all_terminated(&self) -> bool {
         if self.shells.is_empty() {
             return false;
@@ -138,7 +581,7 @@ impl ShellManager {
         let mut max_lengths = vec![0usize; nr_columns];
         for info in info_list {
             for (i, col) in info.iter().enumerate() {
-                max_lengths[i] = max_lengths[i].max(col.len());
+                max_lengths[i] = max_lengths[i].max(display_width(&String::from_utf8_lossy(col)));
             }
         }
 
@@ -152,7 +595,7 @@ impl ShellManager {
                 line.extend_from_slice(col);
                 // Don't pad the last column
                 if i < nr_columns - 1 {
-                    let padding = max_lengths[i].saturating_sub(col.len());
+                    let padding = max_lengths[i].saturating_sub(display_width(&String::from_utf8_lossy(col)));
                     line.extend(std::iter::repeat_n(b' ', padding));
                 }
             }
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// ssh itself refuses to follow more nested `Include`s than this.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+fn ssh_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh"))
+}
+
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '!'])
+}
+
+/// Split a config line into its keyword and arguments (`Key value`, `Key=value`).
+fn split_keyword(line: &str) -> Option<(String, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let rest = line[end..].trim_start().trim_start_matches('=').trim_start();
+    Some((line[..end].to_lowercase(), rest))
+}
+
+fn include_paths(args: &str, base: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for arg in shell_words::split(args).unwrap_or_default() {
+        let expanded = shellexpand::tilde(&arg).to_string();
+        let pattern = if Path::new(&expanded).is_absolute() {
+            expanded
+        } else {
+            base.join(expanded).display().to_string()
+        };
+        if let Ok(entries) = glob::glob(&pattern) {
+            paths.extend(entries.flatten());
+        }
+    }
+    paths
+}
+
+fn parse_config(content: &str, base: &Path, depth: usize, hosts: &mut Vec<String>) {
+    for line in content.lines() {
+        let Some((keyword, args)) = split_keyword(line) else {
+            continue;
+        };
+        match keyword.as_str() {
+            "host" => {
+                for alias in shell_words::split(args).unwrap_or_default() {
+                    if !is_pattern(&alias) {
+                        hosts.push(alias);
+                    }
+                }
+            }
+            "include" if depth < MAX_INCLUDE_DEPTH => {
+                for path in include_paths(args, base) {
+                    if let Ok(content) = fs::read_to_string(&path) {
+                        parse_config(&content, base, depth + 1, hosts);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Concrete `Host` aliases from an ssh config file and the files it includes.
+/// Relative includes are resolved against `~/.ssh`, like ssh does for user configs.
+pub(crate) fn config_hosts(path: &Path) -> Vec<String> {
+    let mut hosts = Vec::new();
+    if let Ok(content) = fs::read_to_string(path) {
+        let base = ssh_dir().unwrap_or_else(|| path.parent().unwrap_or(Path::new(".")).to_path_buf());
+        parse_config(&content, &base, 0, &mut hosts);
+    }
+    hosts
+}
+
+/// Unhashed host names from known_hosts content, `[host]:port` becoming `host:port`.
+fn parse_known_hosts(content: &str) -> Vec<String> {
+    let mut hosts = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        // Markers (@cert-authority, @revoked) describe keys, not hosts to log into
+        if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+            continue;
+        }
+        let Some(names) = line.split_whitespace().next() else {
+            continue;
+        };
+        for name in names.split(',') {
+            if name.starts_with('|') || is_pattern(name) {
+                continue;
+            }
+            let host = match name.strip_prefix('[').and_then(|n| n.split_once("]:")) {
+                Some((host, port)) => format!("{}:{}", host, port),
+                None => name.to_string(),
+            };
+            hosts.push(host);
+        }
+    }
+    hosts
+}
+
+pub(crate) fn known_hosts(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|content| parse_known_hosts(&content))
+        .unwrap_or_default()
+}
+
+pub(crate) fn default_config_hosts() -> Vec<String> {
+    ssh_dir()
+        .map(|dir| config_hosts(&dir.join("config")))
+        .unwrap_or_default()
+}
+
+pub(crate) fn default_known_hosts() -> Vec<String> {
+    ssh_dir()
+        .map(|dir| known_hosts(&dir.join("known_hosts")))
+        .unwrap_or_default()
+}
+
+/// Hosts matching a glob, without duplicates, in their original order.
+pub(crate) fn filter_hosts(hosts: Vec<String>, pattern: &str) -> Vec<String> {
+    let glob_pat = glob::Pattern::new(pattern);
+    let mut seen = HashSet::new();
+    hosts
+        .into_iter()
+        .filter(|host| match &glob_pat {
+            Ok(p) => p.matches(host),
+            Err(_) => host == pattern,
+        })
+        .filter(|host| seen.insert(host.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_hosts() {
+        let content = "\
+# comment
+Host web1 web2 *.internal
+    HostName 10.0.0.1
+Host=db1
+Match host bastion
+    User admin
+host !skip cache?
+";
+        let mut hosts = Vec::new();
+        parse_config(content, Path::new("/nonexistent"), 0, &mut hosts);
+        assert_eq!(hosts, vec!["web1", "web2", "db1"]);
+    }
+
+    #[test]
+    fn test_parse_config_include() {
+        let dir = std::env::temp_dir().join(format!("mash-ssh-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("conf.d/a.conf"), "Host included1\n").unwrap();
+        fs::write(dir.join("conf.d/b.conf"), "Host included2\n").unwrap();
+
+        let mut hosts = Vec::new();
+        parse_config("Host top\nInclude conf.d/*.conf\n", &dir, 0, &mut hosts);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(hosts, vec!["top", "included1", "included2"]);
+    }
+
+    #[test]
+    fn test_parse_known_hosts() {
+        let content = "\
+web1.example.com,10.0.0.1 ssh-ed25519 AAAA
+[git.example.com]:2222 ssh-rsa AAAA
+|1|aGFzaGVk|c2FsdA== ssh-ed25519 AAAA
+@cert-authority *.example.com ssh-rsa AAAA
+# comment
+*.wild ssh-rsa AAAA
+";
+        assert_eq!(
+            parse_known_hosts(content),
+            vec!["web1.example.com", "10.0.0.1", "git.example.com:2222"]
+        );
+    }
+
+    #[test]
+    fn test_filter_hosts() {
+        let hosts = vec!["web1".into(), "db1".into(), "web2".into(), "web1".into()];
+        assert_eq!(filter_hosts(hosts, "web*"), vec!["web1", "web2"]);
+    }
+}
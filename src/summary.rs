@@ -0,0 +1,194 @@
+use std::time::{Duration, Instant};
+
+use crate::durations::format_duration;
+use crate::shell_manager::ShellManager;
+
+const SLOWEST_SHOWN: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HostOutcome {
+    Succeeded,
+    CommandFailed(i32),
+    ConnectionFailed,
+    TimedOut,
+}
+
+impl HostOutcome {
+    /// Classify how a non-interactive run ended for one host.
+    /// ssh reserves exit code 255 for its own errors, so it counts as a connection failure.
+    pub(crate) fn classify(command_sent: bool, timed_out: bool, exit_code: i32) -> Self {
+        if timed_out {
+            HostOutcome::TimedOut
+        } else if !command_sent || exit_code == 255 {
+            HostOutcome::ConnectionFailed
+        } else if exit_code == 0 {
+            HostOutcome::Succeeded
+        } else {
+            HostOutcome::CommandFailed(exit_code)
+        }
+    }
+
+    fn is_failure(&self) -> bool {
+        *self != HostOutcome::Succeeded
+    }
+}
+
+struct HostResult {
+    display_name: String,
+    host: String,
+    outcome: HostOutcome,
+    duration: Option<Duration>,
+}
+
+/// Per-host results of a non-interactive run, reported once all shells are done.
+pub(crate) struct RunSummary {
+    started: Instant,
+    results: Vec<HostResult>,
+}
+
+impl RunSummary {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            results: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        display_name: &str,
+        host: String,
+        outcome: HostOutcome,
+        duration: Option<Duration>,
+    ) {
+        self.results.push(HostResult {
+            display_name: display_name.to_string(),
+            host,
+            outcome,
+            duration,
+        });
+    }
+
+    fn count(&self, pred: impl Fn(&HostOutcome) -> bool) -> usize {
+        self.results.iter().filter(|r| pred(&r.outcome)).count()
+    }
+
+    /// Hosts that did not succeed, in a format `--hosts-file` can read back.
+    pub(crate) fn failed_hosts(&self) -> Vec<String> {
+        self.results
+            .iter()
+            .filter(|r| r.outcome.is_failure())
+            .map(|r| r.host.clone())
+            .collect()
+    }
+
+    pub(crate) fn format(&self) -> String {
+        let elapsed = self.started.elapsed();
+        let succeeded = self.count(|o| *o == HostOutcome::Succeeded);
+        let command_failed = self.count(|o| matches!(o, HostOutcome::CommandFailed(_)));
+        let connection_failed = self.count(|o| *o == HostOutcome::ConnectionFailed);
+        let timed_out = self.count(|o| *o == HostOutcome::TimedOut);
+
+        let mut out = format!(
+            "Summary: {} hosts in {}\n",
+            self.results.len(),
+            format_duration(elapsed)
+        );
+        let rows = vec![
+            vec![b"  succeeded".to_vec(), succeeded.to_string().into_bytes()],
+            vec![b"  command failed".to_vec(), command_failed.to_string().into_bytes()],
+            vec![
+                b"  connection failed".to_vec(),
+                connection_failed.to_string().into_bytes(),
+            ],
+            vec![b"  timed out".to_vec(), timed_out.to_string().into_bytes()],
+        ];
+        for line in ShellManager::format_info(&rows) {
+            out.push_str(&String::from_utf8_lossy(&line));
+        }
+
+        let mut timed: Vec<&HostResult> = self.results.iter().filter(|r| r.duration.is_some()).collect();
+        timed.sort_by_key(|r| std::cmp::Reverse(r.duration));
+        if !timed.is_empty() {
+            let slowest: Vec<String> = timed
+                .iter()
+                .take(SLOWEST_SHOWN)
+                .map(|r| {
+                    format!(
+                        "{} ({})",
+                        r.display_name,
+                        format_duration(r.duration.unwrap_or_default())
+                    )
+                })
+                .collect();
+            out.push_str(&format!("Slowest: {}\n", slowest.join(", ")));
+        }
+
+        let failures: Vec<String> = self
+            .results
+            .iter()
+            .filter(|r| r.outcome.is_failure())
+            .map(|r| match r.outcome {
+                HostOutcome::CommandFailed(code) => format!("{} (exit {})", r.display_name, code),
+                HostOutcome::ConnectionFailed => format!("{} (connection)", r.display_name),
+                HostOutcome::TimedOut => format!("{} (timeout)", r.display_name),
+                HostOutcome::Succeeded => unreachable!(),
+            })
+            .collect();
+        if !failures.is_empty() {
+            out.push_str(&format!("Failed: {}\n", failures.join(", ")));
+        }
+        out
+    }
+
+    pub(crate) fn write_failed_hosts(&self, path: &str) -> std::io::Result<()> {
+        let mut content = String::new();
+        for host in self.failed_hosts() {
+            content.push_str(&host);
+            content.push('\n');
+        }
+        std::fs::write(path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_outcomes() {
+        assert_eq!(HostOutcome::classify(true, false, 0), HostOutcome::Succeeded);
+        assert_eq!(HostOutcome::classify(true, false, 3), HostOutcome::CommandFailed(3));
+        assert_eq!(HostOutcome::classify(true, false, 255), HostOutcome::ConnectionFailed);
+        assert_eq!(HostOutcome::classify(false, false, 0), HostOutcome::ConnectionFailed);
+        assert_eq!(HostOutcome::classify(true, true, 130), HostOutcome::TimedOut);
+    }
+
+    #[test]
+    fn test_failed_hosts() {
+        let mut summary = RunSummary::new();
+        summary.record("web1", "web1".into(), HostOutcome::Succeeded, None);
+        summary.record("web2", "web2:2222".into(), HostOutcome::CommandFailed(1), None);
+        summary.record("web3", "web3".into(), HostOutcome::ConnectionFailed, None);
+        assert_eq!(summary.failed_hosts(), vec!["web2:2222", "web3"]);
+    }
+
+    #[test]
+    fn test_format_counts_and_slowest() {
+        let mut summary = RunSummary::new();
+        summary.record("a", "a".into(), HostOutcome::Succeeded, Some(Duration::from_secs(1)));
+        summary.record("b", "b".into(), HostOutcome::Succeeded, Some(Duration::from_secs(5)));
+        summary.record(
+            "c",
+            "c".into(),
+            HostOutcome::CommandFailed(2),
+            Some(Duration::from_secs(3)),
+        );
+        let out = summary.format();
+        assert!(out.starts_with("Summary: 3 hosts in "));
+        assert!(out.contains("succeeded         2"));
+        assert!(out.contains("command failed    1"));
+        assert!(out.contains("Slowest: b (5.0s), c (3.0s), a (1.0s)"));
+        assert!(out.contains("Failed: c (exit 2)"));
+    }
+}
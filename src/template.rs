@@ -0,0 +1,59 @@
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+static VAR_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap());
+
+/// Replace `{{name}}` placeholders using `lookup`. Unknown names are left untouched, so
+/// lines that merely contain braces (e.g. `docker ps --format '{{.Names}}'`) pass through.
+pub(crate) fn render(line: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    VAR_RE
+        .replace_all(line, |caps: &Captures| {
+            lookup(&caps[1]).unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+pub(crate) fn has_placeholders(line: &str) -> bool {
+    VAR_RE.is_match(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "host" => Some("web1.example.com".into()),
+            "rank" => Some("3".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render_known_vars() {
+        assert_eq!(
+            render("curl http://{{host}}:8080/health?r={{ rank }}", lookup),
+            "curl http://web1.example.com:8080/health?r=3"
+        );
+    }
+
+    #[test]
+    fn test_render_unknown_untouched() {
+        assert_eq!(
+            render("echo {{nope}} {{host}}", lookup),
+            "echo {{nope}} web1.example.com"
+        );
+        assert_eq!(
+            render("docker ps --format '{{.Names}}'", lookup),
+            "docker ps --format '{{.Names}}'"
+        );
+    }
+
+    #[test]
+    fn test_has_placeholders() {
+        assert!(has_placeholders("echo {{host}}"));
+        assert!(!has_placeholders("echo {host}"));
+        assert!(!has_placeholders("echo {{.Names}}"));
+    }
+}